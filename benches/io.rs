@@ -0,0 +1,97 @@
+//! Times `banyan import` with each `--io-backend`, over a tree of many small
+//! files and one of a few big ones. Run it with
+//!
+//!     cargo bench --features io-uring
+//!
+//! Without the feature, or where the kernel won't set up a ring, only the
+//! synchronous backend is timed. Every import goes into a new repository,
+//! so that each one stores everything, but the tree being imported stays in
+//! the page cache, so this measures syscall overhead rather than the disk.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+/// Imports timed per backend and tree, of which the median is reported.
+const RUNS: usize = 5;
+
+fn banyan(repo: &Path, args: &[&str]) -> Result<(), String> {
+    let out = Command::new(env!("CARGO_BIN_EXE_banyan"))
+        .arg("-r")
+        .arg(repo)
+        .args(args)
+        .output()
+        .expect("banyan runs");
+    if out.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&out.stderr).into_owned())
+    }
+}
+
+/// How long importing `root` takes, or why it failed.
+fn import(root: &Path, backend: &str) -> Result<Duration, String> {
+    let repo = tempfile::tempdir().unwrap();
+    banyan(repo.path(), &["init"])?;
+    let root = root.to_str().unwrap();
+    let args = ["import", root, "--progress", "none", "--io-backend", backend];
+    let start = Instant::now();
+    banyan(repo.path(), &args)?;
+    Ok(start.elapsed())
+}
+
+fn small_files(root: &Path) {
+    for d in 0..64 {
+        let dir = root.join(format!("dir{}", d));
+        fs::create_dir(&dir).unwrap();
+        for f in 0..256 {
+            let data = format!("file {} in directory {}\n", f, d);
+            fs::write(dir.join(format!("file{}", f)), data.repeat(f)).unwrap();
+        }
+    }
+}
+
+fn big_files(root: &Path) {
+    for f in 0..8u8 {
+        let data: Vec<u8> =
+            (0..64 << 20).map(|i: u32| (i / 4093) as u8 ^ f).collect();
+        fs::write(root.join(format!("file{}", f)), data).unwrap();
+    }
+}
+
+/// A tree to import, and how to make it.
+type Tree = (&'static str, fn(&Path));
+
+fn main() {
+    let trees: [Tree; 2] =
+        [("small files", small_files), ("big files", big_files)];
+    for (name, make) in trees {
+        let root = tempfile::tempdir().unwrap();
+        make(root.path());
+        for backend in ["sync", "uring"] {
+            let mut times = vec![];
+            for _ in 0..RUNS {
+                match import(root.path(), backend) {
+                    Ok(time) => times.push(time),
+                    Err(e) => {
+                        let e = e.trim();
+                        println!("{}, {}: skipped: {}", name, backend, e);
+                        break;
+                    }
+                }
+            }
+            if times.is_empty() {
+                continue;
+            }
+            times.sort();
+            println!(
+                "{}, {}: median {:?} over {} runs",
+                name,
+                backend,
+                times[times.len() / 2],
+                times.len()
+            );
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! Times `banyan import` with each `--traversal` and `--file-order`, over a
+//! wide tree and a deep one, and reports how much memory each peaked at.
+//! Run it with
+//!
+//!     cargo bench --bench traversal
+//!
+//! As root, the page cache is dropped before every import, so that files
+//! are read from the disk, which is where the order they are read in
+//! matters: on a rotational disk, `inode` and `extent` should seek less.
+//! Otherwise the tree stays cached, and only the cost of the traversal
+//! itself shows. One walker and one hasher keep the reads in order.
+
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Imports timed per tree and order, of which the median is reported.
+const RUNS: usize = 3;
+
+/// How long importing `root` took and the most memory it used, in KiB.
+fn import(root: &Path, order: &[&str], cold: bool) -> (Duration, i64) {
+    let repo = tempfile::tempdir().unwrap();
+    let banyan = || {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_banyan"));
+        cmd.arg("-r").arg(repo.path());
+        cmd
+    };
+    assert!(banyan().arg("init").output().unwrap().status.success());
+    if cold {
+        unsafe { libc::sync() };
+        fs::write("/proc/sys/vm/drop_caches", "3").unwrap();
+    }
+
+    let start = Instant::now();
+    // Reaped with `wait4` below, since `Child::wait` doesn't say how much
+    // memory the child used.
+    #[allow(clippy::zombie_processes)]
+    let child = banyan()
+        .arg("import")
+        .arg(root)
+        .args(["--progress", "none"])
+        .args(["--walk-threads", "1", "--hash-threads", "1"])
+        .args(order)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut status = 0;
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    let pid = child.id() as libc::pid_t;
+    assert_eq!(unsafe { libc::wait4(pid, &mut status, 0, &mut usage) }, pid);
+    let time = start.elapsed();
+    assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+    (time, usage.ru_maxrss)
+}
+
+fn write_files(dir: &Path, files: usize) {
+    for f in 0..files {
+        let data = format!("{} in {}\n", f, dir.display());
+        fs::write(dir.join(format!("file{}", f)), data.repeat(256)).unwrap();
+    }
+}
+
+fn wide(root: &Path) {
+    for d in 0..256 {
+        let dir = root.join(format!("dir{}", d));
+        fs::create_dir(&dir).unwrap();
+        write_files(&dir, 64);
+    }
+}
+
+fn deep(root: &Path) {
+    fn fill(dir: &Path, depth: usize) {
+        write_files(dir, 4);
+        if depth == 0 {
+            return;
+        }
+        for d in 0..3 {
+            let sub = dir.join(format!("dir{}", d));
+            fs::create_dir(&sub).unwrap();
+            fill(&sub, depth - 1);
+        }
+    }
+    fill(root, 7);
+}
+
+/// A tree to import, and how to make it.
+type Tree = (&'static str, fn(&Path));
+
+fn main() {
+    // Dropping the page cache takes root.
+    let cold = fs::OpenOptions::new()
+        .write(true)
+        .open("/proc/sys/vm/drop_caches")
+        .is_ok();
+    println!("page cache {}", if cold { "dropped" } else { "kept" });
+
+    let trees: [Tree; 2] = [("wide tree", wide), ("deep tree", deep)];
+    for (name, make) in trees {
+        let root = tempfile::tempdir().unwrap();
+        make(root.path());
+        for traversal in ["breadth", "depth"] {
+            for file_order in ["dirent", "inode", "extent"] {
+                let order = [
+                    "--traversal",
+                    traversal,
+                    "--file-order",
+                    file_order,
+                ];
+                let mut runs: Vec<_> = (0..RUNS)
+                    .map(|_| import(root.path(), &order, cold))
+                    .collect();
+                runs.sort();
+                let (time, _) = runs[RUNS / 2];
+                let peak = runs.iter().map(|run| run.1).max().unwrap();
+                println!(
+                    "{}, {}, {}: median {:?}, peak {} KiB",
+                    name, traversal, file_order, time, peak
+                );
+            }
+        }
+    }
+}
@@ -1,5 +1,14 @@
+use std::path::PathBuf;
+
 use clap::Parser;
 
+use crate::output::OutputFormat;
+use crate::repo::export::ExportFormat;
+use crate::util::xattr::XattrFilter;
+use crate::progress::ProgressMode;
+use crate::util::uring::IoBackend;
+use crate::util::queue::{FileOrder, Traversal};
+
 /// This doc string acts as a help message when the user runs '--help'
 /// as do all doc strings on fields
 #[derive(Parser, Debug)]
@@ -13,9 +22,13 @@ pub(crate) struct Opts {
     /// Banyan repository folder location
     #[clap(short, long, default_value = "repo")]
     pub repo: String,
-    /// Print more detailed logs and debug info
+    /// Print more detailed logs and debug info; once to log every file,
+    /// twice to also log directories and links
     #[clap(short, long, parse(from_occurrences))]
     pub verbose: i32,
+    /// Format of command results on stdout
+    #[clap(long, arg_enum, global = true, default_value = "text")]
+    pub output: OutputFormat,
     #[clap(subcommand)]
     pub cmd: Commands,
 }
@@ -23,13 +36,205 @@ pub(crate) struct Opts {
 #[derive(Parser, Debug)]
 pub(crate) enum Commands {
     /// Initializes an object store
-    Init,
+    Init {
+        /// Share blocks between objects and the files they come from or are
+        /// restored to, where the filesystem supports reflinks
+        #[clap(long)]
+        reflink: bool,
+    },
+    /// Shows the repository's settings, changing any that are given
+    Config {
+        /// Share blocks between objects and the files they come from or are
+        /// restored to, where the filesystem supports reflinks
+        #[clap(long)]
+        reflink: Option<bool>,
+    },
     /// Imports a filesystem tree into the object store
     Import { 
         /// Path to import into the store
-        path: String,
-        /// Do not traverse across block devices
+        #[clap(required_unless_present = "from-tar")]
+        path: Option<String>,
+        /// Import the contents of a tar archive instead, or of stdin if `-`
+        #[clap(
+            long,
+            parse(from_os_str),
+            conflicts_with_all = &[
+                "path",
+                // Archives are read as a stream, without walking anything.
+                "same-device",
+                "walk-threads",
+                "hash-threads",
+                "io-backend",
+                "honor-nodump",
+                "max-open-files",
+                "raise-fd-limit",
+                "traversal",
+                "file-order",
+            ],
+        )]
+        from_tar: Option<PathBuf>,
+        /// Do not descend into other filesystems; mount points are kept, empty
         #[clap(short, long)]
         same_device: bool,
+        /// Store only the changes since this layer, as a delta on top of it
+        #[clap(long)]
+        delta_from: Option<String>,
+        /// Extended attributes to keep: a comma-separated list of user,
+        /// trusted, security, acl, or all or none
+        #[clap(long, default_value = "all")]
+        xattrs: XattrFilter,
+        /// Threads walking directories; defaults to one per CPU, less two
+        /// on machines with more than four
+        #[clap(long, validator = positive)]
+        walk_threads: Option<usize>,
+        /// Threads hashing and storing files; same default as
+        /// --walk-threads
+        #[clap(long, validator = positive)]
+        hash_threads: Option<usize>,
+        /// How to make the walker's and importer's syscalls
+        #[clap(long, arg_enum, default_value = "auto")]
+        io_backend: IoBackend,
+        /// Leave out files and directories marked nodump (chattr +d), and
+        /// everything under them
+        #[clap(long)]
+        honor_nodump: bool,
+        /// Open at most this many files at once; defaults to the soft limit
+        /// on open files (ulimit -n)
+        #[clap(long, validator = file_count)]
+        max_open_files: Option<u64>,
+        /// Raise the soft limit on open files to the hard limit first
+        #[clap(long)]
+        raise_fd_limit: bool,
+        /// Which entries to visit first
+        #[clap(long, arg_enum, default_value = "breadth")]
+        traversal: Traversal,
+        /// The order to visit each directory's entries, and so read its
+        /// files, in
+        #[clap(long, arg_enum, default_value = "dirent")]
+        file_order: FileOrder,
+        /// How to report progress on stderr
+        #[clap(long, arg_enum, default_value = "auto")]
+        progress: ProgressMode,
+    },
+    /// Flattens a delta layer and its parents into a single full layer
+    Squash {
+        /// Layer hash, or a unique prefix of it
+        layer: String,
+    },
+    /// Lists the contents of a directory in a layer
+    Ls {
+        /// Layer hash, or a unique prefix of it
+        layer: String,
+        /// Path within the layer to list
+        #[clap(parse(from_os_str), default_value = ".")]
+        path: PathBuf,
+        /// Show permissions, owner, size and hash
+        #[clap(short, long)]
+        long: bool,
+        /// List subdirectories recursively
+        #[clap(short = 'R', long)]
+        recursive: bool,
+    },
+    /// Restores a layer, or parts of it, into a directory
+    Restore {
+        /// Layer hash, or a unique prefix of it
+        layer: String,
+        /// Only restore this path within the layer, which may be a glob;
+        /// can be given multiple times
+        #[clap(long = "path", parse(from_os_str))]
+        paths: Vec<PathBuf>,
+        /// Directory to restore into
+        #[clap(short, long, parse(from_os_str))]
+        target: PathBuf,
+        /// Extended attributes to restore: a comma-separated list of user,
+        /// trusted, security, acl, or all or none
+        #[clap(long, default_value = "all")]
+        xattrs: XattrFilter,
+    },
+    /// Mounts a layer as a read-only filesystem until unmounted
+    Mount {
+        /// Layer hash, or a unique prefix of it
+        layer: String,
+        /// Directory to mount the layer on
+        #[clap(parse(from_os_str))]
+        mountpoint: PathBuf,
+        /// Let users other than the one mounting access the filesystem
+        #[clap(long)]
+        allow_other: bool,
     },
+    /// Writes a layer out as an archive
+    Export {
+        /// Layer hash, or a unique prefix of it
+        layer: String,
+        /// Archive format
+        #[clap(long, arg_enum, default_value = "tar")]
+        format: ExportFormat,
+        /// Only write the changes since this layer (OCI layers only)
+        #[clap(long)]
+        parent: Option<String>,
+        /// File to write the archive to, or - for stdout
+        #[clap(short = 'o', long = "out", parse(from_os_str), default_value = "-")]
+        out: PathBuf,
+    },
+    /// Writes a stack of layers out as an OCI image layout directory
+    OciExport {
+        /// Layer hashes, or unique prefixes of them, from the bottom up
+        #[clap(required = true)]
+        layers: Vec<String>,
+        /// Directory to write the image layout to
+        #[clap(short = 'o', long = "out", parse(from_os_str))]
+        out: PathBuf,
+        /// Reference name to tag the image with in the layout's index
+        #[clap(long, default_value = "latest")]
+        tag: String,
+    },
+    /// Imports an image from an OCI image layout directory, storing one
+    /// layer for each of its layers
+    OciImport {
+        /// Image layout directory
+        #[clap(parse(from_os_str))]
+        path: PathBuf,
+        /// Reference name of the image, if the layout holds more than one
+        #[clap(long)]
+        tag: Option<String>,
+        /// Extended attributes to keep: a comma-separated list of user,
+        /// trusted, security, acl, or all or none
+        #[clap(long, default_value = "all")]
+        xattrs: XattrFilter,
+        /// How to report progress on stderr
+        #[clap(long, arg_enum, default_value = "auto")]
+        progress: ProgressMode,
+    },
+    /// Writes the contents of a file in a layer to stdout
+    Cat {
+        /// Layer hash, or a unique prefix of it
+        layer: String,
+        /// Path of the file within the layer
+        #[clap(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Writes the contents of an object to stdout
+    CatObject {
+        /// Object hash
+        hash: String,
+    },
+}
+
+/// A thread count, which has to be at least one.
+fn positive(s: &str) -> Result<(), String> {
+    match s.parse::<usize>() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// A number of open files, which has to be at least one. Whether it is
+/// enough for the walk is only known once the thread counts are.
+fn file_count(s: &str) -> Result<(), String> {
+    match s.parse::<u64>() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
 }
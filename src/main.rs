@@ -5,63 +5,187 @@
 
 // TODO: remove in future
 #![allow(dead_code)]
-#![feature(decl_macro)]
 #![deny(unsafe_op_in_unsafe_fn)]
 
 mod cli_parser;
 
 mod error;
 mod init;
+mod output;
+mod progress;
 mod repo;
 mod util;
 
-use std::{
-    error::Error, ffi::CString, fs::File, os::unix::prelude::AsRawFd,
-    sync::Arc,
-};
+use std::error::Error;
+use std::os::unix::ffi::OsStrExt;
 
 use clap::Parser;
 use cli_parser::{Opts, Commands};
 
-use crate::util::PString;
-
 #[cfg(feature = "dhat-heap")]
 #[global_allocator]
 static ALLOC: dhat::Alloc = dhat::Alloc;
 
-#[allow(unreachable_code)]
-fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+fn main() {
     #[cfg(feature = "dhat-heap")]
     let _profiler = dhat::Profiler::new_heap();
-    
-    let args = Opts::parse();
-
-    match args.cmd {
-        Commands::Init => {
-            let mut path = std::path::PathBuf::new();
-            path.push(&args.repo);
-            std::fs::create_dir_all(&path)?;
 
-            let mut objects_path = path.clone();
-            let mut layers_path = path.clone();
+    let args = Opts::parse();
+    let format = args.output;
 
-            objects_path.push("objects");
-            layers_path.push("layers");
+    if let Err(e) = run(args) {
+        output::emit_error(format, &e);
+        std::process::exit(1);
+    }
+}
 
-            std::fs::create_dir(objects_path)?;
-            std::fs::create_dir(layers_path)?;
+fn run(args: Opts) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match args.cmd {
+        Commands::Init { reflink } => {
+            let config = repo::config::Config { reflink };
+            let res = repo::config::init(&args.repo, config)?;
+            output::emit(args.output, &res);
+        },
+        Commands::Config { reflink } => {
+            let mut config = repo::config::Config::load(&args.repo)?;
+            if let Some(reflink) = reflink {
+                config.reflink = reflink;
+                config.save(&args.repo)?;
+            }
+            output::emit(args.output, &config);
+        },
+        Commands::Import {
+            path,
+            from_tar,
+            same_device,
+            delta_from,
+            xattrs,
+            walk_threads,
+            hash_threads,
+            io_backend,
+            honor_nodump,
+            max_open_files,
+            raise_fd_limit,
+            traversal,
+            file_order,
+            progress,
+        } => {
+            let options = repo::layer::ImportOptions {
+                same_device,
+                progress,
+                verbose: args.verbose,
+                delta_from,
+                xattrs,
+                walk_threads,
+                hash_threads,
+                io_backend,
+                honor_nodump,
+                max_open_files,
+                raise_fd_limit,
+                traversal,
+                file_order,
+            };
+            let res = match (path, from_tar) {
+                (_, Some(archive)) => {
+                    repo::untar::import(&archive, &args.repo, &options)?
+                }
+                (Some(path), None) => {
+                    repo::layer::import(&path, &args.repo, &options)?
+                }
+                (None, None) => unreachable!("clap requires one of them"),
+            };
+            output::emit(args.output, &res);
+        },
+        Commands::Squash { layer } => {
+            let res = repo::layer::squash(&args.repo, &layer)?;
+            output::emit(args.output, &res);
+        },
+        Commands::Ls { layer, path, long, recursive } => {
+            let res = repo::browse::ls(
+                &args.repo,
+                &layer,
+                path.as_os_str().as_bytes(),
+                long,
+                recursive,
+            )?;
+            output::emit(args.output, &res);
+        },
+        Commands::Restore { layer, paths, target, xattrs } => {
+            let paths: Vec<&[u8]> =
+                paths.iter().map(|p| p.as_os_str().as_bytes()).collect();
+            let res = repo::restore::restore(
+                &args.repo, &layer, &paths, &target, &xattrs,
+            )?;
+            output::emit(args.output, &res);
+        },
+        Commands::Mount { layer, mountpoint, allow_other } => {
+            let res = repo::mount::mount(
+                &args.repo,
+                &layer,
+                &mountpoint,
+                allow_other,
+            )?;
+            output::emit(args.output, &res);
         },
-        Commands::Import { path, same_device } => {
-            let res = repo::layer::import(
+        Commands::Export { layer, format, parent, out } => {
+            let parent = parent.as_deref();
+            if out.as_os_str() == "-" {
+                let mut stdout = std::io::stdout().lock();
+                let res = repo::export::export(
+                    &args.repo, &layer, parent, format, &mut stdout,
+                )?;
+                output::emit_stderr(args.output, &res);
+            } else {
+                let mut file = std::fs::File::create(&out)?;
+                let res = repo::export::export(
+                    &args.repo, &layer, parent, format, &mut file,
+                )?;
+                output::emit(args.output, &res);
+            }
+        },
+        Commands::OciExport { layers, out, tag } => {
+            let res = repo::oci::export(&args.repo, &layers, &tag, &out)?;
+            output::emit(args.output, &res);
+        },
+        Commands::OciImport { path, tag, xattrs, progress } => {
+            let res = repo::oci::import(
+                &args.repo,
                 &path,
+                tag.as_deref(),
+                &repo::layer::ImportOptions {
+                    same_device: false,
+                    progress,
+                    verbose: args.verbose,
+                    delta_from: None,
+                    xattrs,
+                    walk_threads: None,
+                    hash_threads: None,
+                    io_backend: util::uring::IoBackend::Auto,
+                    honor_nodump: false,
+                    max_open_files: None,
+                    raise_fd_limit: false,
+                    traversal: util::queue::Traversal::Breadth,
+                    file_order: util::queue::FileOrder::Dirent,
+                },
+            )?;
+            output::emit(args.output, &res);
+        },
+        Commands::Cat { layer, path } => {
+            repo::browse::cat(
                 &args.repo,
+                &layer,
+                path.as_os_str().as_bytes(),
+                &mut std::io::stdout().lock(),
+            )?;
+        },
+        Commands::CatObject { hash } => {
+            repo::browse::cat_object(
+                &args.repo,
+                &hash,
+                &mut std::io::stdout().lock(),
             )?;
-            println!("Successfully serialized state to {:?}.", res);
         },
     };
 
-    return Ok(());
-
-
     Ok(())
 }
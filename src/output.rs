@@ -0,0 +1,60 @@
+use std::fmt::Display;
+
+use clap::ArgEnum;
+use serde::Serialize;
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum OutputFormat {
+    /// Human-readable text
+    Text,
+    /// One JSON document per command on stdout, and errors as JSON on
+    /// stderr
+    Json,
+}
+
+/// Prints the result of a command to stdout in the requested format.
+///
+/// Every command result implements `Display` for text output and
+/// `Serialize` for JSON; field names in the latter are part of the stable
+/// interface, so only ever add to them.
+pub(crate) fn emit<T: Serialize + Display>(format: OutputFormat, value: &T) {
+    match format {
+        OutputFormat::Text => println!("{}", value),
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string(value).expect("output serializes")
+        ),
+    }
+}
+
+/// Like `emit`, but to stderr, for commands whose stdout carries data.
+pub(crate) fn emit_stderr<T: Serialize + Display>(
+    format: OutputFormat,
+    value: &T,
+) {
+    match format {
+        OutputFormat::Text => eprintln!("{}", value),
+        OutputFormat::Json => eprintln!(
+            "{}",
+            serde_json::to_string(value).expect("output serializes")
+        ),
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorOutput {
+    error: String,
+}
+
+/// Prints a failed command's error to stderr in the requested format, so
+/// that it never mixes with data a command was writing to stdout.
+pub(crate) fn emit_error(format: OutputFormat, error: &dyn Display) {
+    match format {
+        OutputFormat::Text => eprintln!("Error: {}", error),
+        OutputFormat::Json => eprintln!(
+            "{}",
+            serde_json::to_string(&ErrorOutput { error: error.to_string() })
+                .expect("output serializes")
+        ),
+    }
+}
@@ -0,0 +1,228 @@
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use clap::ArgEnum;
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use serde::Serialize;
+
+/// How often the reporter thread prints a progress update.
+const INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ProgressMode {
+    /// Status line if stderr is a terminal, nothing otherwise
+    Auto,
+    /// Status line on stderr, redrawn in place
+    Bar,
+    /// One JSON object per interval on stderr
+    Json,
+    /// No progress output
+    None,
+}
+
+impl ProgressMode {
+    /// Resolves `Auto` into either `Bar` or `None`.
+    fn resolve(self) -> ProgressMode {
+        match self {
+            ProgressMode::Auto => {
+                if unsafe { libc::isatty(libc::STDERR_FILENO) } == 1 {
+                    ProgressMode::Bar
+                } else {
+                    ProgressMode::None
+                }
+            }
+            mode => mode,
+        }
+    }
+}
+
+/// Counters shared between all walker threads and the reporter.
+///
+/// Everything is updated with relaxed atomics: the numbers only need to be
+/// roughly right while the walk is running, and exact once it has finished.
+pub(crate) struct Progress {
+    mode: ProgressMode,
+    verbose: i32,
+    start: Instant,
+    pub dirs: AtomicU64,
+    pub files: AtomicU64,
+    pub links: AtomicU64,
+    pub bytes_hashed: AtomicU64,
+    pub bytes_stored: AtomicU64,
+    pub bytes_deduped: AtomicU64,
+    /// Of the bytes stored, those sharing blocks with the imported files.
+    pub bytes_cloned: AtomicU64,
+    /// Files hashed before being stored, for looking unchanged since the
+    /// parent layer.
+    pub files_hashed_first: AtomicU64,
+    /// Links whose attributes were left out, for want of `/proc`.
+    pub link_xattrs_skipped: AtomicU64,
+    pub errors: AtomicU64,
+}
+
+/// A point-in-time copy of the counters, as emitted in JSON mode.
+#[derive(Clone, Debug, Default, Serialize)]
+pub(crate) struct Snapshot {
+    pub elapsed_ms: u64,
+    pub dirs: u64,
+    pub files: u64,
+    pub links: u64,
+    pub bytes_hashed: u64,
+    pub bytes_stored: u64,
+    pub bytes_deduped: u64,
+    pub bytes_cloned: u64,
+    pub files_hashed_first: u64,
+    pub link_xattrs_skipped: u64,
+    pub errors: u64,
+    /// Bytes hashed per second over the last interval.
+    pub throughput: u64,
+    pub done: bool,
+}
+
+impl Progress {
+    pub fn new(mode: ProgressMode, verbose: i32) -> Progress {
+        Progress {
+            mode: mode.resolve(),
+            verbose,
+            start: Instant::now(),
+            dirs: AtomicU64::new(0),
+            files: AtomicU64::new(0),
+            links: AtomicU64::new(0),
+            bytes_hashed: AtomicU64::new(0),
+            bytes_stored: AtomicU64::new(0),
+            bytes_deduped: AtomicU64::new(0),
+            bytes_cloned: AtomicU64::new(0),
+            files_hashed_first: AtomicU64::new(0),
+            link_xattrs_skipped: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Whether per-entry logging at `level` (the number of `-v`s) is on.
+    #[inline]
+    pub fn logging(&self, level: i32) -> bool {
+        self.verbose >= level
+    }
+
+    /// Prints a log line without garbling the status line.
+    pub fn log(&self, line: std::fmt::Arguments) {
+        let stderr = io::stderr();
+        let mut stderr = stderr.lock();
+        let _ = match self.mode {
+            ProgressMode::Bar => writeln!(stderr, "\r\x1b[K{}", line),
+            _ => writeln!(stderr, "{}", line),
+        };
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            elapsed_ms: self.start.elapsed().as_millis() as u64,
+            dirs: self.dirs.load(Ordering::Relaxed),
+            files: self.files.load(Ordering::Relaxed),
+            links: self.links.load(Ordering::Relaxed),
+            bytes_hashed: self.bytes_hashed.load(Ordering::Relaxed),
+            bytes_stored: self.bytes_stored.load(Ordering::Relaxed),
+            bytes_deduped: self.bytes_deduped.load(Ordering::Relaxed),
+            bytes_cloned: self.bytes_cloned.load(Ordering::Relaxed),
+            files_hashed_first: self
+                .files_hashed_first
+                .load(Ordering::Relaxed),
+            link_xattrs_skipped: self
+                .link_xattrs_skipped
+                .load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            throughput: 0,
+            done: false,
+        }
+    }
+
+    /// Prints an update every `INTERVAL` until `done` is signalled or
+    /// disconnected, then prints a final update.
+    pub fn report(&self, done: Receiver<()>) {
+        if self.mode == ProgressMode::None {
+            let _ = done.recv();
+            return;
+        }
+
+        let mut last = self.snapshot();
+        loop {
+            let finished = !matches!(
+                done.recv_timeout(INTERVAL),
+                Err(RecvTimeoutError::Timeout)
+            );
+
+            let mut snap = self.snapshot();
+            let dt = snap.elapsed_ms.saturating_sub(last.elapsed_ms).max(1);
+            snap.throughput =
+                (snap.bytes_hashed - last.bytes_hashed) * 1000 / dt;
+            snap.done = finished;
+            self.print(&snap);
+            last = snap;
+
+            if finished {
+                break;
+            }
+        }
+    }
+
+    /// Runs `f` on this thread while reporting on another.
+    pub fn report_while<T>(&self, f: impl FnOnce() -> T) -> T {
+        let (done_tx, done_rx) = crossbeam_channel::bounded::<()>(0);
+        crossbeam_utils::thread::scope(|s| {
+            s.spawn(move |_| self.report(done_rx));
+            let res = f();
+            drop(done_tx);
+            res
+        })
+        .unwrap()
+    }
+
+    fn print(&self, snap: &Snapshot) {
+        let stderr = io::stderr();
+        let mut stderr = stderr.lock();
+        let _ = match self.mode {
+            ProgressMode::Json => writeln!(
+                stderr,
+                "{}",
+                serde_json::to_string(snap).expect("snapshot serializes")
+            ),
+            ProgressMode::Bar => write!(
+                stderr,
+                "\r\x1b[K{} dirs, {} files, {} links | {} hashed, {} new, \
+                 {} deduped | {}/s | {} errors{}",
+                snap.dirs,
+                snap.files,
+                snap.links,
+                human_bytes(snap.bytes_hashed),
+                human_bytes(snap.bytes_stored),
+                human_bytes(snap.bytes_deduped),
+                human_bytes(snap.throughput),
+                snap.errors,
+                if snap.done { "\n" } else { "" },
+            ),
+            _ => Ok(()),
+        };
+        let _ = stderr.flush();
+    }
+}
+
+fn human_bytes(n: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut n = n as f64;
+    let mut unit = 0;
+    while n >= 1024.0 && unit < UNITS.len() - 1 {
+        n /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", n, UNITS[unit])
+    } else {
+        format!("{:.1} {}", n, UNITS[unit])
+    }
+}
@@ -0,0 +1,244 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use crate::repo::layer::{self, Entry, FsState};
+use crate::util::PString;
+
+/// A single entry as printed by `ls`.
+#[derive(Debug, Serialize)]
+pub struct ListEntry {
+    /// Path relative to the layer root, escaped if not valid UTF-8.
+    pub path: String,
+    /// One of `dir`, `file` or `link`.
+    pub kind: &'static str,
+    pub perms: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<u64>,
+    pub hash: Option<String>,
+    /// Symlink target, escaped if not valid UTF-8.
+    pub target: Option<String>,
+    /// Path relative to the listed directory, for text output.
+    #[serde(skip)]
+    name: String,
+}
+
+/// The result of `ls`.
+#[derive(Debug, Serialize)]
+pub struct Listing {
+    pub layer: String,
+    pub entries: Vec<ListEntry>,
+    #[serde(skip)]
+    long: bool,
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, e) in self.entries.iter().enumerate() {
+            if i != 0 {
+                writeln!(f)?;
+            }
+            if !self.long {
+                write!(f, "{}", e.name)?;
+                continue;
+            }
+
+            write!(
+                f,
+                "{} {:>5} {:>5} {:>10} {:43} {}",
+                mode_string(e.kind, e.perms),
+                e.uid.map_or("-".to_owned(), |x| x.to_string()),
+                e.gid.map_or("-".to_owned(), |x| x.to_string()),
+                e.size.map_or("-".to_owned(), |x| x.to_string()),
+                e.hash.as_deref().unwrap_or("-"),
+                e.name,
+            )?;
+            if let Some(target) = &e.target {
+                write!(f, " -> {}", target)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Render permissions the way `ls -l` does, e.g. `drwxr-xr-x`.
+fn mode_string(kind: &str, perms: Option<u32>) -> String {
+    let mut res = String::with_capacity(10);
+    res.push(match kind {
+        "dir" => 'd',
+        "link" => 'l',
+        _ => '-',
+    });
+    let perms = perms.unwrap_or(0o777);
+    for shift in [6, 3, 0] {
+        let bits = (perms >> shift) & 0o7;
+        res.push(if bits & 4 != 0 { 'r' } else { '-' });
+        res.push(if bits & 2 != 0 { 'w' } else { '-' });
+        res.push(if bits & 1 != 0 { 'x' } else { '-' });
+    }
+    res
+}
+
+/// Strip the leading `./` from a key for display.
+fn display_path(key: &PString) -> String {
+    let s = key.to_string();
+    match s.strip_prefix("./") {
+        Some(rest) => rest.to_owned(),
+        None => s,
+    }
+}
+
+fn object_path(repo_basedir: &str, hash: &str) -> Result<PathBuf, io::Error> {
+    // Hashes are URL-safe base64, so this also keeps us inside `objects`.
+    let valid = !hash.is_empty()
+        && hash.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_');
+    if !valid {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} is not a valid object hash", hash),
+        ));
+    }
+
+    let mut path = PathBuf::from(repo_basedir);
+    path.push("objects");
+    path.push(hash);
+    Ok(path)
+}
+
+fn list_entry(
+    repo_basedir: &str,
+    key: &PString,
+    entry: Entry,
+    name: String,
+) -> Result<ListEntry, io::Error> {
+    let path = display_path(key);
+    Ok(match entry {
+        Entry::Dir(dir) => ListEntry {
+            path,
+            kind: "dir",
+            perms: Some(dir.perms),
+            uid: Some(dir.uid),
+            gid: Some(dir.gid),
+            size: None,
+            hash: None,
+            target: None,
+            name,
+        },
+        Entry::File(obj) => ListEntry {
+            path,
+            kind: "file",
+            perms: Some(obj.perms),
+            uid: Some(obj.uid),
+            gid: Some(obj.gid),
+            size: Some(
+                std::fs::metadata(object_path(repo_basedir, &obj.hash)?)?
+                    .len(),
+            ),
+            hash: Some(obj.hash.clone()),
+            target: None,
+            name,
+        },
+        Entry::Link(link) => ListEntry {
+            path,
+            kind: "link",
+            perms: None,
+            uid: Some(link.uid),
+            gid: Some(link.gid),
+            size: None,
+            hash: None,
+            target: Some(link.target.to_string()),
+            name,
+        },
+    })
+}
+
+/// List the entries at `path` in a layer.
+pub fn ls(
+    repo_basedir: &str,
+    layer: &str,
+    path: &[u8],
+    long: bool,
+    recursive: bool,
+) -> Result<Listing, Box<dyn Error + Send + Sync>> {
+    let hash = layer::resolve(repo_basedir, layer)?;
+    let key = layer::layer_key(path)?;
+    let state = FsState::load_paths(
+        repo_basedir,
+        &hash,
+        std::slice::from_ref(&key),
+        recursive,
+    )?;
+
+    let mut entries = vec![];
+    match state.get(&key) {
+        Some(Entry::Dir(_)) => {}
+        // The root itself isn't recorded, but is always a directory.
+        None if key.as_bytes() == b"." => {}
+        Some(entry) => {
+            let name = display_path(&key);
+            entries.push(list_entry(repo_basedir, &key, entry, name)?);
+            return Ok(Listing { layer: hash, entries, long });
+        }
+        None => {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{}: no such file or directory in layer", key),
+            )))
+        }
+    }
+
+    let skip = key.as_bytes().len() + 1;
+    for (child, entry) in state.children(&key, recursive) {
+        let name = PString::from_vec(child.as_bytes()[skip..].to_vec())?;
+        let name = name.to_string();
+        entries.push(list_entry(repo_basedir, child, entry, name)?);
+    }
+
+    Ok(Listing { layer: hash, entries, long })
+}
+
+/// Write the contents of the file at `path` in a layer to `out`.
+pub fn cat(
+    repo_basedir: &str,
+    layer: &str,
+    path: &[u8],
+    out: &mut dyn Write,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let key = layer::layer_key(path)?;
+    let state = FsState::load_paths(
+        repo_basedir,
+        layer,
+        std::slice::from_ref(&key),
+        false,
+    )?;
+
+    let kind = match state.get(&key) {
+        Some(Entry::File(obj)) => {
+            return cat_object(repo_basedir, &obj.hash, out);
+        }
+        Some(Entry::Dir(_)) => "is a directory",
+        Some(Entry::Link(_)) => "is a symlink",
+        None => "no such file or directory in layer",
+    };
+    Err(Box::new(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{}: {}", key, kind),
+    )))
+}
+
+/// Write the contents of the object `hash` to `out`.
+pub fn cat_object(
+    repo_basedir: &str,
+    hash: &str,
+    out: &mut dyn Write,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut file = File::open(object_path(repo_basedir, hash)?)?;
+    io::copy(&mut file, out)?;
+    out.flush()?;
+    Ok(())
+}
@@ -0,0 +1,77 @@
+//! Settings kept in a repository's `config` file, which apply to every
+//! command run against it. A repository without one uses the defaults.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+/// The settings of a repository.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Share blocks between objects and the files they are imported from
+    /// or restored to, rather than copying them, on filesystems that can.
+    pub reflink: bool,
+}
+
+fn path(repo_basedir: &str) -> PathBuf {
+    let mut path = PathBuf::from(repo_basedir);
+    path.push("config");
+    path
+}
+
+impl Config {
+    pub fn load(repo_basedir: &str) -> io::Result<Config> {
+        match fs::read(path(repo_basedir)) {
+            Ok(ser) => serde_json::from_slice(&ser)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Ok(Config::default())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write the settings out, replacing the old ones all at once.
+    pub fn save(&self, repo_basedir: &str) -> io::Result<()> {
+        let path = path(repo_basedir);
+        let tmp = path.with_extension("tmp");
+        let ser = serde_json::to_vec_pretty(self).map_err(io::Error::other)?;
+        fs::write(&tmp, ser)?;
+        fs::rename(tmp, path)
+    }
+}
+
+/// The result of `init`.
+#[derive(Debug, Serialize)]
+pub struct InitSummary {
+    /// Where the repository was created.
+    pub repo: String,
+    pub config: Config,
+}
+
+impl fmt::Display for InitSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Initialized repository at {} ({}).", self.repo, self.config)
+    }
+}
+
+/// Create an empty repository at `repo_basedir` with the given settings.
+pub fn init(repo_basedir: &str, config: Config) -> io::Result<InitSummary> {
+    let path = PathBuf::from(repo_basedir);
+    fs::create_dir_all(&path)?;
+    for dir in ["objects", "layers", "trees"] {
+        fs::create_dir(path.join(dir))?;
+    }
+    config.save(repo_basedir)?;
+    Ok(InitSummary { repo: repo_basedir.to_string(), config })
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "reflink = {}", self.reflink)
+    }
+}
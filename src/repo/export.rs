@@ -0,0 +1,258 @@
+use std::collections::{hash_map, BTreeMap, HashMap};
+use std::error::Error;
+use std::ffi::CString;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use clap::ArgEnum;
+use serde::Serialize;
+
+use crate::repo::layer::{self, Entry, FsState, Object};
+use crate::repo::overlay::Delta;
+use crate::util::tar::{self, Kind};
+use crate::util::PString;
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ExportFormat {
+    /// POSIX tar with pax extended headers
+    Tar,
+    /// An OCI image layer, which may be a diff against a parent layer
+    OciLayer,
+}
+
+/// The result of `export`.
+#[derive(Debug, Default, Serialize)]
+pub struct ExportSummary {
+    pub layer: String,
+    pub dirs: u64,
+    pub files: u64,
+    pub links: u64,
+    /// Entries of the parent layer marked as removed.
+    pub whiteouts: u64,
+    pub bytes: u64,
+    pub duration_ms: u64,
+}
+
+impl fmt::Display for ExportSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Exported {} directories, {} files and {} links ({} bytes)",
+            self.dirs, self.files, self.links, self.bytes
+        )?;
+        if self.whiteouts != 0 {
+            write!(f, ", removing {} entries", self.whiteouts)?;
+        }
+        Ok(())
+    }
+}
+
+/// The archive path of an entry: its key without the leading `./`.
+fn archive_path(key: &PString) -> Vec<u8> {
+    key.as_bytes().strip_prefix(b"./").unwrap_or(key.as_bytes()).to_vec()
+}
+
+/// Layers don't record modification times yet, so archives use the time
+/// the layer was created for everything.
+pub(crate) fn layer_mtime(repo_basedir: &str, hash: &str) -> io::Result<u64> {
+    let mut layer_path = PathBuf::from(repo_basedir);
+    layer_path.push("layers");
+    layer_path.push(hash);
+    Ok(std::fs::metadata(layer_path)?.mtime() as u64)
+}
+
+/// Stream a layer to `out` as an archive, without touching the disk other
+/// than to read objects. For OCI layers, `parent` names the layer to write
+/// a diff against.
+pub(crate) fn export(
+    repo_basedir: &str,
+    layer: &str,
+    parent: Option<&str>,
+    format: ExportFormat,
+    out: &mut dyn Write,
+) -> Result<ExportSummary, Box<dyn Error + Send + Sync>> {
+    let start = Instant::now();
+    let hash = layer::resolve(repo_basedir, layer)?;
+    let state = FsState::load(repo_basedir, &hash)?;
+    let mtime = layer_mtime(repo_basedir, &hash)?;
+
+    let mut summary = ExportSummary { layer: hash, ..Default::default() };
+    let mut writer = tar::Writer::new(io::BufWriter::new(out));
+    match format {
+        ExportFormat::Tar => {
+            if parent.is_some() {
+                return Err("only OCI layers can be exported as a diff".into());
+            }
+            write_tar(repo_basedir, &state, mtime, &mut writer, &mut summary)?;
+        }
+        ExportFormat::OciLayer => {
+            let delta = layer::diff(repo_basedir, parent, &summary.layer)?;
+            write_oci_layer(
+                repo_basedir,
+                delta,
+                &state,
+                mtime,
+                &mut writer,
+                &mut summary,
+            )?;
+        }
+    }
+    writer.finish()?.flush()?;
+
+    summary.duration_ms = start.elapsed().as_millis() as u64;
+    Ok(summary)
+}
+
+/// Write `delta`, the changes that led to `upper`, as an OCI image layer.
+/// Changed entries are written along with their parent directories, and
+/// removed ones as `.wh.` whiteout files.
+pub(crate) fn write_oci_layer<W: Write>(
+    repo_basedir: &str,
+    delta: Delta,
+    upper: &FsState,
+    mtime: u64,
+    writer: &mut tar::Writer<W>,
+    summary: &mut ExportSummary,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut changed = delta.upper;
+    let keys: Vec<PString> = changed
+        .children(&PString::from_str("."), true)
+        .into_iter()
+        .map(|(key, _)| key)
+        .chain(&delta.whiteouts)
+        .cloned()
+        .collect();
+    for key in keys {
+        let mut key = key.as_bytes();
+        while let Some(i) = key.iter().rposition(|&c| c == b'/') {
+            key = &key[..i];
+            let dir = PString::from_vec(key.to_vec())?;
+            match upper.dirs.get(&dir) {
+                Some(state) => changed.dirs.insert(dir, state.clone()),
+                None => break,
+            };
+        }
+    }
+    write_tar(repo_basedir, &changed, mtime, writer, summary)?;
+
+    for key in &delta.whiteouts {
+        let path = archive_path(key);
+        let (dir, name) = match path.iter().rposition(|&c| c == b'/') {
+            Some(i) => (&path[..=i], &path[i + 1..]),
+            None => (&path[..0], &path[..]),
+        };
+        let mut whiteout = dir.to_vec();
+        whiteout.extend_from_slice(b".wh.");
+        whiteout.extend_from_slice(name);
+
+        let mut header = tar::Header::new(whiteout, Kind::File);
+        header.mode = 0o644;
+        header.mtime = mtime;
+        writer.append(&header, &mut io::empty())?;
+        summary.whiteouts += 1;
+    }
+    Ok(())
+}
+
+/// Append every entry of `state` to a tar archive. Files that were hard
+/// links to one another are stored once, and then as links to the first
+/// path written.
+pub(crate) fn write_tar<W: Write>(
+    repo_basedir: &str,
+    state: &FsState,
+    mtime: u64,
+    writer: &mut tar::Writer<W>,
+    summary: &mut ExportSummary,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let root = PString::from_str(".");
+    let mut inodes: HashMap<(u64, u64), Vec<u8>> = HashMap::new();
+    for (key, entry) in state.children(&root, true) {
+        let path = archive_path(key);
+        let first = match entry {
+            Entry::File(Object { inode: Some(inode), .. }) => {
+                match inodes.entry(*inode) {
+                    hash_map::Entry::Occupied(first) => Some(first),
+                    hash_map::Entry::Vacant(vacant) => {
+                        vacant.insert(path.clone());
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        let mut header = match entry {
+            Entry::Dir(dir) => {
+                let mut path = path;
+                path.push(b'/');
+                let mut header = tar::Header::new(path, Kind::Dir);
+                header.mode = dir.perms;
+                header.uid = dir.uid;
+                header.gid = dir.gid;
+                header.xattrs = xattr_list(&dir.xattrs);
+                summary.dirs += 1;
+                header
+            }
+            Entry::File(obj) => {
+                let mut header = match first {
+                    Some(first) => {
+                        let mut header =
+                            tar::Header::new(path, Kind::Hardlink);
+                        header.link = first.get().clone();
+                        header
+                    }
+                    None => tar::Header::new(path, Kind::File),
+                };
+                header.mode = obj.perms;
+                header.uid = obj.uid;
+                header.gid = obj.gid;
+                header.xattrs = xattr_list(&obj.xattrs);
+                summary.files += 1;
+                header
+            }
+            Entry::Link(link) => {
+                let mut header = tar::Header::new(path, Kind::Symlink);
+                header.mode = 0o777;
+                header.uid = link.uid;
+                header.gid = link.gid;
+                header.link = link.target.as_bytes().to_vec();
+                header.xattrs = xattr_list(&link.xattrs);
+                summary.links += 1;
+                header
+            }
+        };
+        header.mtime = match entry {
+            // Links keep their own time, which tar can't go before 1970 in.
+            Entry::Link(link) => link.mtime.max(0) as u64,
+            _ => mtime,
+        };
+
+        if header.kind == Kind::Hardlink {
+            writer.append(&header, &mut io::empty())?;
+        } else if let Entry::File(obj) = entry {
+            let mut object = PathBuf::from(repo_basedir);
+            object.push("objects");
+            object.push(&obj.hash);
+            let mut file = File::open(object)?;
+            header.size = file.metadata()?.len();
+            summary.bytes += header.size;
+            writer.append(&header, &mut file)?;
+        } else {
+            writer.append(&header, &mut io::empty())?;
+        }
+    }
+    Ok(())
+}
+
+fn xattr_list(
+    xattrs: &Option<BTreeMap<CString, Vec<u8>>>,
+) -> Vec<(Vec<u8>, Vec<u8>)> {
+    xattrs
+        .iter()
+        .flatten()
+        .map(|(k, v)| (k.as_bytes().to_vec(), v.clone()))
+        .collect()
+}
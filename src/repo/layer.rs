@@ -1,38 +1,145 @@
 use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::error::Error;
-use std::ffi::CString;
-use std::fs::{File, Metadata};
+use std::ffi::{CString, NulError};
+use std::fmt;
+use std::fs::Metadata;
 use std::io::{self, Write};
 use std::os::unix::fs::MetadataExt;
-use std::os::unix::prelude::{AsRawFd, RawFd, OsStrExt};
+use std::os::unix::prelude::{AsRawFd, FromRawFd, OsStrExt, OwnedFd, RawFd};
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
-use libc::{DT_LNK, DT_UNKNOWN, O_DIRECTORY, O_NOFOLLOW, S_IFLNK, S_IFMT};
+use crossbeam_channel::{Receiver, Sender};
+
+use libc::{c_int, O_DIRECTORY, S_IFLNK, S_IFMT};
 use serde::{Deserialize, Serialize};
 
+use crate::progress::{Progress, ProgressMode};
+use crate::repo::config::Config;
 use crate::repo::object;
-use crate::util::queue::{NodeSlice, Queue};
-use crate::util::{
-    self, close, lstatat, openat, os_to_utf, readlinkat, PString,
-};
+use crate::repo::overlay::Delta;
+use crate::repo::spill::{Record, Spill, RUN_LEN};
+use crate::repo::tree::{self, TreeStats};
+use crate::util::queue::{FileOrder, NodeSlice, Queue, QueueOptions};
+use crate::util::queue::Traversal;
+use crate::util::uring::IoBackend;
+use crate::util::xattr::{self, Target, XattrFilter};
+use crate::util::{self, close, fd_path, lstatat, readlinkat, PString};
+use crate::util::{open_beneath, open_path_beneath};
+
+/// What `statx` tells us about an entry beyond what `stat` does.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub struct StatxInfo {
+    /// When the entry was created, if the filesystem keeps track.
+    pub(crate) btime: Option<(i64, u32)>,
+    /// Which of the immutable, append-only and nodump flags it has, as
+    /// `util::ATTR_*` bits.
+    pub(crate) flags: u32,
+}
+
+impl StatxInfo {
+    pub(crate) fn of(stat: &util::Stat) -> StatxInfo {
+        StatxInfo {
+            btime: stat.btime,
+            flags: stat.attributes as u32 & util::KEPT_ATTRS,
+        }
+    }
+}
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Object {
+    pub(crate) hash: String,
+    pub(crate) perms: u32,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) xattrs: Option<BTreeMap<CString, Vec<u8>>>,
+    pub(crate) statx: StatxInfo,
+    /// The device and inode number it was found at, if other paths were
+    /// hard links to it too, so that exports can link them up again.
+    pub(crate) inode: Option<(u64, u64)>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirState {
+    pub(crate) perms: u32,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) xattrs: Option<BTreeMap<CString, Vec<u8>>>,
+    pub(crate) statx: StatxInfo,
+}
+
+/// A symlink, with the owner, modification time and attributes of the link
+/// itself.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Link {
+    pub(crate) target: PString,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) mtime: i64,
+    pub(crate) mtime_nsec: u32,
+    pub(crate) xattrs: Option<BTreeMap<CString, Vec<u8>>>,
+    pub(crate) statx: StatxInfo,
+}
+
+impl Link {
+    /// A link we know only the target of, as stored before links had any
+    /// metadata of their own.
+    pub(crate) fn bare(target: PString) -> Link {
+        Link {
+            target,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            mtime_nsec: 0,
+            xattrs: None,
+            statx: StatxInfo::default(),
+        }
+    }
+}
+
+/// `Object` as full layers stored it.
+#[derive(Deserialize)]
+struct ObjectV1 {
     hash: String,
     perms: u32,
     uid: u32,
     gid: u32,
-    xattrs: Option<BTreeMap<String, Vec<u8>>>,
+    xattrs: Option<BTreeMap<CString, Vec<u8>>>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct DirState {
+impl From<ObjectV1> for Object {
+    fn from(old: ObjectV1) -> Object {
+        let ObjectV1 { hash, perms, uid, gid, xattrs } = old;
+        let statx = StatxInfo::default();
+        Object { hash, perms, uid, gid, xattrs, statx, inode: None }
+    }
+}
+
+/// `DirState` as full layers stored it.
+#[derive(Deserialize)]
+struct DirStateV1 {
     perms: u32,
     uid: u32,
     gid: u32,
-    xattrs: Option<BTreeMap<String, Vec<u8>>>,
+    xattrs: Option<BTreeMap<CString, Vec<u8>>>,
+}
+
+impl From<DirStateV1> for DirState {
+    fn from(old: DirStateV1) -> DirState {
+        let DirStateV1 { perms, uid, gid, xattrs } = old;
+        DirState { perms, uid, gid, xattrs, statx: StatxInfo::default() }
+    }
+}
+
+/// Convert every value of a map stored in an older format.
+fn upgrade<V: Into<W>, W>(
+    old: BTreeMap<PString, V>,
+) -> BTreeMap<PString, W> {
+    old.into_iter().map(|(key, v)| (key, v.into())).collect()
 }
 
 pub struct Layer {
@@ -40,28 +147,313 @@ pub struct Layer {
     timestamp: u64,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct FsState {
-    dirs: BTreeMap<PString, DirState>,
-    objects: BTreeMap<PString, Object>,
+/// Every entry in a layer, keyed by its path relative to the imported root,
+/// which always starts with `./`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct FsState {
+    pub(crate) dirs: BTreeMap<PString, DirState>,
+    pub(crate) objects: BTreeMap<PString, Object>,
+    pub(crate) links: BTreeMap<PString, Link>,
+}
+
+/// `FsState` as full layers stored it, before link targets were raw bytes
+/// and symlinks had metadata. Xattr names were strings, which bincode
+/// stores just like the `CString`s they are now.
+#[derive(Deserialize)]
+struct FsStateV1 {
+    dirs: BTreeMap<PString, DirStateV1>,
+    objects: BTreeMap<PString, ObjectV1>,
     links: BTreeMap<PString, String>,
 }
 
-impl FsState {
-    fn extend(&mut self, other: Self) {
-        self.dirs.extend(other.dirs);
-        self.objects.extend(other.objects);
+impl TryFrom<FsStateV1> for FsState {
+    type Error = NulError;
+
+    fn try_from(old: FsStateV1) -> Result<FsState, NulError> {
+        let mut links = BTreeMap::new();
+        for (key, target) in old.links {
+            let target = PString::from_vec(target.into_bytes())?;
+            links.insert(key, Link::bare(target));
+        }
+        Ok(FsState {
+            dirs: upgrade(old.dirs),
+            objects: upgrade(old.objects),
+            links,
+        })
     }
 }
 
 impl FsState {
-    fn new() -> FsState {
+    pub(crate) fn new() -> FsState {
         FsState {
             dirs: BTreeMap::new(),
             objects: BTreeMap::new(),
             links: BTreeMap::new(),
         }
     }
+
+    /// Load a layer from the repository, given its hash or a unique prefix.
+    /// Delta layers are flattened onto their parents, so the result is
+    /// always the whole tree.
+    pub(crate) fn load(
+        repo_basedir: &str,
+        layer: &str,
+    ) -> Result<FsState, Box<dyn Error + Send + Sync>> {
+        let mut deltas = vec![];
+        let mut hash = resolve(repo_basedir, layer)?;
+        let mut state = loop {
+            match read_layer(repo_basedir, &hash)? {
+                Stored::Full(state) => break state,
+                Stored::Tree(root) => break tree::load(repo_basedir, &root)?,
+                Stored::Delta(layer) => {
+                    hash = layer.parent;
+                    deltas.push(layer.delta);
+                }
+            }
+        };
+
+        for delta in deltas.iter().rev() {
+            state.apply(delta);
+        }
+        Ok(state)
+    }
+
+    /// Load only what is needed to look at `keys` in a layer: their parent
+    /// directories, and everything at or below each key, or just what is
+    /// immediately below it unless `recursive` is set. Only layers stored as
+    /// trees can be loaded in part; others are loaded whole.
+    pub(crate) fn load_paths(
+        repo_basedir: &str,
+        layer: &str,
+        keys: &[PString],
+        recursive: bool,
+    ) -> Result<FsState, Box<dyn Error + Send + Sync>> {
+        let hash = resolve(repo_basedir, layer)?;
+        match read_layer(repo_basedir, &hash)? {
+            Stored::Tree(root) => {
+                tree::load_paths(repo_basedir, &root, keys, recursive)
+            }
+            _ => FsState::load(repo_basedir, &hash),
+        }
+    }
+}
+
+/// Work out the changes from layer `lower`, or from nothing, to `upper`.
+/// When both are stored as trees, subtrees they share are skipped.
+pub(crate) fn diff(
+    repo_basedir: &str,
+    lower: Option<&str>,
+    upper: &str,
+) -> Result<Delta, Box<dyn Error + Send + Sync>> {
+    let upper = resolve(repo_basedir, upper)?;
+    let lower = match lower {
+        Some(lower) => resolve(repo_basedir, lower)?,
+        None => return Ok(FsState::load(repo_basedir, &upper)?.diff(&FsState::new())),
+    };
+    match (read_layer(repo_basedir, &lower)?, read_layer(repo_basedir, &upper)?) {
+        (Stored::Tree(lower), Stored::Tree(upper)) => {
+            tree::diff(repo_basedir, &lower, &upper)
+        }
+        _ => {
+            let lower = FsState::load(repo_basedir, &lower)?;
+            Ok(FsState::load(repo_basedir, &upper)?.diff(&lower))
+        }
+    }
+}
+
+/// Delta and tree layers start with these, which can't be the start of a
+/// bincoded `FsState` as it would claim an absurd number of directories.
+const DELTA_MAGIC: &[u8; 8] = b"banyanD1";
+const TREE_MAGIC: &[u8; 8] = b"banyanT1";
+
+/// A layer stored as a tree of per-directory objects.
+#[derive(Debug, Serialize, Deserialize)]
+struct TreeLayer {
+    /// Hash of the tree for the root directory.
+    root: String,
+}
+
+/// A layer stored as its differences from a parent layer.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct DeltaLayer {
+    /// Full hash of the parent, which may itself be a delta layer.
+    pub(crate) parent: String,
+    pub(crate) delta: Delta,
+}
+
+/// A layer as it is stored in the repository. Layers used to be stored
+/// whole as a single `FsState`; new ones are stored as trees.
+pub(crate) enum Stored {
+    Full(FsState),
+    Tree(String),
+    Delta(DeltaLayer),
+}
+
+/// Read a single layer, given its full hash, without resolving its parents.
+pub(crate) fn read_layer(
+    repo_basedir: &str,
+    hash: &str,
+) -> Result<Stored, Box<dyn Error + Send + Sync>> {
+    let mut path = PathBuf::from(repo_basedir);
+    path.push("layers");
+    path.push(hash);
+    let ser = std::fs::read(path)?;
+    Ok(if let Some(rest) = ser.strip_prefix(DELTA_MAGIC) {
+        Stored::Delta(bincode::deserialize(rest)?)
+    } else if let Some(rest) = ser.strip_prefix(TREE_MAGIC) {
+        Stored::Tree(bincode::deserialize::<TreeLayer>(rest)?.root)
+    } else {
+        let old: FsStateV1 = bincode::deserialize(&ser)?;
+        Stored::Full(FsState::try_from(old)?)
+    })
+}
+
+/// The full hashes of a layer and each of its parents, topmost first.
+pub(crate) fn stack(
+    repo_basedir: &str,
+    layer: &str,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let mut hash = resolve(repo_basedir, layer)?;
+    let mut stack = vec![];
+    loop {
+        let stored = read_layer(repo_basedir, &hash)?;
+        stack.push(hash);
+        match stored {
+            Stored::Full(_) | Stored::Tree(_) => return Ok(stack),
+            Stored::Delta(layer) => hash = layer.parent,
+        }
+    }
+}
+
+/// A borrowed entry of any kind in an `FsState`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Entry<'a> {
+    Dir(&'a DirState),
+    File(&'a Object),
+    Link(&'a Link),
+}
+
+impl FsState {
+    /// Look up a single entry by its key.
+    pub(crate) fn get(&self, key: &PString) -> Option<Entry<'_>> {
+        self.get_key_value(key).map(|(_, entry)| entry)
+    }
+
+    /// Look up a single entry by its key, also returning the stored key.
+    pub(crate) fn get_key_value(
+        &self,
+        key: &PString,
+    ) -> Option<(&PString, Entry<'_>)> {
+        if let Some((key, dir)) = self.dirs.get_key_value(key) {
+            Some((key, Entry::Dir(dir)))
+        } else if let Some((key, obj)) = self.objects.get_key_value(key) {
+            Some((key, Entry::File(obj)))
+        } else {
+            self.links
+                .get_key_value(key)
+                .map(|(key, link)| (key, Entry::Link(link)))
+        }
+    }
+
+    /// All entries below the directory `dir`, sorted by path. Unless
+    /// `recursive` is set, only its immediate children are returned.
+    pub(crate) fn children<'a>(
+        &'a self,
+        dir: &PString,
+        recursive: bool,
+    ) -> Vec<(&'a PString, Entry<'a>)> {
+        let prefix = dir.append_path(&CString::default());
+        let wanted = |path: &PString| {
+            let rest = &path.as_bytes()[prefix.as_bytes().len()..];
+            recursive || !rest.contains(&b'/')
+        };
+
+        let mut res = vec![];
+        for (path, v) in under(&self.dirs, &prefix).filter(|e| wanted(e.0)) {
+            res.push((path, Entry::Dir(v)));
+        }
+        for (path, v) in under(&self.objects, &prefix).filter(|e| wanted(e.0)) {
+            res.push((path, Entry::File(v)));
+        }
+        for (path, v) in under(&self.links, &prefix).filter(|e| wanted(e.0)) {
+            res.push((path, Entry::Link(v)));
+        }
+
+        res.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        res
+    }
+}
+
+/// Every entry of `map` whose key starts with `prefix`. Keys are sorted
+/// bytewise, so these are contiguous and start at `prefix` itself.
+pub(crate) fn under<'a: 'p, 'p, V>(
+    map: &'a BTreeMap<PString, V>,
+    prefix: &'p PString,
+) -> impl Iterator<Item = (&'a PString, &'a V)> + 'p {
+    map.range(prefix..)
+        .take_while(move |(path, _)| {
+            path.as_bytes().starts_with(prefix.as_bytes())
+        })
+}
+
+/// Convert a user-supplied path within a layer into an `FsState` key, so
+/// that `/a/b`, `a/b/` and `./a/b` all refer to `./a/b`.
+pub(crate) fn layer_key(path: &[u8]) -> Result<PString, NulError> {
+    let mut path = path;
+    loop {
+        if let Some(rest) = path.strip_prefix(b"/") {
+            path = rest;
+        } else if let Some(rest) = path.strip_prefix(b"./") {
+            path = rest;
+        } else {
+            break;
+        }
+    }
+    while let Some(rest) = path.strip_suffix(b"/") {
+        path = rest;
+    }
+
+    let mut key = b".".to_vec();
+    if !path.is_empty() && path != b"." {
+        key.push(b'/');
+        key.extend_from_slice(path);
+    }
+    PString::from_vec(key)
+}
+
+/// Find the full hash of the layer named by `prefix`.
+pub(crate) fn resolve(
+    repo_basedir: &str,
+    prefix: &str,
+) -> Result<String, io::Error> {
+    let mut path = PathBuf::from(repo_basedir);
+    path.push("layers");
+
+    let mut found: Option<String> = None;
+    for entry in std::fs::read_dir(path)? {
+        let name = entry?.file_name();
+        let name = match name.to_str() {
+            Some(name) if name.starts_with(prefix) => name,
+            _ => continue,
+        };
+        if name == prefix {
+            return Ok(name.to_owned());
+        }
+        if found.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("layer prefix {} is ambiguous", prefix),
+            ));
+        }
+        found = Some(name.to_owned());
+    }
+
+    found.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no layer named {}", prefix),
+        )
+    })
 }
 
 #[derive(Debug)]
@@ -74,8 +466,115 @@ struct Work {
 }
 
 struct WalkOptions {
+    /// The directory being imported, which keys are relative to.
+    root: PathBuf,
     ignore_errors: bool,
+    /// The root's device, with `--same-device`, which directories on other
+    /// devices are kept from being descended into.
     root_device: Option<u64>,
+    xattrs: XattrFilter,
+    /// The layer this one will be a delta from, if any.
+    parent: Option<Parent>,
+    /// Clone files into the store where the filesystem can.
+    reflink: bool,
+    /// Batch syscalls through io_uring.
+    uring: bool,
+    /// Leave out entries marked nodump.
+    honor_nodump: bool,
+    /// How many files the walk may have open at once.
+    fd_limit: u64,
+    traversal: Traversal,
+    file_order: FileOrder,
+}
+
+impl WalkOptions {
+    /// Options for walking `root` that stop at the first error and keep
+    /// every extended attribute.
+    fn new(root: PathBuf, same_device: bool) -> io::Result<WalkOptions> {
+        let dev = root.metadata()?.dev();
+        Ok(WalkOptions {
+            root,
+            ignore_errors: false,
+            root_device: if same_device { Some(dev) } else { None },
+            xattrs: XattrFilter::ALL,
+            parent: None,
+            reflink: false,
+            uring: false,
+            honor_nodump: false,
+            fd_limit: util::nofile_limit(false)?,
+            traversal: Traversal::Breadth,
+            file_order: FileOrder::Dirent,
+        })
+    }
+}
+
+/// The files of the layer an import is a delta from, to guess which files
+/// haven't changed.
+enum Parent {
+    /// Looked up a directory at a time.
+    Tree(tree::Lookup),
+    /// Layers stored otherwise are loaded whole.
+    Full(FsState),
+}
+
+impl Parent {
+    fn load(
+        repo_basedir: &str,
+        hash: &str,
+    ) -> Result<Parent, Box<dyn Error + Send + Sync>> {
+        Ok(match read_layer(repo_basedir, hash)? {
+            Stored::Tree(root) => {
+                Parent::Tree(tree::Lookup::new(repo_basedir, &root))
+            }
+            _ => Parent::Full(FsState::load(repo_basedir, hash)?),
+        })
+    }
+
+    /// The file at `key`, if there is one.
+    fn file(
+        &self,
+        key: &PString,
+    ) -> Result<Option<Object>, Box<dyn Error + Send + Sync>> {
+        Ok(match self {
+            Parent::Tree(lookup) => match lookup.get(key)? {
+                Some(tree::Node::File(obj)) => Some(obj),
+                _ => None,
+            },
+            Parent::Full(state) => state.objects.get(key).cloned(),
+        })
+    }
+}
+
+/// Options for `import`, as given on the command line.
+pub struct ImportOptions {
+    /// Do not traverse across block devices.
+    pub same_device: bool,
+    /// How to report progress while walking.
+    pub progress: ProgressMode,
+    /// Per-entry logging level; 1 logs files, 2 also logs dirs and links.
+    pub verbose: i32,
+    /// Store the layer as a delta on top of this layer.
+    pub delta_from: Option<String>,
+    /// Which extended attributes to keep.
+    pub xattrs: XattrFilter,
+    /// Threads walking directories, or `default_threads()`.
+    pub walk_threads: Option<usize>,
+    /// Threads hashing and storing files, or `default_threads()`.
+    pub hash_threads: Option<usize>,
+    /// Whether to batch syscalls through io_uring.
+    pub io_backend: IoBackend,
+    /// Leave out entries marked nodump (`chattr +d`), and everything under
+    /// them.
+    pub honor_nodump: bool,
+    /// Open at most this many files at once, or as many as `RLIMIT_NOFILE`
+    /// allows.
+    pub max_open_files: Option<u64>,
+    /// Raise the soft `RLIMIT_NOFILE` to the hard one first.
+    pub raise_fd_limit: bool,
+    /// Which entries to visit first.
+    pub traversal: Traversal,
+    /// The order to visit each directory's entries in.
+    pub file_order: FileOrder,
 }
 
 #[derive(Debug)]
@@ -84,18 +583,153 @@ struct WalkError {
     error: io::Error,
 }
 
-struct NQWorker {
-    queue: Arc<Queue>,
-    /// Whether all workers should terminate at the next opportunity. Note
-    /// that we need this because we don't want other `Work` to be done after
-    /// we quit. We wouldn't need this if have a priority channel.
-    quit_now: Arc<AtomicBool>,
-    active_workers: Arc<AtomicUsize>,
-    state: FsState,
+impl fmt::Display for WalkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.error)
+    }
+}
+
+impl std::error::Error for WalkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// What the walkers and hashers have in common: somewhere to put the
+/// entries they find, and to report errors to.
+struct Collector {
+    /// Entries found since the last time they were spilled.
+    entries: Vec<(PString, Record)>,
+    spill: Arc<Spill>,
     options: Arc<WalkOptions>,
     errors: Arc<Mutex<Vec<WalkError>>>,
+    /// Set when an error should end the walk.
+    stop: Arc<AtomicBool>,
+    progress: Arc<Progress>,
+}
+
+impl Collector {
+    /// Record an entry, spilling them all to disk once there are enough.
+    fn push(&mut self, path: PString, record: Record) -> io::Result<()> {
+        self.entries.push((path, record));
+        if self.entries.len() >= RUN_LEN {
+            self.spill.spill(&mut self.entries)?;
+        }
+        Ok(())
+    }
+
+    fn handle_error(&mut self, path: &PString, error: io::Error) -> WalkState {
+        Progress::add(&self.progress.errors, 1);
+        if self.progress.logging(1) {
+            self.progress.log(format_args!("error: {}: {}", path, error));
+        }
+        // Running out of descriptors isn't down to this entry, and would
+        // leave out whatever else runs into it, so it ends the walk.
+        let fatal = out_of_fds(&error);
+        self.errors
+            .lock()
+            .unwrap()
+            .push(WalkError { path: path.clone(), error });
+
+        match self.options.ignore_errors && !fatal {
+            true => WalkState::Continue,
+            false => {
+                self.stop.store(true, Ordering::Relaxed);
+                WalkState::Quit
+            }
+        }
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.spill.spill(&mut self.entries)
+    }
+}
+
+/// A file found by a walker, waiting to be hashed and stored.
+struct FileJob {
+    path: PString,
+    /// Open for reading, and closed by the hasher.
     fd: RawFd,
-    objectfd: RawFd,
+    stat: util::Stat,
+}
+
+/// Files waiting for a hasher, per hasher. Each holds a file descriptor.
+const FILE_JOBS_PER_HASHER: usize = 16;
+
+/// Descriptors set aside for everything but walking and hashing: standard
+/// streams, the repository, runs being spilled or merged, and so on.
+const RESERVED_FDS: u64 = 64;
+
+fn out_of_fds(error: &io::Error) -> bool {
+    matches!(error.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+}
+
+/// How a walk shares out the descriptors it may have open.
+#[derive(Clone, Copy, Debug)]
+struct FdBudget {
+    /// Directories the queue may keep open for their entries.
+    dirs: usize,
+    /// Entries a walker takes off the queue at once, with io_uring.
+    batch: usize,
+}
+
+/// How to share out `limit` descriptors, once the walkers and hashers have
+/// the least they need.
+fn fd_budget(
+    limit: u64,
+    threads: Threads,
+    uring: bool,
+) -> Result<FdBudget, Box<dyn Error + Send + Sync>> {
+    // The entry a walker is visiting, the directory it's in if the walker
+    // had to open it again, a file it's sorting by extent, and its ring.
+    let walker = if uring { 4 } else { 3 };
+    // Files waiting for a hasher, and the one it's storing along with its
+    // temporary object and its ring.
+    let hasher = FILE_JOBS_PER_HASHER as u64 + 3;
+    let needed = RESERVED_FDS
+        + threads.walk as u64 * walker
+        + threads.hash as u64 * hasher;
+    let left = match limit.checked_sub(needed) {
+        Some(left) if left > 0 => left,
+        _ => {
+            return Err(format!(
+                "{} walkers and {} hashers need more than {} open files; \
+                 raise the limit with `ulimit -n` or --raise-fd-limit, or \
+                 use fewer threads",
+                threads.walk, threads.hash, limit
+            )
+            .into())
+        }
+    };
+    // Each more entry in a batch takes up to two more descriptors, and
+    // batches get at most a quarter of what's left.
+    let mut batch = 1;
+    if uring {
+        let more = left / 4 / (2 * threads.walk as u64);
+        batch += more.min(BATCH as u64 - 1);
+    }
+    let dirs = left - threads.walk as u64 * 2 * (batch - 1);
+    Ok(FdBudget {
+        dirs: dirs.min(usize::MAX as u64) as usize,
+        batch: batch as usize,
+    })
+}
+
+struct NQWorker {
+    queue: Arc<Queue>,
+    files: Sender<FileJob>,
+    found: Collector,
+    /// The directory being imported, open for the whole walk.
+    root: RawFd,
+    /// Directories the queue didn't keep open, opened again to visit their
+    /// entries.
+    reopened: Vec<(Arc<PString>, OwnedFd)>,
+    /// Entries to take off the queue at once, with io_uring.
+    batch: usize,
 }
 
 enum WalkState {
@@ -103,38 +737,64 @@ enum WalkState {
     Quit,
 }
 
+impl Drop for NQWorker {
+    fn drop(&mut self) {
+        // The others would wait forever for a worker that panicked.
+        if std::thread::panicking() {
+            self.queue.close();
+        }
+    }
+}
+
+fn perms(stat: &libc::stat) -> u32 {
+    stat.st_mode & (libc::S_IRWXU | libc::S_IRWXG | libc::S_IRWXO)
+}
+
+/// How a walker opens an entry with this metadata, besides never following
+/// links, or `None` for a symlink, which isn't opened at all.
+fn open_flags(stat: &libc::stat) -> Option<c_int> {
+    if stat.st_mode & S_IFMT == S_IFLNK {
+        return None;
+    }
+    let dir = stat.st_mode & S_IFMT == libc::S_IFDIR;
+    Some(if dir { O_DIRECTORY } else { 0 })
+}
+
+/// Refuse entries a layer can't hold before they are opened, which for a
+/// fifo would block, and would have a device read as if it were a file.
+fn supported(stat: &libc::stat) -> io::Result<()> {
+    match stat.st_mode & S_IFMT {
+        libc::S_IFREG | libc::S_IFDIR | S_IFLNK => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unsupported file type",
+        )),
+    }
+}
+
+/// Entries a walker takes off the queue at once, with io_uring, at most.
+const BATCH: usize = 64;
+
 impl NQWorker {
     /// Runs this worker until there is no more work left to do.
     ///
-    /// The worker will call the caller's callback for all entries that aren't
-    /// skipped by the ignore matcher.
-    fn run(mut self) -> FsState {
-        while !self.should_quit() {
-            match self.queue.advance() {
-                Some(dent) => {
-                    // Add to current working thread count, process work item
-                    // and then subtract when done.
-                    self.active_workers.fetch_add(1, Ordering::Relaxed);
-                    let res = self.visit(dent);
-                    self.active_workers.fetch_sub(1, Ordering::Relaxed);
-                    
-                    // If we're expected to quit, quit now.
-                    if let WalkState::Quit = res {
-                        self.quit_now();
-                        break;
-                    }
-                }
-                // If there's nothing in the queue, and no threads are busy,
-                // then we end the worker. If there's work being done, we spin.
-                None => {
-                    if self.active_workers.load(Ordering::Relaxed) == 0 {
-                        break;
-                    }
-                }
+    /// Directories and links are recorded as they are found, and files are
+    /// passed on to the hashers.
+    fn run(mut self) -> io::Result<()> {
+        #[cfg(feature = "io-uring")]
+        if self.found.options.uring {
+            return self.run_batched();
+        }
+        // The queue sleeps until there is more to do, and runs dry once
+        // every worker is waiting on it.
+        while let Some(dent) = self.queue.next() {
+            // If we're expected to quit, stop everyone now.
+            if let WalkState::Quit = self.visit(dent) {
+                self.queue.close();
+                break;
             }
         }
-
-        self.state
+        self.found.finish()
     }
 
     fn visit(&mut self, dent: NodeSlice) -> WalkState {
@@ -142,175 +802,642 @@ impl NQWorker {
         if fname == CString::new(".").unwrap().as_ref() || fname == CString::new("..").unwrap().as_ref() {
             return WalkState::Continue;
         }
+        if self.found.stopped() {
+            return WalkState::Quit;
+        }
 
-        let path = dent.fullpath();
-
-        match self.visit_internal(&dent, path) {
+        // Only one directory is opened again at a time.
+        let dir = dent.basedir();
+        self.reopened.retain(|(reopened, _)| Arc::ptr_eq(reopened, dir));
+        match self.visit_internal(&dent) {
             Ok(_) => WalkState::Continue,
-            Err(e) => self.handle_error(&dent.fullpath(), e),
+            Err(e) => self.found.handle_error(&dent.fullpath(), e),
         }
     }
 
-    fn handle_error(&mut self, path: &PString, error: io::Error) -> WalkState {
-        self.errors
-            .lock()
-            .unwrap()
-            .push(WalkError { path: path.clone(), error });
+    /// The directory `dent` is in: kept open by the queue, or opened again
+    /// from the root, neither through a link nor outside of it. Opened
+    /// again, it has to be the directory that was read, which it isn't if
+    /// that was moved in the meantime.
+    fn dirfd(&mut self, dent: &NodeSlice) -> io::Result<RawFd> {
+        if let Some(fd) = dent.dirfd() {
+            return Ok(fd);
+        }
+        let dir = dent.basedir();
+        let reopened = self.reopened.iter().find(|(d, _)| Arc::ptr_eq(d, dir));
+        if let Some((_, fd)) = reopened {
+            return Ok(fd.as_raw_fd());
+        }
+        let path: &PString = dir;
+        let fd = open_path_beneath(self.root, path.as_ref(), O_DIRECTORY)?;
+        // SAFETY: nothing else has the descriptor we just opened.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let stat = lstatat(fd.as_raw_fd(), &CString::new(".")?)?;
+        if dent.closed_dir() != Some((stat.st_dev, stat.st_ino)) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "directory was moved during the walk",
+            ));
+        }
+        self.reopened.push((dir.clone(), fd));
+        Ok(self.reopened.last().unwrap().1.as_raw_fd())
+    }
 
-        match self.options.ignore_errors {
-            true => WalkState::Continue,
-            false => WalkState::Quit,
+    /// Everything is looked up by name in the directory it was found in,
+    /// which the queue keeps open, and is never reached through a link, so
+    /// whatever happens to the tree in the meantime, the walk stays in it.
+    fn visit_internal(&mut self, dent: &NodeSlice) -> io::Result<()> {
+        let path = dent.fullpath();
+        let dirfd = self.dirfd(dent)?;
+        let stat = util::lstatxat(dirfd, dent.filename())?;
+        if self.nodump(&path, &stat) {
+            return Ok(());
+        }
+        supported(&stat.st)?;
+        let fd = match open_flags(&stat.st) {
+            Some(flags) => Some(open_beneath(dirfd, dent.filename(), flags)?),
+            None => None,
+        };
+        self.visit_stat(dirfd, dent, path, &stat, fd)
+    }
+
+    /// Whether to leave out `path`, and everything under it, for being
+    /// marked nodump, which `--honor-nodump` says to.
+    fn nodump(&self, path: &PString, stat: &util::Stat) -> bool {
+        let options = &self.found.options;
+        let nodump = stat.attributes & util::ATTR_NODUMP as u64 != 0;
+        if !(options.honor_nodump && nodump) {
+            return false;
+        }
+        let progress = &self.found.progress;
+        if progress.logging(2) {
+            progress.log(format_args!("N {}", path));
         }
+        true
     }
 
-    fn visit_internal(
+    /// Record what's at `dent`, in the directory `dirfd`, whose key is
+    /// `path`, given its metadata and, unless it is a link, an open file
+    /// descriptor for it, which is passed on to a hasher or closed.
+    fn visit_stat(
         &mut self,
+        dirfd: RawFd,
         dent: &NodeSlice,
         path: PString,
-    ) -> Result<(), std::io::Error> {
-        let (stat, link) = if dent.filetype() == DT_UNKNOWN {
-            let stat = util::lstatat(self.fd, path.as_ref())?;
-            let link = stat.st_mode & S_IFMT == S_IFLNK;
-            (Some(stat), link)
-        } else {
-            (None, dent.filetype() == DT_LNK)
+        stat: &util::Stat,
+        fd: Option<RawFd>,
+    ) -> io::Result<()> {
+
+        let progress = &self.found.progress;
+        let options = &self.found.options;
+        let fd = match fd {
+            Some(fd) => fd,
+            None => {
+                let target = readlinkat(dirfd, dent.filename())?;
+                Progress::add(&progress.links, 1);
+                if progress.logging(2) {
+                    progress.log(format_args!("L {} -> {}", path, target));
+                }
+                // Symlinks can't be opened, so their attributes are read by
+                // path, without following the link, through the directory's
+                // descriptor. Without `/proc` there is no such path, and one
+                // from the root could lead to another link by now, so they
+                // are left out and counted.
+                let xattrs = match fd_path(dirfd, dent.filename()) {
+                    Some(full) => {
+                        xattr::read(Target::Link(&full), &options.xattrs)?
+                    }
+                    None => {
+                        if options.xattrs != XattrFilter::NONE {
+                            Progress::add(&progress.link_xattrs_skipped, 1);
+                        }
+                        None
+                    }
+                };
+                return self.found.push(
+                    path,
+                    Record::Link(Link {
+                        target,
+                        uid: stat.st.st_uid,
+                        gid: stat.st.st_gid,
+                        mtime: stat.st.st_mtime,
+                        mtime_nsec: stat.st.st_mtime_nsec as u32,
+                        xattrs,
+                        statx: StatxInfo::of(stat),
+                    }),
+                );
+            }
         };
 
-        if link {
-            let link = readlinkat(self.fd, path.as_ref())?;
-            self.state.links.insert(path, os_to_utf(link.as_os_str())?);
-            return Ok(());
+        if stat.st.st_mode & S_IFMT != libc::S_IFDIR {
+            // we assume its a file, TOCTOU be damned
+            let job = FileJob { path, fd, stat: *stat };
+            return match self.files.send(job) {
+                Ok(()) => Ok(()),
+                // Every hasher is gone, which only happens if they panicked.
+                Err(job) => {
+                    close(job.0.fd)?;
+                    Err(io::Error::other("no hashers left"))
+                }
+            };
         }
 
-        let stat = match stat {
-            Some(stat) => stat,
-            None => lstatat(self.fd, path.as_ref())?,
-        };
+        // SAFETY: nothing else has the descriptor.
+        self.dir(unsafe { OwnedFd::from_raw_fd(fd) }, path, stat)
+    }
+
+    /// Like `run`, but taking entries off the queue `batch` at a time, to
+    /// stat and open each batch with a couple of trips through io_uring.
+    #[cfg(feature = "io-uring")]
+    fn run_batched(mut self) -> io::Result<()> {
+        let mut batch = Vec::with_capacity(self.batch);
+        while let Some(dent) = self.queue.next() {
+            batch.push(dent);
+            while batch.len() < self.batch {
+                match self.queue.advance() {
+                    Some(dent) => batch.push(dent),
+                    None => break,
+                }
+            }
+            if let WalkState::Quit = self.visit_batch(&mut batch) {
+                self.queue.close();
+                break;
+            }
+        }
+        self.found.finish()
+    }
 
-        // TODO: check if same device
+    #[cfg(feature = "io-uring")]
+    fn visit_batch(&mut self, batch: &mut Vec<NodeSlice>) -> WalkState {
+        use std::ffi::CStr;
 
-        let dir = stat.st_mode & libc::S_IFMT == libc::S_IFDIR;
+        use crate::util::uring;
 
-        let fd = openat(
-            self.fd,
-            path.as_ref(),
-            O_NOFOLLOW & if dir { O_DIRECTORY } else { 0 },
-        )?;
-        if dir {
-            self.queue.add_folder(fd, Arc::new(path.clone()))?;
-            self.state.dirs.insert(
-                path,
-                DirState {
-                    perms: stat.st_mode
-                        & (libc::S_IRWXU | libc::S_IRWXG | libc::S_IRWXO),
-                    uid: stat.st_uid,
-                    gid: stat.st_gid,
-                    xattrs: util::xattrs(fd)?,
-                },
-            );
-        } else {
-            // we assume its a file, TOCTOU be damned
-            let hash = object::import(fd, self.objectfd)?;
-            self.state.objects.insert(
-                path,
-                Object {
-                    hash,
-                    perms: stat.st_mode
-                        & (libc::S_IRWXU | libc::S_IRWXG | libc::S_IRWXO),
-                    uid: stat.st_uid,
-                    gid: stat.st_gid,
-                    xattrs: util::xattrs(fd)?,
-                },
-            );
+        let dents: Vec<NodeSlice> = batch
+            .drain(..)
+            .filter(|dent| {
+                let fname = dent.filename().to_bytes();
+                fname != b"." && fname != b".."
+            })
+            .collect();
+        if self.found.stopped() {
+            return WalkState::Quit;
         }
 
-        close(fd)?;
+        // Entries whose directory can't be opened again are looked up in no
+        // directory at all, and fail like it did.
+        self.reopened.clear();
+        let mut lost = Vec::with_capacity(dents.len());
+        let mut dirfds = Vec::with_capacity(dents.len());
+        for dent in &dents {
+            match self.dirfd(dent) {
+                Ok(fd) => dirfds.push(fd),
+                Err(e) => {
+                    dirfds.push(-1);
+                    lost.push((dirfds.len() - 1, e));
+                }
+            }
+        }
 
-        Ok(())
-    }
+        // If the ring fails, which it shouldn't, do it the slow way.
+        let paths: Vec<PString> = dents.iter().map(|d| d.fullpath()).collect();
+        let names: Vec<(RawFd, &CStr)> = dirfds
+            .iter()
+            .zip(&dents)
+            .map(|(fd, dent)| (*fd, dent.filename()))
+            .collect();
+        let mut stats = uring::lstat_all(&names).unwrap_or_else(|_| {
+            names.iter().map(|(fd, name)| util::lstatxat(*fd, name)).collect()
+        });
+        for (i, e) in lost {
+            stats[i] = Err(e);
+        }
+        let wanted: Vec<bool> = paths
+            .iter()
+            .zip(&stats)
+            .map(|(path, stat)| match stat {
+                Ok(stat) => !self.nodump(path, stat),
+                Err(_) => true,
+            })
+            .collect();
+        for stat in &mut stats {
+            let refused = stat.as_ref().map(|stat| supported(&stat.st));
+            if let Ok(Err(e)) = refused {
+                *stat = Err(e);
+            }
+        }
+        let opens: Vec<_> = names
+            .iter()
+            .zip(&stats)
+            .zip(&wanted)
+            .filter(|(_, &wanted)| wanted)
+            .filter_map(|(((dirfd, name), stat), _)| {
+                Some((*dirfd, *name, open_flags(&stat.as_ref().ok()?.st)?))
+            })
+            .collect();
+        let fds = uring::open_all(&opens).unwrap_or_else(|_| {
+            opens
+                .iter()
+                .map(|(dirfd, name, flags)| open_beneath(*dirfd, name, *flags))
+                .collect()
+        });
 
-    /// Indicates that all workers should quit immediately.
-    fn quit_now(&self) {
-        self.quit_now.store(true, Ordering::Release);
+        let mut fds = fds.into_iter();
+        let mut state = WalkState::Continue;
+        let entries = dents.iter().zip(dirfds).zip(paths).zip(stats);
+        for ((((dent, dirfd), path), stat), wanted) in entries.zip(wanted) {
+            if !wanted {
+                continue;
+            }
+            let fd = match &stat {
+                Ok(stat) if open_flags(&stat.st).is_some() => fds.next(),
+                _ => None,
+            };
+            if let WalkState::Quit = state {
+                // Everyone is stopping, so just tidy up.
+                if let Some(Ok(fd)) = fd {
+                    let _ = close(fd);
+                }
+                continue;
+            }
+            let res = stat.and_then(|stat| {
+                let fd = fd.transpose()?;
+                self.visit_stat(dirfd, dent, path.clone(), &stat, fd)
+            });
+            if let Err(e) = res {
+                state = self.found.handle_error(&path, e);
+            }
+        }
+        state
     }
 
-    /// Returns true if this worker should quit immediately.
-    fn should_quit(&self) -> bool {
-        self.quit_now.load(Ordering::Relaxed)
+    fn dir(
+        &mut self,
+        dir: OwnedFd,
+        path: PString,
+        stat: &util::Stat,
+    ) -> io::Result<()> {
+        // Once queued, the directory may be closed by whichever walker deals
+        // with the last of its entries, or straight away.
+        let target = Target::Fd(dir.as_raw_fd());
+        let options = &self.found.options;
+        let xattrs = xattr::read(target, &options.xattrs)?;
+        // A mount point is kept, like `rsync -x` does, but nothing in it.
+        let device = options.root_device.unwrap_or(stat.st.st_dev);
+        if stat.st.st_dev == device {
+            self.queue.add_folder(dir, Arc::new(path.clone()))?;
+        }
+        let progress = &self.found.progress;
+        Progress::add(&progress.dirs, 1);
+        if progress.logging(2) {
+            progress.log(format_args!("D {}", path));
+        }
+        self.found.push(
+            path,
+            Record::Dir(DirState {
+                perms: perms(&stat.st),
+                uid: stat.st.st_uid,
+                gid: stat.st.st_gid,
+                xattrs,
+                statx: StatxInfo::of(stat),
+            }),
+        )
     }
 }
 
-fn visit(
-    basepath: PathBuf,
-    mut repo: PathBuf,
-    ignore_errors: bool,
-    same_device: bool,
-) -> Result<FsState, io::Error> {
-    let threads = std::thread::available_parallelism()?.get();
-    let threads = if (threads > 4) {
-        threads - 2
-    } else {
-        threads
-    };
-    let metadata = basepath.metadata()?;
-    let dev = metadata.dev();
+/// Stores the files the walkers find, in a pool of its own so that big
+/// files don't hold up the walk and lots of small ones keep the disk busy.
+struct Hasher {
+    files: Receiver<FileJob>,
+    found: Collector,
+    objectfd: RawFd,
+}
 
+/// Whether the parent layer has a file at `path` whose object is as big as
+/// the file there now. Layers don't record modification times, so the size
+/// is all there is to go on, but a big file that kept its size has most
+/// likely kept its contents.
+fn probably_stored(
+    parent: Option<&Parent>,
+    objectfd: RawFd,
+    path: &PString,
+    stat: &libc::stat,
+) -> bool {
+    if (stat.st_size as u64) < object::WHOLE_FILE_MAX as u64 {
+        // Small files are hashed before they are stored anyway.
+        return false;
+    }
+    // This is only a guess, so errors just mean guessing no.
+    let obj = match parent.map(|parent| parent.file(path)) {
+        Some(Ok(Some(obj))) => obj,
+        _ => return false,
+    };
+    let name = match CString::new(obj.hash) {
+        Ok(name) => name,
+        Err(_) => return false,
+    };
+    matches!(lstatat(objectfd, &name), Ok(st) if st.st_size == stat.st_size)
+}
+
+impl Hasher {
+    /// Store files until the walkers are done and every file is stored.
+    fn run(mut self) -> io::Result<()> {
+        while let Ok(job) = self.files.recv() {
+            // Once the walk has failed, only tidy up.
+            if self.found.stopped() {
+                close(job.fd)?;
+                continue;
+            }
+            let res = self.store(&job).and(close(job.fd));
+            if let Err(e) = res {
+                self.found.handle_error(&job.path, e);
+            }
+        }
+        self.found.finish()
+    }
+
+    fn store(&mut self, job: &FileJob) -> io::Result<()> {
+        let FileJob { path, fd, stat } = job;
+        let options = &self.found.options;
+        let parent = options.parent.as_ref();
+        let strategy = object::Strategy {
+            hash_first: probably_stored(parent, self.objectfd, path, &stat.st),
+            reflink: options.reflink,
+            uring: options.uring,
+        };
+        let imported = object::import(*fd, self.objectfd, strategy)?;
+        let progress = &self.found.progress;
+        Progress::add(&progress.files, 1);
+        if strategy.hash_first {
+            Progress::add(&progress.files_hashed_first, 1);
+        }
+        Progress::add(&progress.bytes_hashed, imported.size);
+        if imported.stored {
+            Progress::add(&progress.bytes_stored, imported.size);
+        } else {
+            Progress::add(&progress.bytes_deduped, imported.size);
+        }
+        if imported.cloned {
+            Progress::add(&progress.bytes_cloned, imported.size);
+        }
+        if progress.logging(1) {
+            let status = if imported.stored { "A" } else { "=" };
+            progress.log(format_args!("{} {}", status, path));
+        }
+        let xattrs = xattr::read(Target::Fd(*fd), &self.found.options.xattrs)?;
+        self.found.push(
+            path.clone(),
+            Record::File(Object {
+                hash: imported.hash,
+                perms: perms(&stat.st),
+                uid: stat.st.st_uid,
+                gid: stat.st.st_gid,
+                xattrs,
+                statx: StatxInfo::of(stat),
+                inode: if stat.st.st_nlink > 1 {
+                    Some((stat.st.st_dev, stat.st.st_ino))
+                } else {
+                    None
+                },
+            }),
+        )
+    }
+}
+
+/// How many threads to walk and to hash with, by default: all the CPUs,
+/// less a couple for the progress reporter and everything else once there
+/// are enough of them.
+fn default_threads() -> io::Result<usize> {
+    let threads = std::thread::available_parallelism()?.get();
+    Ok(if threads > 4 { threads - 2 } else { threads })
+}
+
+/// How many threads `visit` uses for each of its jobs.
+#[derive(Clone, Copy, Debug)]
+struct Threads {
+    walk: usize,
+    hash: usize,
+}
+
+fn visit(
+    repo_basedir: &str,
+    options: WalkOptions,
+    threads: Threads,
+    progress: Arc<Progress>,
+) -> Result<Arc<Spill>, Box<dyn Error + Send + Sync>> {
+    let basepath = &options.root;
+    let ignore_errors = options.ignore_errors;
+    let spill = Arc::new(Spill::new(repo_basedir)?);
     let dirfd = util::openat(libc::AT_FDCWD, &CString::new(basepath.as_os_str().as_bytes().to_vec())?, O_DIRECTORY)?;
+    // SAFETY: nothing else has the descriptor we just opened.
+    let root = unsafe { OwnedFd::from_raw_fd(dirfd) };
+    let mut repo = PathBuf::from(repo_basedir);
     repo.push("objects");
     let objectfd = util::openat(libc::AT_FDCWD, &CString::new(repo.as_os_str().as_bytes().to_vec())?, O_DIRECTORY)?;
-    let queue = Arc::new(
-        util::queue::Queue::new_with_folder(dirfd, Arc::new(util::PString::from_str(".")))?
-    );
+    // SAFETY: as above.
+    let objects = unsafe { OwnedFd::from_raw_fd(objectfd) };
+    let budget = fd_budget(options.fd_limit, threads, options.uring)?;
+    let queue = Arc::new(Queue::new_with_folder(
+        root.try_clone()?,
+        Arc::new(PString::from_str(".")),
+        QueueOptions {
+            max_open: budget.dirs,
+            traversal: options.traversal,
+            file_order: options.file_order,
+        },
+    )?);
 
-    let options = Arc::new(WalkOptions {
-        ignore_errors,
-        root_device: if same_device { Some(dev) } else { None },
-    });
+    let options = Arc::new(options);
 
     // Create the workers and then wait for them to finish.
-    let quit_now = Arc::new(AtomicBool::new(false));
-    let active_workers = Arc::new(AtomicUsize::new(0));
-    let mut final_state = FsState::new();
+    queue.set_workers(threads.walk);
+    let mut results = vec![];
     let errors: Arc<Mutex<Vec<WalkError>>> = Arc::new(Mutex::new(vec![]));
+    let stop = Arc::new(AtomicBool::new(false));
+    let collector = || Collector {
+        entries: vec![],
+        spill: spill.clone(),
+        options: options.clone(),
+        errors: errors.clone(),
+        stop: stop.clone(),
+        progress: progress.clone(),
+    };
+    let (files_tx, files_rx) =
+        crossbeam_channel::bounded(threads.hash * FILE_JOBS_PER_HASHER);
+    let (done_tx, done_rx) = crossbeam_channel::bounded::<()>(0);
     crossbeam_utils::thread::scope(|s| {
+        let reporter = progress.clone();
+        s.spawn(move |_| reporter.report(done_rx));
+
         let mut handles = vec![];
-        for _ in 0..threads {
+        for _ in 0..threads.hash {
+            let hasher = Hasher {
+                files: files_rx.clone(),
+                found: collector(),
+                objectfd: objects.as_raw_fd(),
+            };
+            handles.push(s.spawn(|_| hasher.run()));
+        }
+        for _ in 0..threads.walk {
             let worker = NQWorker {
                 queue: queue.clone(),
-                quit_now: quit_now.clone(),
-                active_workers: active_workers.clone(),
-                state: FsState::new(),
-                errors: errors.clone(),
-                options: options.clone(),
-                fd: dirfd,
-                objectfd
+                files: files_tx.clone(),
+                found: collector(),
+                root: root.as_raw_fd(),
+                reopened: vec![],
+                batch: budget.batch,
             };
             handles.push(s.spawn(|_| worker.run()));
         }
+        // The hashers finish once every walker has dropped its sender.
+        drop(files_tx);
+        drop(files_rx);
         for handle in handles {
-            final_state.extend(handle.join().unwrap());
+            results.push(handle.join().unwrap());
         }
+        drop(done_tx);
     })
     .unwrap(); // Pass along panics from threads
-    
-    util::close(dirfd)?;
-    util::close(objectfd)?;
 
-    Ok(final_state)
+    for result in results {
+        result?;
+    }
+
+    // If we weren't told to ignore errors, or ran out of descriptors, the
+    // walk stopped early and the state is incomplete, so report the error
+    // rather than the state.
+    let mut errors = errors.lock().unwrap();
+    if let Some(i) = errors.iter().position(|e| out_of_fds(&e.error)) {
+        return Err(format!(
+            "{}; raise the limit with `ulimit -n` or --raise-fd-limit, or \
+             open fewer files at once with --max-open-files",
+            errors.swap_remove(i)
+        )
+        .into());
+    }
+    if !ignore_errors && !errors.is_empty() {
+        return Err(Box::new(errors.swap_remove(0)));
+    }
+    drop(errors);
+
+    Ok(spill)
+}
+
+/// The result of `import`.
+#[derive(Debug, Serialize)]
+pub struct ImportSummary {
+    /// Hash of the newly written layer.
+    pub layer: String,
+    /// The layer this one was stored as a delta on top of.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    pub dirs: u64,
+    pub files: u64,
+    pub links: u64,
+    /// Entries of the parent that are gone from this layer.
+    pub whiteouts: u64,
+    /// Directory trees newly stored, and already present from other layers.
+    pub trees_stored: u64,
+    pub trees_deduped: u64,
+    pub bytes_hashed: u64,
+    pub bytes_stored: u64,
+    pub bytes_deduped: u64,
+    /// Of the bytes stored, those that share blocks with the imported files.
+    pub bytes_cloned: u64,
+    /// Files that looked unchanged since the parent, so were hashed before
+    /// being stored.
+    pub files_hashed_first: u64,
+    /// Links whose extended attributes were left out, for want of `/proc`
+    /// to read them through.
+    pub link_xattrs_skipped: u64,
+    pub errors: u64,
+    pub duration_ms: u64,
+}
+
+impl fmt::Display for ImportSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Visited {} directories and {} objects",
+            self.dirs,
+            self.files + self.links
+        )?;
+        match &self.parent {
+            Some(parent) => write!(
+                f,
+                "Successfully serialized state to {} as a delta on {}.",
+                self.layer, parent
+            ),
+            None => {
+                write!(f, "Successfully serialized state to {}.", self.layer)
+            }
+        }?;
+        if self.link_xattrs_skipped != 0 {
+            write!(
+                f,
+                "\nLeft out the xattrs of {} links, without /proc to read \
+                 them through",
+                self.link_xattrs_skipped
+            )?;
+        }
+        Ok(())
+    }
 }
 
 /// Import a filesystem tree.
 pub fn import(
     path: &str,
     repo_basedir: &str,
-) -> Result<String, Box<dyn Error + Send + Sync>> {
+    options: &ImportOptions,
+) -> Result<ImportSummary, Box<dyn Error + Send + Sync>> {
     let path = PathBuf::from(path.trim_end_matches('/'));
-    let state = visit(path, PathBuf::from(&repo_basedir), false, true)?;
-    println!("Visited {:?} directories and {:?} objects", state.dirs.len(), state.objects.len() + state.links.len());
-    
-    let ser = bincode::serialize(&state)?;
+    // Look for the parent first, so a typo doesn't cost a whole walk.
+    let parent = options
+        .delta_from
+        .as_deref()
+        .map(|parent| resolve(repo_basedir, parent))
+        .transpose()?;
+    let lower = parent
+        .as_deref()
+        .map(|parent| Parent::load(repo_basedir, parent))
+        .transpose()?;
+    let threads = Threads {
+        walk: options.walk_threads.map_or_else(default_threads, Ok)?,
+        hash: options.hash_threads.map_or_else(default_threads, Ok)?,
+    };
+    let progress = Arc::new(Progress::new(options.progress, options.verbose));
+    let limit = util::nofile_limit(options.raise_fd_limit)?;
+    let walk = WalkOptions {
+        xattrs: options.xattrs,
+        parent: lower,
+        reflink: Config::load(repo_basedir)?.reflink,
+        uring: options.io_backend.use_uring()?,
+        honor_nodump: options.honor_nodump,
+        fd_limit: options.max_open_files.map_or(limit, |max| max.min(limit)),
+        traversal: options.traversal,
+        file_order: options.file_order,
+        ..WalkOptions::new(path, options.same_device)?
+    };
+    let spill = visit(repo_basedir, walk, threads, progress.clone())?;
+
+    // The walk's results come back sorted from the spilled runs, and go
+    // straight into trees without ever all being in memory at once.
+    let mut trees = TreeStats::default();
+    let mut counts = Counts::default();
+    let entries = spill.merge()?.inspect(|entry| {
+        if let Ok((_, record)) = entry {
+            counts.add(record);
+        }
+    });
+    let root = tree::write_sorted(repo_basedir, entries, &mut trees)?;
+    drop(spill);
+    store_tree(repo_basedir, root, trees, counts, parent.as_deref(), &progress)
+}
+
+/// Write serialized layer data into the repository, returning its hash.
+fn write_layer(repo_basedir: &str, ser: &[u8]) -> io::Result<String> {
     let statehash = base64::encode_config(
-        blake3::hash(&ser).as_bytes(),
+        blake3::hash(ser).as_bytes(),
         base64::URL_SAFE_NO_PAD,
     );
 
@@ -319,7 +1446,1144 @@ pub fn import(
     path.push(&statehash);
 
     let mut layer = std::fs::File::create(path)?;
-    layer.write(&ser);
-
+    layer.write_all(ser)?;
     Ok(statehash)
-}
\ No newline at end of file
+}
+
+/// Serialize a full layer, storing its trees.
+fn tree_layer(
+    repo_basedir: &str,
+    state: &FsState,
+    trees: &mut TreeStats,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let root = tree::write(repo_basedir, state, trees)?;
+    let mut ser = TREE_MAGIC.to_vec();
+    bincode::serialize_into(&mut ser, &TreeLayer { root })?;
+    Ok(ser)
+}
+
+/// How many entries of each kind a layer has.
+#[derive(Debug, Default)]
+struct Counts {
+    dirs: u64,
+    files: u64,
+    links: u64,
+}
+
+impl Counts {
+    fn add(&mut self, record: &Record) {
+        match record {
+            Record::Dir(_) => self.dirs += 1,
+            Record::File(_) => self.files += 1,
+            Record::Link(_) => self.links += 1,
+        }
+    }
+}
+
+fn delta_layer(
+    parent: &str,
+    delta: Delta,
+) -> Result<(Vec<u8>, u64), Box<dyn Error + Send + Sync>> {
+    let layer = DeltaLayer { parent: parent.to_string(), delta };
+    let mut ser = DELTA_MAGIC.to_vec();
+    bincode::serialize_into(&mut ser, &layer)?;
+    Ok((ser, layer.delta.whiteouts.len() as u64))
+}
+
+/// Write a layer into the repository and summarize how it was imported.
+/// With a `parent`, which must be a full hash, only the differences from
+/// it are stored.
+pub(crate) fn store(
+    repo_basedir: &str,
+    state: &FsState,
+    parent: Option<&str>,
+    progress: &Progress,
+) -> Result<ImportSummary, Box<dyn Error + Send + Sync>> {
+    let mut whiteouts = 0;
+    let mut trees = TreeStats::default();
+    let ser = match parent {
+        Some(parent) => {
+            let lower = FsState::load(repo_basedir, parent)?;
+            let (ser, removed) = delta_layer(parent, state.diff(&lower))?;
+            whiteouts = removed;
+            ser
+        }
+        None => tree_layer(repo_basedir, state, &mut trees)?,
+    };
+    let counts = Counts {
+        dirs: state.dirs.len() as u64,
+        files: state.objects.len() as u64,
+        links: state.links.len() as u64,
+    };
+    summarize(repo_basedir, &ser, parent, counts, whiteouts, trees, progress)
+}
+
+/// Like `store`, for a layer whose trees are already written, with `root`
+/// as its root tree.
+fn store_tree(
+    repo_basedir: &str,
+    root: String,
+    trees: TreeStats,
+    counts: Counts,
+    parent: Option<&str>,
+    progress: &Progress,
+) -> Result<ImportSummary, Box<dyn Error + Send + Sync>> {
+    let mut whiteouts = 0;
+    let ser = match parent {
+        Some(parent) => {
+            let delta = match read_layer(repo_basedir, parent)? {
+                Stored::Tree(lower) => tree::diff(repo_basedir, &lower, &root)?,
+                _ => tree::load(repo_basedir, &root)?
+                    .diff(&FsState::load(repo_basedir, parent)?),
+            };
+            let (ser, removed) = delta_layer(parent, delta)?;
+            whiteouts = removed;
+            ser
+        }
+        None => {
+            let mut ser = TREE_MAGIC.to_vec();
+            bincode::serialize_into(&mut ser, &TreeLayer { root })?;
+            ser
+        }
+    };
+    summarize(repo_basedir, &ser, parent, counts, whiteouts, trees, progress)
+}
+
+fn summarize(
+    repo_basedir: &str,
+    ser: &[u8],
+    parent: Option<&str>,
+    counts: Counts,
+    whiteouts: u64,
+    trees: TreeStats,
+    progress: &Progress,
+) -> Result<ImportSummary, Box<dyn Error + Send + Sync>> {
+    let statehash = write_layer(repo_basedir, ser)?;
+
+    let stats = progress.snapshot();
+    Ok(ImportSummary {
+        layer: statehash,
+        parent: parent.map(|p| p.to_string()),
+        dirs: counts.dirs,
+        files: counts.files,
+        links: counts.links,
+        whiteouts,
+        trees_stored: trees.stored,
+        trees_deduped: trees.deduped,
+        bytes_hashed: stats.bytes_hashed,
+        bytes_stored: stats.bytes_stored,
+        bytes_deduped: stats.bytes_deduped,
+        bytes_cloned: stats.bytes_cloned,
+        files_hashed_first: stats.files_hashed_first,
+        link_xattrs_skipped: stats.link_xattrs_skipped,
+        errors: stats.errors,
+        duration_ms: stats.elapsed_ms,
+    })
+}
+
+/// The result of `squash`.
+#[derive(Debug, Serialize)]
+pub struct SquashSummary {
+    /// Hash of the new full layer.
+    pub layer: String,
+    /// The layers that were squashed, topmost first.
+    pub stack: Vec<String>,
+}
+
+impl fmt::Display for SquashSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Squashed {} layers into {}.",
+            self.stack.len(),
+            self.layer
+        )
+    }
+}
+
+/// Flatten a delta layer and its parents into a single full layer.
+pub fn squash(
+    repo_basedir: &str,
+    layer: &str,
+) -> Result<SquashSummary, Box<dyn Error + Send + Sync>> {
+    let stack = stack(repo_basedir, layer)?;
+    let state = FsState::load(repo_basedir, &stack[0])?;
+    let ser = tree_layer(repo_basedir, &state, &mut TreeStats::default())?;
+    let layer = write_layer(repo_basedir, &ser)?;
+    Ok(SquashSummary { layer, stack })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+    use std::ptr;
+
+    use super::*;
+
+    /// Options for walking `root` in tests, which carry on past errors.
+    fn options(root: &Path) -> WalkOptions {
+        WalkOptions {
+            ignore_errors: true,
+            ..WalkOptions::new(root.to_path_buf(), false).unwrap()
+        }
+    }
+
+    /// Walk `root` with `threads` workers, returning what was found and how
+    /// many errors were skipped over.
+    fn walk(root: &Path, repo: &Path, threads: Threads) -> (FsState, u64) {
+        walk_with(repo, threads, options(root))
+    }
+
+    fn walk_with(
+        repo: &Path,
+        threads: Threads,
+        options: WalkOptions,
+    ) -> (FsState, u64) {
+        let progress = Arc::new(Progress::new(ProgressMode::None, 0));
+        let spill =
+            visit(repo.to_str().unwrap(), options, threads, progress.clone())
+                .unwrap();
+
+        let mut state = FsState::new();
+        for entry in spill.merge().unwrap() {
+            let (key, record) = entry.unwrap();
+            match record {
+                Record::Dir(dir) => state.dirs.insert(key, dir).is_none(),
+                Record::File(obj) => state.objects.insert(key, obj).is_none(),
+                Record::Link(target) => {
+                    state.links.insert(key, target).is_none()
+                }
+            };
+        }
+        (state, progress.snapshot().errors)
+    }
+
+    /// What the walker should find under `root`, read with `std::fs`:
+    /// directories, files with their contents, and symlink targets.
+    #[derive(Default)]
+    struct Expected {
+        dirs: BTreeSet<String>,
+        files: BTreeMap<String, Vec<u8>>,
+        links: BTreeMap<String, String>,
+    }
+
+    fn expected(root: &Path, key: &str, into: &mut Expected) {
+        for entry in fs::read_dir(root.join(key)).unwrap() {
+            let entry = entry.unwrap();
+            let name = entry.file_name().into_string().unwrap();
+            let key = format!("{}/{}", key, name);
+            let kind = entry.file_type().unwrap();
+            if kind.is_symlink() {
+                let target = fs::read_link(entry.path()).unwrap();
+                into.links.insert(key, target.to_str().unwrap().to_string());
+            } else if kind.is_dir() {
+                into.dirs.insert(key.clone());
+                expected(root, &key, into);
+            } else if let Ok(data) = fs::read(entry.path()) {
+                into.files.insert(key, data);
+            }
+        }
+    }
+
+    fn keys<T>(map: &BTreeMap<PString, T>) -> BTreeSet<String> {
+        map.keys().map(|k| k.to_string()).collect()
+    }
+
+    fn check(root: &Path, repo: &Path, state: &FsState) {
+        let mut want = Expected::default();
+        expected(root, ".", &mut want);
+
+        assert_eq!(keys(&state.dirs), want.dirs);
+        assert_eq!(keys(&state.objects), want.files.keys().cloned().collect());
+        let links: BTreeMap<_, _> = state
+            .links
+            .iter()
+            .map(|(k, l)| (k.to_string(), l.target.to_string()))
+            .collect();
+        assert_eq!(links, want.links);
+
+        for (key, obj) in &state.objects {
+            let stored = fs::read(repo.join("objects").join(&obj.hash));
+            let key = key.to_string();
+            assert_eq!(stored.unwrap(), want.files[&key], "{}", key);
+        }
+        for (key, link) in &state.links {
+            let meta = fs::symlink_metadata(root.join(key.to_string()));
+            let meta = meta.unwrap();
+            assert_eq!((link.uid, link.gid), (meta.uid(), meta.gid()));
+            assert_eq!(link.mtime, meta.mtime(), "{}", key);
+        }
+        for (key, dir) in &state.dirs {
+            let mode = fs::metadata(root.join(key.to_string()))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(dir.perms, mode & 0o777, "{}", key);
+        }
+    }
+
+    fn repo() -> tempfile::TempDir {
+        let repo = tempfile::tempdir().unwrap();
+        fs::create_dir(repo.path().join("objects")).unwrap();
+        repo
+    }
+
+    fn threads(walk: usize, hash: usize) -> Threads {
+        Threads { walk, hash }
+    }
+
+    /// Walk `root` with several thread counts, checking that each walk is
+    /// complete and that they all agree.
+    fn check_all(root: &Path) -> FsState {
+        let repo = repo();
+        let (first, errors) = walk(root, repo.path(), threads(1, 1));
+        assert_eq!(errors, 0);
+        check(root, repo.path(), &first);
+        for (w, h) in [(2, 1), (1, 4), (4, 4), (8, 2)] {
+            let (state, errors) = walk(root, repo.path(), threads(w, h));
+            assert_eq!(errors, 0);
+            assert_eq!(state, first, "{} walkers, {} hashers", w, h);
+        }
+        if IoBackend::Auto.use_uring().unwrap() {
+            let options = WalkOptions { uring: true, ..options(root) };
+            let (state, errors) =
+                walk_with(repo.path(), threads(2, 2), options);
+            assert_eq!(errors, 0);
+            assert_eq!(state, first, "io_uring");
+        }
+        let orders = [
+            (Traversal::Depth, FileOrder::Dirent),
+            (Traversal::Breadth, FileOrder::Inode),
+            (Traversal::Depth, FileOrder::Extent),
+        ];
+        for (traversal, file_order) in orders {
+            let options =
+                WalkOptions { traversal, file_order, ..options(root) };
+            let (state, errors) =
+                walk_with(repo.path(), threads(2, 2), options);
+            assert_eq!(errors, 0);
+            assert_eq!(state, first, "{:?}, {:?}", traversal, file_order);
+        }
+        first
+    }
+
+    #[test]
+    fn deep_nesting() {
+        let root = tempfile::tempdir().unwrap();
+        let mut dir = root.path().to_path_buf();
+        for depth in 0..200 {
+            dir.push("d");
+            fs::create_dir(&dir).unwrap();
+            fs::write(dir.join("f"), depth.to_string()).unwrap();
+        }
+        let state = check_all(root.path());
+        assert_eq!(state.dirs.len(), 200);
+        assert_eq!(state.objects.len(), 200);
+    }
+
+    #[test]
+    fn wide_directories() {
+        let root = tempfile::tempdir().unwrap();
+        // Several times over what fits in one queue node's worth of dirents,
+        // in more than one directory so that workers split them up.
+        for dir in ["a", "b", "c/d"] {
+            let dir = root.path().join(dir);
+            fs::create_dir_all(&dir).unwrap();
+            for i in 0..2000 {
+                fs::write(dir.join(format!("file-{:04}", i)), [i as u8])
+                    .unwrap();
+            }
+            for i in 0..100 {
+                fs::create_dir(dir.join(format!("dir-{:03}", i))).unwrap();
+            }
+        }
+        let state = check_all(root.path());
+        assert_eq!(state.objects.len(), 6000);
+        assert_eq!(state.dirs.len(), 4 + 300);
+    }
+
+    #[test]
+    fn few_open_files() {
+        // More directories than the walk may keep open, so that most are
+        // closed once read and opened again for their entries.
+        let root = tempfile::tempdir().unwrap();
+        for i in 0..64 {
+            let dir = root.path().join(format!("dir-{}", i));
+            fs::create_dir_all(dir.join("sub/subsub")).unwrap();
+            fs::write(dir.join("file"), i.to_string()).unwrap();
+            fs::write(dir.join("sub/file"), i.to_string()).unwrap();
+            symlink("file", dir.join("link")).unwrap();
+        }
+        let repo = repo();
+        let (first, errors) = walk(root.path(), repo.path(), threads(1, 1));
+        assert_eq!(errors, 0);
+
+        let mut backends = vec![false];
+        if IoBackend::Auto.use_uring().unwrap() {
+            backends.push(true);
+        }
+        for uring in backends {
+            for (w, h) in [(1, 1), (4, 2)] {
+                // Room for the threads, and a couple of directories.
+                let least = (0..)
+                    .find(|&n| fd_budget(n, threads(w, h), uring).is_ok())
+                    .unwrap();
+                let options = WalkOptions {
+                    uring,
+                    fd_limit: least + 2,
+                    ..options(root.path())
+                };
+                let (state, errors) =
+                    walk_with(repo.path(), threads(w, h), options);
+                assert_eq!(errors, 0);
+                assert_eq!(state, first, "{} walkers, {} hashers", w, h);
+            }
+        }
+
+        // Too few for the threads at all.
+        let options = WalkOptions { fd_limit: 16, ..options(root.path()) };
+        let progress = Arc::new(Progress::new(ProgressMode::None, 0));
+        let repo_dir = repo.path().to_str().unwrap();
+        let err = visit(repo_dir, options, threads(1, 1), progress)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("ulimit -n"), "{}", err);
+    }
+
+    #[test]
+    fn same_device() {
+        let root = tempfile::tempdir().unwrap();
+        let mnt = root.path().join("mnt");
+        fs::create_dir(&mnt).unwrap();
+        fs::write(root.path().join("file"), b"here").unwrap();
+        // Mounting takes privileges, which the test can't do without.
+        let target = CString::new(mnt.as_os_str().as_bytes()).unwrap();
+        let tmpfs = CString::new("tmpfs").unwrap();
+        let ret = unsafe {
+            libc::mount(
+                tmpfs.as_ptr(),
+                target.as_ptr(),
+                tmpfs.as_ptr(),
+                0,
+                ptr::null(),
+            )
+        };
+        if ret != 0 {
+            return;
+        }
+        fs::write(mnt.join("elsewhere"), b"there").unwrap();
+
+        let repo = repo();
+        let (state, errors) = walk(root.path(), repo.path(), threads(2, 2));
+        assert_eq!(errors, 0);
+        assert_eq!(state.objects.len(), 2);
+
+        // The mount point stays, empty.
+        let options = WalkOptions {
+            ignore_errors: true,
+            ..WalkOptions::new(root.path().to_path_buf(), true).unwrap()
+        };
+        let (state, errors) = walk_with(repo.path(), threads(2, 2), options);
+        unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) };
+        assert_eq!(errors, 0);
+        assert_eq!(keys(&state.objects), BTreeSet::from(["./file".into()]));
+        assert_eq!(keys(&state.dirs), BTreeSet::from(["./mnt".into()]));
+    }
+
+    #[test]
+    fn moved_while_closed() {
+        let root = tempfile::tempdir().unwrap();
+        let path = |p: &str| root.path().join(p);
+        fs::create_dir(path("a")).unwrap();
+        fs::write(path("a/x"), b"x").unwrap();
+        fs::write(path("a/y"), b"y").unwrap();
+
+        // A queue with room for the root only, so that `a` is closed once
+        // read, and a walker taking entries off it by hand.
+        let repo = repo();
+        let rootfd = OwnedFd::from(fs::File::open(root.path()).unwrap());
+        let queue = Arc::new(
+            Queue::new_with_folder(
+                rootfd.try_clone().unwrap(),
+                Arc::new(PString::from_str(".")),
+                QueueOptions {
+                    max_open: 1,
+                    traversal: Traversal::Breadth,
+                    file_order: FileOrder::Dirent,
+                },
+            )
+            .unwrap(),
+        );
+        queue.set_workers(1);
+        let (files, found_files) = crossbeam_channel::unbounded();
+        let errors = Arc::new(Mutex::new(vec![]));
+        let mut worker = NQWorker {
+            queue: queue.clone(),
+            files,
+            found: Collector {
+                entries: vec![],
+                spill: Arc::new(Spill::new(repo.path().to_str().unwrap())
+                    .unwrap()),
+                options: Arc::new(options(root.path())),
+                errors: errors.clone(),
+                stop: Arc::new(AtomicBool::new(false)),
+                progress: Arc::new(Progress::new(ProgressMode::None, 0)),
+            },
+            root: rootfd.as_raw_fd(),
+            reopened: vec![],
+            batch: 1,
+        };
+
+        // Once `a` has been read, and before any of its entries have been
+        // visited, it's moved, and another put in its place.
+        let mut moved = false;
+        while let Some(dent) = queue.next() {
+            if !moved && dent.basedir().as_bytes() == b"./a" {
+                fs::rename(path("a"), path("b")).unwrap();
+                fs::create_dir(path("a")).unwrap();
+                fs::write(path("a/x"), b"not x").unwrap();
+                moved = true;
+            }
+            worker.visit(dent);
+        }
+        assert!(moved);
+
+        // Its entries are lost to the walk, rather than taken from the
+        // directory that isn't the one they were read from.
+        drop(worker);
+        let found: Vec<String> = found_files
+            .try_iter()
+            .map(|job| {
+                close(job.fd).unwrap();
+                job.path.to_string()
+            })
+            .collect();
+        assert!(found.iter().all(|p| !p.starts_with("./a/")), "{:?}", found);
+        let mut errors: Vec<String> = errors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        errors.sort();
+        assert_eq!(
+            errors,
+            [
+                "./a/x: directory was moved during the walk",
+                "./a/y: directory was moved during the walk",
+            ]
+        );
+    }
+
+    /// The paths `queue` hands out, taken one at a time like a single
+    /// walker would, queueing directories as they come.
+    fn drain(queue: &Queue) -> Vec<String> {
+        let mut paths = vec![];
+        while let Some(dent) = queue.advance() {
+            let name = dent.filename().to_bytes();
+            if name == b"." || name == b".." {
+                continue;
+            }
+            let path = dent.fullpath();
+            if dent.filetype() == libc::DT_DIR {
+                let fd = open_beneath(
+                    dent.dirfd().unwrap(),
+                    dent.filename(),
+                    O_DIRECTORY,
+                )
+                .unwrap();
+                let dir = unsafe { OwnedFd::from_raw_fd(fd) };
+                queue.add_folder(dir, Arc::new(path.clone())).unwrap();
+            }
+            paths.push(path.to_string());
+        }
+        paths
+    }
+
+    #[test]
+    fn traversal_orders() {
+        let root = tempfile::tempdir().unwrap();
+        for dir in ["one", "two"] {
+            let dir = root.path().join(dir);
+            fs::create_dir_all(dir.join("sub")).unwrap();
+            for i in 0..32 {
+                fs::write(dir.join("sub").join(i.to_string()), b"").unwrap();
+            }
+        }
+        let queue = |traversal, file_order| {
+            let root = fs::File::open(root.path()).unwrap();
+            let options = QueueOptions {
+                max_open: usize::MAX,
+                traversal,
+                file_order,
+            };
+            let path = Arc::new(PString::from_str("."));
+            Queue::new_with_folder(root.into(), path, options).unwrap()
+        };
+        let at = |paths: &[String], path: &str| {
+            paths.iter().position(|p| p == path).unwrap()
+        };
+
+        // Breadth-first, both subdirectories come before any of their
+        // files; depth-first, the second one's files come before the first
+        // subdirectory.
+        let paths = drain(&queue(Traversal::Breadth, FileOrder::Dirent));
+        assert_eq!(paths.len(), 2 * 34);
+        assert!(at(&paths, "./two/sub/0") > at(&paths, "./one/sub"));
+        assert!(at(&paths, "./one/sub/0") > at(&paths, "./two/sub"));
+        let paths = drain(&queue(Traversal::Depth, FileOrder::Dirent));
+        assert_eq!(paths.len(), 2 * 34);
+        let (first, second) = match at(&paths, "./one") < at(&paths, "./two")
+        {
+            true => ("./one/sub", "./two/sub/0"),
+            false => ("./two/sub", "./one/sub/0"),
+        };
+        assert!(at(&paths, second) < at(&paths, first), "{:?}", paths);
+
+        // Each directory's files by inode.
+        let paths = drain(&queue(Traversal::Breadth, FileOrder::Inode));
+        let inodes: Vec<u64> = paths
+            .iter()
+            .filter(|path| path.starts_with("./one/sub/"))
+            .map(|path| fs::metadata(root.path().join(path)).unwrap().ino())
+            .collect();
+        assert_eq!(inodes.len(), 32);
+        assert!(inodes.windows(2).all(|w| w[0] < w[1]), "{:?}", inodes);
+    }
+
+    /// Workers that run out of entries park until another one adds a
+    /// directory, and the queue closes once every one of them is idle.
+    #[test]
+    fn workers_park_and_finish() {
+        // A chain of directories, so that only one worker has anything to
+        // do at a time, and the rest keep running dry.
+        let root = tempfile::tempdir().unwrap();
+        let mut dir = root.path().to_path_buf();
+        for i in 0..16 {
+            dir.push(format!("dir-{}", i));
+            fs::create_dir(&dir).unwrap();
+            for j in 0..4 {
+                fs::write(dir.join(format!("file-{}", j)), b"").unwrap();
+            }
+        }
+
+        for traversal in [Traversal::Breadth, Traversal::Depth] {
+            for workers in [1, 2, 4, 8] {
+                let root = fs::File::open(root.path()).unwrap();
+                let options = QueueOptions {
+                    max_open: usize::MAX,
+                    traversal,
+                    file_order: FileOrder::Dirent,
+                };
+                let path = Arc::new(PString::from_str("."));
+                let queue = Queue::new_with_folder(root.into(), path, options);
+                let queue = Arc::new(queue.unwrap());
+                queue.set_workers(workers);
+
+                let (tx, rx) = crossbeam_channel::unbounded();
+                for _ in 0..workers {
+                    let (queue, tx) = (queue.clone(), tx.clone());
+                    std::thread::spawn(move || {
+                        let mut seen = 0;
+                        while let Some(dent) = queue.next() {
+                            let name = dent.filename().to_bytes();
+                            if name == b"." || name == b".." {
+                                continue;
+                            }
+                            seen += 1;
+                            if dent.filetype() != libc::DT_DIR {
+                                continue;
+                            }
+                            // Long enough for the others to park.
+                            let pause = std::time::Duration::from_millis(2);
+                            std::thread::sleep(pause);
+                            let fd = open_beneath(
+                                dent.dirfd().unwrap(),
+                                dent.filename(),
+                                O_DIRECTORY,
+                            )
+                            .unwrap();
+                            let dir = unsafe { OwnedFd::from_raw_fd(fd) };
+                            let path = Arc::new(dent.fullpath());
+                            queue.add_folder(dir, path).unwrap();
+                        }
+                        tx.send(seen).unwrap();
+                    });
+                }
+
+                let timeout = std::time::Duration::from_secs(60);
+                let mut seen = 0;
+                for _ in 0..workers {
+                    seen += rx.recv_timeout(timeout).unwrap_or_else(|_| {
+                        panic!("{:?} with {} workers hung", traversal, workers)
+                    });
+                }
+                assert_eq!(seen, 16 * 5);
+                assert!(queue.next().is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn symlinks() {
+        let root = tempfile::tempdir().unwrap();
+        // Links spread across directories, so that each worker finds some.
+        for i in 0..64 {
+            let dir = root.path().join(format!("dir-{}", i));
+            fs::create_dir(&dir).unwrap();
+            fs::write(dir.join("target"), i.to_string()).unwrap();
+            symlink("target", dir.join("to-file")).unwrap();
+            symlink("..", dir.join("to-parent")).unwrap();
+            symlink(format!("missing-{}", i), dir.join("dangling")).unwrap();
+        }
+        symlink("/etc/passwd", root.path().join("absolute")).unwrap();
+        let state = check_all(root.path());
+        assert_eq!(state.links.len(), 64 * 3 + 1);
+        // Links to directories aren't followed.
+        assert_eq!(state.dirs.len(), 64);
+    }
+
+    #[test]
+    fn swapped_for_symlink() {
+        // Entries the walker has seen, swapped for links out of the tree
+        // before the walker gets around to opening them.
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("secret"), b"secret").unwrap();
+        symlink(outside.path(), root.path().join("dir")).unwrap();
+        symlink(outside.path().join("secret"), root.path().join("file"))
+            .unwrap();
+
+        let rootfd = fs::File::open(root.path()).unwrap();
+        let open = |name, flags| {
+            let name = CString::new(name).unwrap();
+            open_beneath(rootfd.as_raw_fd(), &name, flags).unwrap_err()
+        };
+        let dir = open("dir", O_DIRECTORY);
+        assert_eq!(dir.raw_os_error(), Some(libc::ENOTDIR));
+        assert_eq!(open("file", 0).raw_os_error(), Some(libc::ELOOP));
+    }
+
+    #[test]
+    fn empty_directories() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("a/b/c")).unwrap();
+        fs::create_dir(root.path().join("empty")).unwrap();
+        fs::write(root.path().join("a/empty-file"), b"").unwrap();
+        let state = check_all(root.path());
+        assert_eq!(state.dirs.len(), 4);
+        assert_eq!(state.objects.len(), 1);
+
+        let root = tempfile::tempdir().unwrap();
+        let state = check_all(root.path());
+        assert_eq!(state, FsState::new());
+    }
+
+    #[test]
+    fn unreadable_files() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("readable"), b"yes").unwrap();
+        let secret = root.path().join("secret");
+        fs::write(&secret, b"no").unwrap();
+        let none = fs::Permissions::from_mode(0o000);
+        fs::set_permissions(&secret, none).unwrap();
+
+        let repo = repo();
+        // Root can read the file regardless of its mode.
+        let root_user = unsafe { libc::geteuid() } == 0;
+        for n in [1, 4] {
+            let threads = threads(n, n);
+            let (state, errors) = walk(root.path(), repo.path(), threads);
+            check(root.path(), repo.path(), &state);
+            assert_eq!(errors, if root_user { 0 } else { 1 });
+            assert_eq!(state.objects.len(), if root_user { 2 } else { 1 });
+        }
+
+        // Without ignoring errors, the walk fails rather than leaving the
+        // file out.
+        if !root_user {
+            let progress = Arc::new(Progress::new(ProgressMode::None, 0));
+            let options =
+                WalkOptions::new(root.path().to_path_buf(), false).unwrap();
+            let repo = repo.path().to_str().unwrap();
+            let res = visit(repo, options, threads(2, 2), progress);
+            assert!(res.is_err());
+        }
+    }
+
+    #[test]
+    fn failed_walks_end() {
+        // Plenty of files for the hashers to have queued up, and a socket
+        // halfway down, which can't be opened even by root, and then a
+        // fifo, which would block whoever opened it.
+        let root = tempfile::tempdir().unwrap();
+        for i in 0..32 {
+            let dir = root.path().join(format!("dir-{:02}/sub", i));
+            fs::create_dir_all(&dir).unwrap();
+            for j in 0..32 {
+                fs::write(dir.join(format!("file-{}", j)), [i, j]).unwrap();
+            }
+        }
+        let socket = root.path().join("dir-16/sub/socket");
+        let mut listener =
+            Some(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+
+        let repo = repo();
+        let mut uring = vec![false];
+        if IoBackend::Auto.use_uring().unwrap() {
+            uring.push(true);
+        }
+        for special in ["socket", "fifo"] {
+            if special == "fifo" {
+                drop(listener.take());
+                fs::remove_file(&socket).unwrap();
+                let fifo = root.path().join("dir-16/sub/fifo");
+                let fifo = CString::new(fifo.as_os_str().as_bytes()).unwrap();
+                assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+            }
+            for &uring in &uring {
+                for (w, h) in [(1, 1), (4, 1), (1, 4), (8, 8)] {
+                    // Every walker and hasher has to exit for the walk to
+                    // return, so one that doesn't shows up as a timeout.
+                    let options = WalkOptions {
+                        uring,
+                        ..WalkOptions::new(root.path().to_path_buf(), false)
+                            .unwrap()
+                    };
+                    let repo = repo.path().to_str().unwrap().to_string();
+                    let (tx, rx) = crossbeam_channel::bounded(1);
+                    std::thread::spawn(move || {
+                        let progress =
+                            Arc::new(Progress::new(ProgressMode::None, 0));
+                        let res =
+                            visit(&repo, options, threads(w, h), progress)
+                                .map(|_| ())
+                                .map_err(|e| e.to_string());
+                        tx.send(res).unwrap();
+                    });
+                    let timeout = std::time::Duration::from_secs(60);
+                    let res = rx.recv_timeout(timeout).unwrap_or_else(|_| {
+                        panic!("{} walkers, {} hashers hung", w, h)
+                    });
+                    let err = res.unwrap_err();
+                    let path = format!("./dir-16/sub/{}: ", special);
+                    assert!(err.starts_with(&path), "{}", err);
+                }
+            }
+        }
+    }
+
+    /// Full layers as they were written before paths were raw bytes are
+    /// still read.
+    #[test]
+    fn baseline_layers() {
+        type Xattrs = Option<BTreeMap<String, Vec<u8>>>;
+        #[derive(Serialize)]
+        struct OldObject {
+            hash: String,
+            perms: u32,
+            uid: u32,
+            gid: u32,
+            xattrs: Xattrs,
+        }
+        #[derive(Serialize)]
+        struct OldDir {
+            perms: u32,
+            uid: u32,
+            gid: u32,
+            xattrs: Xattrs,
+        }
+        #[derive(Serialize)]
+        struct OldState {
+            dirs: BTreeMap<PString, OldDir>,
+            objects: BTreeMap<PString, OldObject>,
+            links: BTreeMap<PString, String>,
+        }
+
+        let xattrs = BTreeMap::from([("user.a".to_string(), b"1".to_vec())]);
+        let old = OldState {
+            dirs: BTreeMap::from([(
+                PString::from_str("./dir"),
+                OldDir { perms: 0o755, uid: 1, gid: 2, xattrs: None },
+            )]),
+            objects: BTreeMap::from([(
+                PString::from_str("./dir/file"),
+                OldObject {
+                    hash: "hash".to_string(),
+                    perms: 0o644,
+                    uid: 3,
+                    gid: 4,
+                    xattrs: Some(xattrs),
+                },
+            )]),
+            links: BTreeMap::from([(
+                PString::from_str("./link"),
+                "dir/file".to_string(),
+            )]),
+        };
+        let repo = repo();
+        fs::create_dir(repo.path().join("layers")).unwrap();
+        let repo_dir = repo.path().to_str().unwrap();
+        let layer = write_layer(repo_dir, &bincode::serialize(&old).unwrap());
+
+        let state = FsState::load(repo_dir, &layer.unwrap()).unwrap();
+        let dir = &state.dirs[&PString::from_str("./dir")];
+        assert_eq!((dir.perms, dir.uid, dir.gid), (0o755, 1, 2));
+        let obj = &state.objects[&PString::from_str("./dir/file")];
+        assert_eq!((obj.hash.as_str(), obj.uid, obj.gid), ("hash", 3, 4));
+        let name = CString::new("user.a").unwrap();
+        assert_eq!(obj.xattrs, Some(BTreeMap::from([(name, b"1".to_vec())])));
+        let link = &state.links[&PString::from_str("./link")];
+        assert_eq!(*link, Link::bare(PString::from_str("dir/file")));
+    }
+
+    fn setxattr(path: &Path, name: &str, value: &[u8]) {
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let name = CString::new(name).unwrap();
+        let ret = unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+    }
+
+    #[test]
+    fn xattrs() {
+        let root = tempfile::tempdir().unwrap();
+        let file = root.path().join("file");
+        fs::write(&file, b"data").unwrap();
+        fs::create_dir(root.path().join("dir")).unwrap();
+        // Bigger than the first guess of most buffer sizes.
+        let big = vec![b'x'; 3000];
+        setxattr(&file, "user.big", &big);
+        setxattr(&file, "user.empty", b"");
+        setxattr(&root.path().join("dir"), "user.dir", b"d");
+
+        let repo = repo();
+        let (state, errors) = walk(root.path(), repo.path(), threads(2, 2));
+        assert_eq!(errors, 0);
+        let file = &state.objects[&PString::from_str("./file")];
+        let want = BTreeMap::from([
+            (CString::new("user.big").unwrap(), big),
+            (CString::new("user.empty").unwrap(), vec![]),
+        ]);
+        assert_eq!(file.xattrs.as_ref(), Some(&want));
+        let dir = &state.dirs[&PString::from_str("./dir")];
+        assert_eq!(dir.xattrs.as_ref().unwrap().len(), 1);
+
+        // Filtered out attributes leave no trace, not even an empty map.
+        let xattrs = "trusted,acl".parse().unwrap();
+        let options = WalkOptions { xattrs, ..options(root.path()) };
+        let (state, _) = walk_with(repo.path(), threads(2, 2), options);
+        assert!(state.objects.values().all(|o| o.xattrs.is_none()));
+        assert!(state.dirs.values().all(|d| d.xattrs.is_none()));
+    }
+
+    #[test]
+    fn inode_flags() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("file"), b"data").unwrap();
+        fs::write(root.path().join("skipped"), b"data").unwrap();
+        fs::create_dir(root.path().join("dir")).unwrap();
+        fs::write(root.path().join("dir/inside"), b"data").unwrap();
+        for name in ["skipped", "dir"] {
+            let file = fs::File::open(root.path().join(name)).unwrap();
+            let nodump = util::ATTR_NODUMP;
+            match util::set_inode_flags(file.as_raw_fd(), nodump, nodump) {
+                Ok(()) => {}
+                // Not every filesystem has flags.
+                Err(e) if e.raw_os_error() == Some(libc::ENOTTY) => return,
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        let repo = repo();
+        let (state, errors) = walk(root.path(), repo.path(), threads(2, 2));
+        assert_eq!(errors, 0);
+        let flags = |key| state.objects[&PString::from_str(key)].statx.flags;
+        assert_eq!(flags("./file"), 0);
+        assert_eq!(flags("./skipped"), util::ATTR_NODUMP);
+        let dir = &state.dirs[&PString::from_str("./dir")];
+        assert_eq!(dir.statx.flags, util::ATTR_NODUMP);
+
+        // Honoring nodump leaves out the directory's contents too.
+        let options =
+            WalkOptions { honor_nodump: true, ..options(root.path()) };
+        let (state, errors) = walk_with(repo.path(), threads(2, 2), options);
+        assert_eq!(errors, 0);
+        assert_eq!(keys(&state.objects), BTreeSet::from(["./file".into()]));
+        assert!(state.dirs.is_empty());
+    }
+
+    #[test]
+    fn symlink_metadata() {
+        let root = tempfile::tempdir().unwrap();
+        let link = root.path().join("link");
+        symlink("target", &link).unwrap();
+        let path = CString::new(link.as_os_str().as_bytes()).unwrap();
+        let times = [
+            libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+            libc::timespec { tv_sec: 1_000_000_000, tv_nsec: 42 },
+        ];
+        let ret = unsafe {
+            libc::utimensat(
+                libc::AT_FDCWD,
+                path.as_ptr(),
+                times.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        };
+        assert_eq!(ret, 0);
+
+        // Only root can give a link away, or label it with a trusted
+        // attribute; user attributes aren't allowed on links at all.
+        let root_user = unsafe { libc::geteuid() } == 0;
+        if root_user {
+            std::os::unix::fs::lchown(&link, Some(1234), Some(5678)).unwrap();
+            let value = b"label";
+            let ret = unsafe {
+                libc::lsetxattr(
+                    path.as_ptr(),
+                    b"trusted.label\0".as_ptr() as *const libc::c_char,
+                    value.as_ptr() as *const libc::c_void,
+                    value.len(),
+                    0,
+                )
+            };
+            assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+        }
+
+        let state = check_all(root.path());
+        let link = &state.links[&PString::from_str("./link")];
+        assert_eq!(link.target, PString::from_str("target"));
+        assert_eq!((link.mtime, link.mtime_nsec), (1_000_000_000, 42));
+        if root_user {
+            assert_eq!((link.uid, link.gid), (1234, 5678));
+            let xattrs = link.xattrs.as_ref().unwrap();
+            let label = CString::new("trusted.label").unwrap();
+            assert_eq!(xattrs[&label], b"label");
+        }
+    }
+
+    /// Big files that look unchanged since the parent are hashed before
+    /// they are stored, which must come to the same thing either way.
+    #[test]
+    fn delta_from_guesses() {
+        let root = tempfile::tempdir().unwrap();
+        let big = root.path().join("big");
+        let size = object::WHOLE_FILE_MAX * 3 + 5;
+        fs::write(&big, vec![1u8; size]).unwrap();
+        fs::write(root.path().join("small"), b"small").unwrap();
+
+        let repo = repo();
+        fs::create_dir(repo.path().join("layers")).unwrap();
+        fs::create_dir(repo.path().join("trees")).unwrap();
+        let repo_dir = repo.path().to_str().unwrap();
+        let import_from = |parent: Option<&str>| {
+            let options = ImportOptions {
+                same_device: false,
+                progress: ProgressMode::None,
+                verbose: 0,
+                delta_from: parent.map(str::to_string),
+                xattrs: XattrFilter::ALL,
+                walk_threads: Some(2),
+                hash_threads: Some(2),
+                io_backend: IoBackend::Auto,
+                honor_nodump: false,
+                max_open_files: None,
+                raise_fd_limit: false,
+                traversal: Traversal::Breadth,
+                file_order: FileOrder::Dirent,
+            };
+            import(root.path().to_str().unwrap(), repo_dir, &options).unwrap()
+        };
+
+        let first = import_from(None);
+        assert_eq!(first.bytes_stored, size as u64 + 5);
+        assert_eq!(first.files_hashed_first, 0);
+        // Only the big file is worth guessing about.
+        let second = import_from(Some(&first.layer));
+        assert_eq!(second.files_hashed_first, 1);
+        assert_eq!(second.bytes_stored, 0);
+        assert_eq!(second.bytes_deduped, size as u64 + 5);
+
+        // Same size, different contents: the guess is wrong, and the new
+        // contents are stored anyway.
+        fs::write(&big, vec![2u8; size]).unwrap();
+        let third = import_from(Some(&second.layer));
+        assert_eq!(third.files_hashed_first, 1);
+        assert_eq!(third.bytes_stored, size as u64);
+        let state = FsState::load(repo_dir, &third.layer).unwrap();
+        let obj = &state.objects[&PString::from_str("./big")];
+        let stored = fs::read(repo.path().join("objects").join(&obj.hash));
+        assert!(stored.unwrap() == vec![2u8; size]);
+    }
+
+    /// The io_uring walker, which has to be there when asked for, makes
+    /// the very same layer as plain syscalls do.
+    #[cfg(feature = "io-uring")]
+    #[test]
+    fn uring_layer() {
+        // Directories of many batches and of part of one, links and files
+        // big enough to go through the ring too.
+        let root = tempfile::tempdir().unwrap();
+        for i in 0..8 {
+            let dir = root.path().join(format!("dir-{}/sub", i));
+            fs::create_dir_all(&dir).unwrap();
+            for j in 0..BATCH * 2 + i {
+                fs::write(dir.join(format!("file-{}", j)), [i as u8, 1])
+                    .unwrap();
+            }
+            symlink("file-0", dir.join("link")).unwrap();
+            fs::write(dir.join("big"), vec![i as u8; 3 << 20]).unwrap();
+        }
+        let socket = root.path().join("socket");
+        let _listener =
+            std::os::unix::net::UnixListener::bind(socket).unwrap();
+
+        let repo = repo();
+        fs::create_dir(repo.path().join("layers")).unwrap();
+        fs::create_dir(repo.path().join("trees")).unwrap();
+        let repo_dir = repo.path().to_str().unwrap();
+        let import_with = |io_backend| {
+            let options = ImportOptions {
+                same_device: false,
+                progress: ProgressMode::None,
+                verbose: 0,
+                delta_from: None,
+                xattrs: XattrFilter::ALL,
+                walk_threads: Some(2),
+                hash_threads: Some(2),
+                io_backend,
+                honor_nodump: false,
+                max_open_files: None,
+                raise_fd_limit: false,
+                traversal: Traversal::Breadth,
+                file_order: FileOrder::Dirent,
+            };
+            import(root.path().to_str().unwrap(), repo_dir, &options)
+        };
+
+        // Either way, the socket fails the walk the same way.
+        let err = |io_backend| import_with(io_backend).unwrap_err();
+        let sync = err(IoBackend::Sync).to_string();
+        assert_eq!(err(IoBackend::Uring).to_string(), sync);
+        assert!(sync.starts_with("./socket: "), "{}", sync);
+
+        fs::remove_file(root.path().join("socket")).unwrap();
+        let sync = import_with(IoBackend::Sync).unwrap();
+        let uring = import_with(IoBackend::Uring).unwrap();
+        assert_eq!(uring.layer, sync.layer);
+        assert_eq!((uring.files, uring.links), (sync.files, sync.links));
+        assert_eq!(uring.errors, 0);
+    }
+}
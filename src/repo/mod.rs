@@ -1,3 +1,13 @@
+pub mod browse;
+pub mod config;
+pub mod export;
 pub mod layer;
+pub mod mount;
 pub mod object;
+pub mod oci;
+pub mod overlay;
+pub mod restore;
+pub mod spill;
+pub mod tree;
+pub mod untar;
 
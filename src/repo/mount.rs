@@ -0,0 +1,465 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::ffi::CString;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::repo::layer::{self, Entry, FsState};
+use crate::util::fuse::{self, Request, Session};
+use crate::util::PString;
+
+/// How long the kernel may cache entries and attributes for. Layers are
+/// immutable, so this can be as long as we like.
+const TTL_SECS: u64 = 3600;
+
+struct Node {
+    /// Key of this entry in the `FsState`.
+    key: PString,
+    /// Offset of the final path component in `key`.
+    name_start: usize,
+    parent: u64,
+    /// `DT_*` type, for directory listings.
+    typ: u8,
+    /// Inode numbers of all children, sorted by name.
+    children: Vec<u64>,
+}
+
+impl Node {
+    fn name(&self) -> &[u8] {
+        &self.key.as_bytes()[self.name_start..]
+    }
+}
+
+/// A read-only view of a layer, served over FUSE.
+struct LayerFs {
+    repo_basedir: PathBuf,
+    state: FsState,
+    /// Indexed by inode number; entry 0 is unused and 1 is the root.
+    nodes: Vec<Node>,
+    /// Object sizes, looked up the first time they are needed.
+    sizes: HashMap<u64, u64>,
+    /// Open object files, by file handle.
+    handles: HashMap<u64, File>,
+    next_fh: u64,
+    /// Time of the layer's creation, used for every timestamp.
+    mtime: u64,
+    root_uid: u32,
+    root_gid: u32,
+}
+
+impl LayerFs {
+    fn new(
+        repo_basedir: &str,
+        layer: &str,
+    ) -> Result<LayerFs, Box<dyn Error + Send + Sync>> {
+        let hash = layer::resolve(repo_basedir, layer)?;
+        let state = FsState::load(repo_basedir, &hash)?;
+
+        let mut layer_path = PathBuf::from(repo_basedir);
+        layer_path.push("layers");
+        layer_path.push(&hash);
+        let meta = std::fs::metadata(layer_path)?;
+
+        let root = PString::from_str(".");
+        let mut nodes = vec![
+            Node {
+                key: root.clone(),
+                name_start: 0,
+                parent: 0,
+                typ: libc::DT_UNKNOWN,
+                children: vec![],
+            },
+            Node {
+                key: root.clone(),
+                name_start: 0,
+                parent: fuse::ROOT_ID,
+                typ: libc::DT_DIR,
+                children: vec![],
+            },
+        ];
+
+        // Keys are sorted, so parents are always numbered before their
+        // children, and each directory's children end up sorted by name.
+        let mut inodes: HashMap<Vec<u8>, u64> = HashMap::new();
+        inodes.insert(b".".to_vec(), fuse::ROOT_ID);
+        for (key, entry) in state.children(&root, true) {
+            let bytes = key.as_bytes();
+            let slash = bytes.iter().rposition(|&c| c == b'/').unwrap();
+            let parent = match inodes.get(&bytes[..slash]) {
+                Some(&parent) => parent,
+                // Orphans can't be reached, so leave them out.
+                None => continue,
+            };
+            let ino = nodes.len() as u64;
+            nodes.push(Node {
+                key: key.clone(),
+                name_start: slash + 1,
+                parent,
+                typ: match entry {
+                    Entry::Dir(_) => libc::DT_DIR,
+                    Entry::File(_) => libc::DT_REG,
+                    Entry::Link(_) => libc::DT_LNK,
+                },
+                children: vec![],
+            });
+            nodes[parent as usize].children.push(ino);
+            inodes.insert(bytes.to_vec(), ino);
+        }
+
+        Ok(LayerFs {
+            repo_basedir: PathBuf::from(repo_basedir),
+            state,
+            nodes,
+            sizes: HashMap::new(),
+            handles: HashMap::new(),
+            next_fh: 1,
+            mtime: meta.mtime() as u64,
+            root_uid: meta.uid(),
+            root_gid: meta.gid(),
+        })
+    }
+
+    fn node(&self, ino: u64) -> io::Result<&Node> {
+        match self.nodes.get(ino as usize) {
+            Some(node) if ino != 0 => Ok(node),
+            _ => Err(io::Error::from_raw_os_error(libc::ENOENT)),
+        }
+    }
+
+    fn entry(&self, ino: u64) -> io::Result<Option<Entry<'_>>> {
+        let node = self.node(ino)?;
+        if ino == fuse::ROOT_ID {
+            return Ok(None);
+        }
+        Ok(self.state.get(&node.key))
+    }
+
+    fn object_path(&self, hash: &str) -> PathBuf {
+        let mut path = self.repo_basedir.clone();
+        path.push("objects");
+        path.push(hash);
+        path
+    }
+
+    fn attr(&mut self, ino: u64) -> io::Result<fuse::Attr> {
+        let mut attr = fuse::Attr {
+            ino,
+            atime: self.mtime,
+            mtime: self.mtime,
+            ctime: self.mtime,
+            nlink: 1,
+            blksize: 4096,
+            ..Default::default()
+        };
+
+        let (kind, perms, uid, gid, size) = match self.entry(ino)? {
+            None => (libc::S_IFDIR, 0o755, self.root_uid, self.root_gid, 0),
+            Some(Entry::Dir(dir)) => {
+                (libc::S_IFDIR, dir.perms, dir.uid, dir.gid, 0)
+            }
+            Some(Entry::File(obj)) => {
+                let (perms, uid, gid) = (obj.perms, obj.uid, obj.gid);
+                let size = match self.sizes.get(&ino) {
+                    Some(&size) => size,
+                    None => {
+                        let path = self.object_path(&obj.hash);
+                        let size = std::fs::metadata(path)?.len();
+                        self.sizes.insert(ino, size);
+                        size
+                    }
+                };
+                (libc::S_IFREG, perms, uid, gid, size)
+            }
+            Some(Entry::Link(link)) => {
+                attr.mtime = link.mtime.max(0) as u64;
+                attr.mtimensec = link.mtime_nsec;
+                (
+                    libc::S_IFLNK,
+                    0o777,
+                    link.uid,
+                    link.gid,
+                    link.target.as_bytes().len() as u64,
+                )
+            }
+        };
+
+        attr.mode = kind | perms;
+        attr.uid = uid;
+        attr.gid = gid;
+        attr.size = size;
+        attr.blocks = size.div_ceil(512);
+        if kind == libc::S_IFDIR {
+            attr.nlink = 2;
+        }
+        Ok(attr)
+    }
+
+    fn xattrs(&self, ino: u64) -> io::Result<Option<&BTreeMap<CString, Vec<u8>>>> {
+        Ok(match self.entry(ino)? {
+            Some(Entry::Dir(dir)) => dir.xattrs.as_ref(),
+            Some(Entry::File(obj)) => obj.xattrs.as_ref(),
+            Some(Entry::Link(link)) => link.xattrs.as_ref(),
+            None => None,
+        })
+    }
+
+    /// Handle a single request, returning the reply on success.
+    fn handle(&mut self, req: &Request) -> io::Result<Option<Vec<u8>>> {
+        use crate::util::fuse::Pod;
+
+        let ino = req.header.nodeid;
+        let entry_out = |fs: &mut LayerFs, ino| -> io::Result<Vec<u8>> {
+            Ok(fuse::EntryOut {
+                nodeid: ino,
+                entry_valid: TTL_SECS,
+                attr_valid: TTL_SECS,
+                attr: fs.attr(ino)?,
+                ..Default::default()
+            }
+            .as_bytes()
+            .to_vec())
+        };
+
+        Ok(Some(match req.header.opcode {
+            fuse::FUSE_INIT => {
+                let init: fuse::InitIn = req.arg()?;
+                if init.major != fuse::KERNEL_VERSION {
+                    return Err(io::Error::from_raw_os_error(libc::EPROTO));
+                }
+                fuse::InitOut {
+                    major: fuse::KERNEL_VERSION,
+                    minor: fuse::KERNEL_MINOR_VERSION.min(init.minor),
+                    max_readahead: init.max_readahead,
+                    flags: init.flags
+                        & (fuse::FUSE_ASYNC_READ | fuse::FUSE_MAX_PAGES),
+                    max_background: 16,
+                    congestion_threshold: 12,
+                    max_write: fuse::MAX_READ,
+                    time_gran: 1,
+                    max_pages: (fuse::MAX_READ / 4096) as u16,
+                    ..Default::default()
+                }
+                .as_bytes()
+                .to_vec()
+            }
+            fuse::FUSE_DESTROY => vec![],
+            fuse::FUSE_FORGET
+            | fuse::FUSE_BATCH_FORGET
+            | fuse::FUSE_INTERRUPT => return Ok(None),
+
+            fuse::FUSE_LOOKUP => {
+                let name = req.name()?;
+                let node = self.node(ino)?;
+                let child = node
+                    .children
+                    .binary_search_by(|&c| self.nodes[c as usize].name().cmp(name))
+                    .map(|i| node.children[i])
+                    .map_err(|_| io::Error::from_raw_os_error(libc::ENOENT))?;
+                entry_out(self, child)?
+            }
+            fuse::FUSE_GETATTR => fuse::AttrOut {
+                attr_valid: TTL_SECS,
+                attr: self.attr(ino)?,
+                ..Default::default()
+            }
+            .as_bytes()
+            .to_vec(),
+            fuse::FUSE_READLINK => match self.entry(ino)? {
+                Some(Entry::Link(link)) => link.target.as_bytes().to_vec(),
+                _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+            },
+
+            fuse::FUSE_OPEN => {
+                let open: fuse::OpenIn = req.arg()?;
+                if open.flags as i32 & libc::O_ACCMODE != libc::O_RDONLY {
+                    return Err(io::Error::from_raw_os_error(libc::EROFS));
+                }
+                let hash = match self.entry(ino)? {
+                    Some(Entry::File(obj)) => obj.hash.clone(),
+                    _ => return Err(io::Error::from_raw_os_error(libc::EISDIR)),
+                };
+                let file = File::open(self.object_path(&hash))?;
+                let fh = self.next_fh;
+                self.next_fh += 1;
+                self.handles.insert(fh, file);
+                fuse::OpenOut {
+                    fh,
+                    open_flags: fuse::FOPEN_KEEP_CACHE,
+                    ..Default::default()
+                }
+                .as_bytes()
+                .to_vec()
+            }
+            fuse::FUSE_READ => {
+                let read: fuse::ReadIn = req.arg()?;
+                let file = self
+                    .handles
+                    .get(&read.fh)
+                    .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))?;
+                let mut buf = vec![0; read.size as usize];
+                let mut n = 0;
+                while n < buf.len() {
+                    match file.read_at(&mut buf[n..], read.offset + n as u64)? {
+                        0 => break,
+                        m => n += m,
+                    }
+                }
+                buf.truncate(n);
+                buf
+            }
+            fuse::FUSE_RELEASE => {
+                let release: fuse::ReleaseIn = req.arg()?;
+                self.handles.remove(&release.fh);
+                vec![]
+            }
+            fuse::FUSE_FLUSH => vec![],
+
+            fuse::FUSE_OPENDIR => {
+                if !matches!(self.entry(ino)?, None | Some(Entry::Dir(_))) {
+                    return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+                }
+                fuse::OpenOut::default().as_bytes().to_vec()
+            }
+            fuse::FUSE_READDIR => {
+                let read: fuse::ReadIn = req.arg()?;
+                let node = self.node(ino)?;
+                let dots: [(u64, &[u8]); 2] =
+                    [(ino, b"."), (node.parent, b"..")];
+                let children = node.children.iter().map(|&c| {
+                    (c, self.nodes[c as usize].name())
+                });
+
+                // Offsets are just positions in this list, so resuming a
+                // listing is a matter of skipping that many entries.
+                let mut buf = Vec::with_capacity(read.size as usize);
+                for (i, (child, name)) in dots
+                    .iter()
+                    .copied()
+                    .chain(children)
+                    .enumerate()
+                    .skip(read.offset as usize)
+                {
+                    if !fuse::push_dirent(
+                        &mut buf,
+                        read.size as usize,
+                        child,
+                        i as u64 + 1,
+                        self.nodes[child as usize].typ,
+                        name,
+                    ) {
+                        break;
+                    }
+                }
+                buf
+            }
+            fuse::FUSE_RELEASEDIR => vec![],
+
+            fuse::FUSE_GETXATTR => {
+                let getxattr: fuse::GetxattrIn = req.arg()?;
+                let name = CString::new(req.name_after::<fuse::GetxattrIn>()?)?;
+                let value = self
+                    .xattrs(ino)?
+                    .and_then(|x| x.get(&name))
+                    .ok_or_else(|| io::Error::from_raw_os_error(libc::ENODATA))?;
+                sized_reply(getxattr.size, value)?
+            }
+            fuse::FUSE_LISTXATTR => {
+                let getxattr: fuse::GetxattrIn = req.arg()?;
+                let mut list = vec![];
+                for name in self.xattrs(ino)?.iter().flat_map(|x| x.keys()) {
+                    list.extend_from_slice(name.as_bytes_with_nul());
+                }
+                sized_reply(getxattr.size, &list)?
+            }
+
+            fuse::FUSE_STATFS => fuse::StatfsOut {
+                files: self.nodes.len() as u64 - 1,
+                bsize: 4096,
+                frsize: 4096,
+                namelen: 255,
+                ..Default::default()
+            }
+            .as_bytes()
+            .to_vec(),
+
+            _ => return Err(io::Error::from_raw_os_error(libc::ENOSYS)),
+        }))
+    }
+}
+
+/// Reply to a `getxattr`-style request: a size of zero asks how big the
+/// value is, otherwise the value is wanted if it fits.
+fn sized_reply(size: u32, value: &[u8]) -> io::Result<Vec<u8>> {
+    use crate::util::fuse::Pod;
+
+    if size == 0 {
+        let out = fuse::GetxattrOut { size: value.len() as u32, padding: 0 };
+        Ok(out.as_bytes().to_vec())
+    } else if (size as usize) < value.len() {
+        Err(io::Error::from_raw_os_error(libc::ERANGE))
+    } else {
+        Ok(value.to_vec())
+    }
+}
+
+/// Mount a layer read-only at `mountpoint`, serving requests until it is
+/// unmounted or we are interrupted.
+/// The result of `mount`, once the layer has been unmounted again.
+#[derive(Debug, Serialize)]
+pub struct MountSummary {
+    pub layer: String,
+    pub mountpoint: PathBuf,
+    /// FUSE requests served while mounted.
+    pub requests: u64,
+    pub duration_ms: u64,
+}
+
+impl fmt::Display for MountSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Served {} requests for {} at {}",
+            self.requests,
+            self.layer,
+            self.mountpoint.display()
+        )
+    }
+}
+
+pub fn mount(
+    repo_basedir: &str,
+    layer: &str,
+    mountpoint: &Path,
+    allow_other: bool,
+) -> Result<MountSummary, Box<dyn Error + Send + Sync>> {
+    let start = Instant::now();
+    let mut fs = LayerFs::new(repo_basedir, layer)?;
+    fuse::stop_on_signals()?;
+    let session = Session::mount(mountpoint, "banyan", allow_other)?;
+
+    let mut requests = 0;
+    let mut buf = vec![0u8; fuse::MAX_READ as usize + 4096];
+    while let Some(req) = session.next(&mut buf)? {
+        requests += 1;
+        let unique = req.header.unique;
+        match fs.handle(&req) {
+            Ok(Some(reply)) => session.reply(unique, &[&reply])?,
+            Ok(None) => {}
+            Err(e) => session
+                .reply_err(unique, e.raw_os_error().unwrap_or(libc::EIO))?,
+        }
+    }
+
+    Ok(MountSummary {
+        layer: layer.to_string(),
+        mountpoint: mountpoint.to_path_buf(),
+        requests,
+        duration_ms: start.elapsed().as_millis() as u64,
+    })
+}
@@ -1,69 +1,564 @@
 use std::ffi::CString;
 use std::fs;
 use std::io;
-use std::io::Read;
+use std::io::{Read, Write};
+use std::mem::ManuallyDrop;
 use std::os::unix::prelude::RawFd;
 use std::cell::RefCell;
 
+/// The smallest read buffer.
+const MIN_BUF: usize = 16 << 10;
+/// Files smaller than this are read whole into memory and hashed, and only
+/// written out if the object is new.
+pub(crate) const WHOLE_FILE_MAX: usize = 1 << 20;
+/// Anything bigger is read this much at a time, each chunk hashed and then
+/// written to a temporary object while it is still in memory.
+const CHUNK: usize = 4 << 20;
+/// Inputs at least this big are hashed across several threads, which BLAKE3
+/// can do by hashing parts of its tree separately.
+const PARALLEL_MIN: usize = 128 << 10;
+
 thread_local! {
-    pub static READ_BUF: RefCell<Vec<u8>> = RefCell::new(vec![0u8; 16384]);
+    /// Grows as big files come along, up to `CHUNK`.
+    static READ_BUF: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
 }
 
+/// Open the object store of a repository, for use with `import` and
+/// `import_stream`.
 #[cfg(unix)]
-pub fn import(file: RawFd, repofd: RawFd) -> Result<String, std::io::Error> {
-    use std::{
-        io::Seek,
-        os::unix::prelude::{FromRawFd, IntoRawFd}, borrow::BorrowMut,
-    };
+pub fn open_store(repo_basedir: &str) -> Result<RawFd, std::io::Error> {
+    use std::os::unix::ffi::OsStrExt;
 
-    use libc::{O_CREAT, O_EXCL, O_WRONLY};
+    let mut objects = std::path::PathBuf::from(repo_basedir);
+    objects.push("objects");
+    crate::util::open(
+        &CString::new(objects.as_os_str().as_bytes())?,
+        libc::O_DIRECTORY,
+    )
+}
 
-    use crate::util::openat;
+/// The result of importing a single file into the object store.
+#[derive(Debug)]
+pub struct Imported {
+    pub hash: String,
+    /// Number of bytes hashed.
+    pub size: u64,
+    /// Whether the object was newly written, rather than already present.
+    pub stored: bool,
+    /// Whether the new object shares its blocks with the file.
+    pub cloned: bool,
+}
 
-    let mut hasher = blake3::Hasher::new();
-    let mut file = unsafe { fs::File::from_raw_fd(file) };
+/// How `import` should go about storing a file.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Strategy {
+    /// The file's contents are probably stored already, so hash it before
+    /// copying it.
+    pub hash_first: bool,
+    /// Share the file's blocks with the object rather than copy them, where
+    /// the filesystem can.
+    pub reflink: bool,
+    /// Read the next chunk of a big file and write out the last one through
+    /// io_uring while hashing this one, where it is available.
+    pub uring: bool,
+}
+
+/// Import an open file into the object store. The file is left open.
+///
+/// Files too big to read whole are copied into the store as they are hashed,
+/// so that they are only read once, unless `strategy` says to hash them
+/// first. Then they are only copied if the object turns out to be new, by
+/// cloning or copying within the kernel where possible.
+#[cfg(unix)]
+pub fn import(
+    file: RawFd,
+    repofd: RawFd,
+    strategy: Strategy,
+) -> Result<Imported, std::io::Error> {
+    use std::io::Seek;
+    use std::os::unix::prelude::FromRawFd;
+
+    let mut file = ManuallyDrop::new(unsafe { fs::File::from_raw_fd(file) });
+    let before = file.metadata()?;
+    let size = before.len();
 
     READ_BUF.with(|buf| {
         let mut buf = buf.borrow_mut();
-        #[allow(irrefutable_let_patterns)]
-        while let n = file.read(&mut buf)? {
-            if n != 16384 {
-                let rest = &buf[0..n];
-                hasher.update(rest);
-                break;
-            } else {
-                hasher.update(&buf);
+        // Cloning beats writing even small files.
+        if size < WHOLE_FILE_MAX as u64 && !strategy.reflink {
+            // One byte more than the file, to see that it really ends there.
+            let want = (size as usize + 1).max(MIN_BUF);
+            grow(&mut buf, want);
+            let n = read_full(&mut *file, &mut buf[..want])?;
+            if n < want {
+                return store_whole(&buf[..n], repofd);
             }
+            // It grew since we looked, so stream it after all.
+            file.rewind()?;
         }
-    
-        let hash = base64::encode_config(
-            hasher.finalize().as_bytes(),
-            base64::URL_SAFE_NO_PAD,
-        );
-    
-        file.rewind()?;
-    
-        let ret = match openat(
-            repofd,
-            &CString::new(hash.clone())?,
-            O_CREAT | O_EXCL | O_WRONLY,
-        ) {
-            Ok(fd) => {
-                let mut resfile = unsafe { fs::File::from_raw_fd(fd) };
-                io::copy(&mut file, &mut resfile)?;
-                Ok(hash)
+        let len = if size < CHUNK as u64 {
+            (size as usize + 1).max(MIN_BUF)
+        } else {
+            CHUNK
+        };
+        #[cfg(feature = "io-uring")]
+        if strategy.uring
+            && !(strategy.hash_first || strategy.reflink)
+            && crate::util::uring::probe().is_ok()
+        {
+            grow(&mut buf, 2 * len);
+            return store_uring(&file, &mut buf[..2 * len], repofd);
+        }
+        grow(&mut buf, len);
+        let buf = &mut buf[..len];
+        if strategy.hash_first || strategy.reflink {
+            let (hash, hashed) = hash(&mut *file, buf)?;
+            if exists(repofd, &hash)? {
+                let (size, stored, cloned) = (hashed, false, false);
+                return Ok(Imported { hash, size, stored, cloned });
             }
-            Err(e) => {
-                if e.kind() == io::ErrorKind::AlreadyExists {
-                    Ok(hash)
-                } else {
-                    Err(e)
-                }
+            let copied =
+                store_copy(&file, &before, hash, hashed, repofd, strategy)?;
+            if let Some(imported) = copied {
+                return Ok(imported);
             }
-        };    
-        // Do not close!
-        file.into_raw_fd();
+            file.rewind()?;
+        }
+        store_stream(&mut *file, buf, repofd)
+    })
+}
 
-        ret    
+/// Import an object whose contents can only be read once, such as a member
+/// of a tar stream. The contents are written to a temporary file while being
+/// hashed, which is then linked into place under its hash, or thrown away if
+/// the object already exists.
+#[cfg(unix)]
+pub fn import_stream(
+    src: &mut dyn Read,
+    repofd: RawFd,
+) -> Result<Imported, std::io::Error> {
+    READ_BUF.with(|buf| {
+        let mut buf = buf.borrow_mut();
+        grow(&mut buf, CHUNK);
+        store_stream(src, &mut buf[..CHUNK], repofd)
     })
 }
+
+/// Copy the whole of `src` into the empty file `dst`, returning how many
+/// bytes that was and whether they were cloned.
+///
+/// With `reflink`, `dst` shares `src`'s blocks if the filesystem can do that
+/// (btrfs and XFS can, within one filesystem). Otherwise `io::copy` copies
+/// within the kernel with `copy_file_range` where it can, and reads and
+/// writes where it can't.
+#[cfg(unix)]
+pub(crate) fn copy(
+    mut src: &fs::File,
+    mut dst: &fs::File,
+    reflink: bool,
+) -> io::Result<(u64, bool)> {
+    use std::io::Seek;
+    use std::os::unix::prelude::AsRawFd;
+
+    if reflink && crate::util::reflink(src.as_raw_fd(), dst.as_raw_fd())? {
+        return Ok((src.metadata()?.len(), true));
+    }
+    src.rewind()?;
+    Ok((io::copy(&mut src, &mut dst)?, false))
+}
+
+fn grow(buf: &mut Vec<u8>, len: usize) {
+    if buf.len() < len {
+        buf.resize(len, 0);
+    }
+}
+
+/// Read until `buf` is full or the input ends, returning how much was read.
+fn read_full(src: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match src.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(read) => n += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+fn update(hasher: &mut blake3::Hasher, data: &[u8]) {
+    if data.len() >= PARALLEL_MIN {
+        hasher.update_with_join::<blake3::join::RayonJoin>(data);
+    } else {
+        hasher.update(data);
+    }
+}
+
+fn encode(hasher: &blake3::Hasher) -> String {
+    let hash = hasher.finalize();
+    base64::encode_config(hash.as_bytes(), base64::URL_SAFE_NO_PAD)
+}
+
+/// Hash all of `src`, returning the hash and how many bytes there were.
+fn hash(src: &mut dyn Read, buf: &mut [u8]) -> io::Result<(String, u64)> {
+    let mut hasher = blake3::Hasher::new();
+    let mut size: u64 = 0;
+    loop {
+        let n = read_full(src, buf)?;
+        if n == 0 {
+            break;
+        }
+        update(&mut hasher, &buf[..n]);
+        size += n as u64;
+    }
+    Ok((encode(&hasher), size))
+}
+
+/// Whether the store has the object `hash` already.
+#[cfg(unix)]
+fn exists(repofd: RawFd, hash: &str) -> io::Result<bool> {
+    match crate::util::lstatat(repofd, &CString::new(hash)?) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// A new object, under a temporary name until it is linked under its hash.
+/// Dropping it removes the temporary name.
+#[cfg(unix)]
+struct TmpObject {
+    repofd: RawFd,
+    name: CString,
+    file: fs::File,
+}
+
+#[cfg(unix)]
+impl TmpObject {
+    fn create(repofd: RawFd) -> io::Result<TmpObject> {
+        use std::os::unix::prelude::FromRawFd;
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        use libc::{O_CREAT, O_EXCL, O_WRONLY};
+
+        static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+        let name = CString::new(format!(
+            ".tmp.{}.{}",
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ))?;
+        let flags = O_CREAT | O_EXCL | O_WRONLY;
+        let fd = crate::util::openat(repofd, &name, flags)?;
+        let file = unsafe { fs::File::from_raw_fd(fd) };
+        Ok(TmpObject { repofd, name, file })
+    }
+
+    /// Link the object under `hash`, returning whether it is new, rather
+    /// than already stored.
+    fn link(self, hash: &str) -> io::Result<bool> {
+        let name = CString::new(hash)?;
+        let ret = unsafe {
+            libc::linkat(
+                self.repofd,
+                self.name.as_ptr(),
+                self.repofd,
+                name.as_ptr(),
+                0,
+            )
+        };
+        if ret == 0 {
+            return Ok(true);
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() == io::ErrorKind::AlreadyExists {
+            Ok(false)
+        } else {
+            Err(e)
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for TmpObject {
+    fn drop(&mut self) {
+        // The object is either linked under its hash now, or not wanted.
+        unsafe { libc::unlinkat(self.repofd, self.name.as_ptr(), 0) };
+    }
+}
+
+/// Store `file`, already hashed as `hash`, without reading it into memory
+/// again. Returns `None` if it changed since it was hashed, and has to be
+/// hashed again as it is stored.
+#[cfg(unix)]
+fn store_copy(
+    file: &fs::File,
+    before: &fs::Metadata,
+    hash: String,
+    size: u64,
+    repofd: RawFd,
+    strategy: Strategy,
+) -> io::Result<Option<Imported>> {
+    use std::os::unix::fs::MetadataExt;
+
+    let tmp = TmpObject::create(repofd)?;
+    let (_, cloned) = copy(file, &tmp.file, strategy.reflink)?;
+    let after = file.metadata()?;
+    let unchanged = |m: &fs::Metadata| {
+        m.len() == size
+            && m.mtime() == before.mtime()
+            && m.mtime_nsec() == before.mtime_nsec()
+    };
+    if !unchanged(before) || !unchanged(&after) {
+        return Ok(None);
+    }
+    let stored = tmp.link(&hash)?;
+    Ok(Some(Imported { hash, size, stored, cloned: cloned && stored }))
+}
+
+/// Store contents that are all in memory already, through a temporary
+/// object like the rest, so that no reader ever sees it half written.
+#[cfg(unix)]
+fn store_whole(data: &[u8], repofd: RawFd) -> io::Result<Imported> {
+    let mut hasher = blake3::Hasher::new();
+    update(&mut hasher, data);
+    let hash = encode(&hasher);
+    let size = data.len() as u64;
+
+    if exists(repofd, &hash)? {
+        return Ok(Imported { hash, size, stored: false, cloned: false });
+    }
+    let mut tmp = TmpObject::create(repofd)?;
+    tmp.file.write_all(data)?;
+    let stored = tmp.link(&hash)?;
+    Ok(Imported { hash, size, stored, cloned: false })
+}
+
+/// Store contents read from `src` a buffer at a time, through a temporary
+/// object.
+#[cfg(unix)]
+fn store_stream(
+    src: &mut dyn Read,
+    buf: &mut [u8],
+    repofd: RawFd,
+) -> io::Result<Imported> {
+    let mut tmp = TmpObject::create(repofd)?;
+    let mut hasher = blake3::Hasher::new();
+    let mut size: u64 = 0;
+    loop {
+        let n = read_full(src, buf)?;
+        if n == 0 {
+            break;
+        }
+        update(&mut hasher, &buf[..n]);
+        tmp.file.write_all(&buf[..n])?;
+        size += n as u64;
+    }
+
+    let hash = encode(&hasher);
+    let stored = tmp.link(&hash)?;
+    Ok(Imported { hash, size, stored, cloned: false })
+}
+
+/// Store a file the way `store_stream` does, but with the reads and writes
+/// queued on this thread's io_uring to run while the hashing doesn't. `buf`
+/// is split in two, one half filling while the other is hashed and written.
+#[cfg(feature = "io-uring")]
+fn store_uring(
+    file: &fs::File,
+    buf: &mut [u8],
+    repofd: RawFd,
+) -> io::Result<Imported> {
+    use crate::util::uring;
+
+    let tmp = TmpObject::create(repofd)?;
+    let mut hasher = blake3::Hasher::new();
+    let size = uring::with(|ring| {
+        let mut pipe = Pipe { ring, pending: [None; 2], written: [(0, 0); 2] };
+        let res = pipe.run(file, &tmp.file, buf, &mut hasher);
+        // The buffer can't be used again while the kernel may still be
+        // using it.
+        for tag in pipe.pending.iter().flatten() {
+            let _ = pipe.ring.finish(*tag);
+        }
+        res
+    })?;
+
+    let hash = encode(&hasher);
+    let stored = tmp.link(&hash)?;
+    Ok(Imported { hash, size, stored, cloned: false })
+}
+
+/// The state of `store_uring`.
+#[cfg(feature = "io-uring")]
+struct Pipe<'a> {
+    ring: &'a mut crate::util::uring::Ring,
+    /// The operation running on each half of the buffer, if any.
+    pending: [Option<u64>; 2],
+    /// Where each half was last written to, and how much of it.
+    written: [(u64, usize); 2],
+}
+
+#[cfg(feature = "io-uring")]
+impl Pipe<'_> {
+    /// Wait for the write from half `i`, at `half`, if there is one, and
+    /// finish it off if the kernel wrote less than all of it, as it may.
+    fn flush(
+        &mut self,
+        i: usize,
+        dst: &fs::File,
+        half: *const u8,
+    ) -> io::Result<()> {
+        use std::os::unix::fs::FileExt;
+
+        let tag = match self.pending[i] {
+            Some(tag) => tag,
+            None => return Ok(()),
+        };
+        // If the ring fails, the write is still pending.
+        let res = self.ring.wait(tag)?;
+        self.pending[i] = None;
+        let n = res? as usize;
+        let (at, len) = self.written[i];
+        if n < len {
+            // SAFETY: the write is over, so the kernel is done with it.
+            let data = unsafe { std::slice::from_raw_parts(half, len) };
+            dst.write_all_at(&data[n..], at + n as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Copy `src` to `dst`, hashing it on the way, and return its size.
+    fn run(
+        &mut self,
+        src: &fs::File,
+        dst: &fs::File,
+        buf: &mut [u8],
+        hasher: &mut blake3::Hasher,
+    ) -> io::Result<u64> {
+        use std::os::unix::prelude::AsRawFd;
+
+        use io_uring::{opcode, types};
+
+        let half = buf.len() / 2;
+        let base = buf.as_mut_ptr();
+        // SAFETY: both halves are within `buf`.
+        let halves = [base, unsafe { base.add(half) }];
+        let read = |at: u64, i: usize| {
+            opcode::Read::new(types::Fd(src.as_raw_fd()), halves[i], half as _)
+                .offset(at)
+                .build()
+        };
+        let write = |at: u64, i: usize, len: usize| {
+            opcode::Write::new(types::Fd(dst.as_raw_fd()), halves[i], len as _)
+                .offset(at)
+                .build()
+        };
+
+        // SAFETY, for every push: `store_uring` waits for everything still
+        // pending before the buffer goes away, and nothing new is started on
+        // a half until what was running on it is done.
+        let mut size: u64 = 0;
+        let mut cur = 0;
+        self.pending[cur] = Some(unsafe { self.ring.push(read(0, cur))? });
+        loop {
+            let tag = self.pending[cur].expect("a read is running");
+            let res = self.ring.wait(tag)?;
+            self.pending[cur] = None;
+            let n = res? as usize;
+            if n == 0 {
+                break;
+            }
+            let next = 1 - cur;
+            self.flush(next, dst, halves[next])?;
+            let at = size + n as u64;
+            let op = read(at, next);
+            self.pending[next] = Some(unsafe { self.ring.push(op)? });
+
+            // SAFETY: nothing is running on this half now.
+            let data = unsafe { std::slice::from_raw_parts(halves[cur], n) };
+            update(hasher, data);
+            self.written[cur] = (size, n);
+            let op = write(size, cur, n);
+            self.pending[cur] = Some(unsafe { self.ring.push(op)? });
+            size += n as u64;
+            cur = next;
+        }
+        self.flush(1 - cur, dst, halves[1 - cur])?;
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Seek;
+    use std::os::unix::prelude::AsRawFd;
+
+    use super::*;
+
+    /// Every way of storing a file.
+    const STRATEGIES: [Strategy; 4] = [
+        Strategy { hash_first: false, reflink: false, uring: false },
+        Strategy { hash_first: true, reflink: false, uring: false },
+        Strategy { hash_first: false, reflink: true, uring: false },
+        Strategy { hash_first: false, reflink: false, uring: true },
+    ];
+
+    /// Import files on either side of each size that changes how they are
+    /// read, each way they can be stored, checking that they hash the same
+    /// as in one go and are stored intact, and only once.
+    #[test]
+    fn import_sizes() {
+        let sizes = [
+            0,
+            1,
+            MIN_BUF,
+            WHOLE_FILE_MAX - 1,
+            WHOLE_FILE_MAX,
+            CHUNK,
+            2 * CHUNK + 12345,
+        ];
+        for strategy in STRATEGIES {
+            let dir = tempfile::tempdir().unwrap();
+            let objects = dir.path().join("objects");
+            fs::create_dir(&objects).unwrap();
+            let objects = fs::File::open(&objects).unwrap();
+
+            for size in sizes {
+                let data: Vec<u8> =
+                    (0..size).map(|i| (i * 7 + i / 4093) as u8).collect();
+                let path = dir.path().join(format!("file{}", size));
+                fs::write(&path, &data).unwrap();
+                let file = fs::File::open(&path).unwrap();
+
+                let imported =
+                    import(file.as_raw_fd(), objects.as_raw_fd(), strategy)
+                        .unwrap();
+                let expected = base64::encode_config(
+                    blake3::hash(&data).as_bytes(),
+                    base64::URL_SAFE_NO_PAD,
+                );
+                let what = format!("{} bytes, {:?}", size, strategy);
+                assert_eq!(imported.hash, expected, "{}", what);
+                assert_eq!(imported.size, size as u64);
+                assert!(imported.stored);
+                let stored = dir.path().join("objects").join(&imported.hash);
+                assert!(fs::read(stored).unwrap() == data, "{}", what);
+
+                // The file is still open, and its contents are known now.
+                for strategy in STRATEGIES {
+                    (&file).rewind().unwrap();
+                    let again =
+                        import(file.as_raw_fd(), objects.as_raw_fd(), strategy)
+                            .unwrap();
+                    assert_eq!(again.hash, expected);
+                    assert!(!again.stored && !again.cloned);
+                }
+            }
+
+            // Nothing is left behind but the objects.
+            let names = fs::read_dir(dir.path().join("objects")).unwrap();
+            assert_eq!(names.count(), sizes.len());
+        }
+    }
+}
@@ -0,0 +1,508 @@
+//! OCI image layouts: a directory of blobs named by their sha256 digest,
+//! with an `index.json` pointing at image manifests, which in turn list a
+//! config and the image's layers from the bottom up.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use flate2::read::GzDecoder;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::progress::Progress;
+use crate::repo::export::{self, ExportSummary};
+use crate::repo::layer::{self, FsState, ImportOptions};
+use crate::repo::{object, untar};
+use crate::util::{self, tar};
+
+const LAYOUT_VERSION: &str = "1.0.0";
+const INDEX: &str = "application/vnd.oci.image.index.v1+json";
+const MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+const CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+const LAYER: &str = "application/vnd.oci.image.layer.v1.tar";
+const LAYER_GZIP: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+/// Media types used by Docker before OCI, which layouts may still contain.
+const DOCKER_LIST: &str =
+    "application/vnd.docker.distribution.manifest.list.v2+json";
+const DOCKER_LAYER: &str = "application/vnd.docker.image.rootfs.diff.tar";
+const DOCKER_LAYER_GZIP: &str =
+    "application/vnd.docker.image.rootfs.diff.tar.gzip";
+const REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: String,
+    digest: String,
+    size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    platform: Option<Platform>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Platform {
+    architecture: String,
+    os: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ImageLayout {
+    image_layout_version: String,
+}
+
+/// An image index, which is also the format of `index.json`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Index {
+    schema_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    media_type: Option<String>,
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    schema_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    media_type: Option<String>,
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ImageConfig {
+    architecture: String,
+    os: String,
+    rootfs: RootFs,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RootFs {
+    #[serde(rename = "type")]
+    typ: String,
+    diff_ids: Vec<String>,
+}
+
+/// The architecture we run on, as OCI platforms name it.
+fn architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "x86" => "386",
+        "aarch64" => "arm64",
+        "powerpc64" => "ppc64le",
+        arch => arch,
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The path of the blob with the given digest.
+fn blob_path(layout: &Path, digest: &str) -> io::Result<PathBuf> {
+    let valid = digest.strip_prefix("sha256:").filter(|hex| {
+        hex.len() == 64
+            && hex.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'))
+    });
+    match valid {
+        Some(hex) => Ok(layout.join("blobs").join("sha256").join(hex)),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported blob digest {}", digest),
+        )),
+    }
+}
+
+/// Open a blob after checking that its contents match its descriptor.
+fn open_blob(layout: &Path, desc: &Descriptor) -> io::Result<File> {
+    let path = blob_path(layout, &desc.digest)?;
+    let mut file = File::open(&path).map_err(|e| {
+        io::Error::new(e.kind(), format!("{}: {}", desc.digest, e))
+    })?;
+
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut file, &mut hasher)?;
+    let digest = format!("sha256:{}", hex(&hasher.finalize()));
+    if size != desc.size || digest != desc.digest {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("blob {} does not match its digest or size", desc.digest),
+        ));
+    }
+
+    io::Seek::rewind(&mut file)?;
+    Ok(file)
+}
+
+fn read_json<T: DeserializeOwned>(
+    layout: &Path,
+    desc: &Descriptor,
+) -> Result<T, Box<dyn Error + Send + Sync>> {
+    let blob = BufReader::new(open_blob(layout, desc)?);
+    Ok(serde_json::from_reader(blob)?)
+}
+
+/// A blob being written into a layout, named by its digest once done.
+struct BlobWriter {
+    file: File,
+    tmp: PathBuf,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl BlobWriter {
+    fn new(layout: &Path) -> io::Result<BlobWriter> {
+        let tmp = layout
+            .join("blobs")
+            .join("sha256")
+            .join(format!(".tmp.{}", std::process::id()));
+        Ok(BlobWriter {
+            file: File::create(&tmp)?,
+            tmp,
+            hasher: Sha256::new(),
+            size: 0,
+        })
+    }
+
+    fn finish(
+        self,
+        layout: &Path,
+        media_type: &str,
+    ) -> io::Result<Descriptor> {
+        let digest = format!("sha256:{}", hex(&self.hasher.finalize()));
+        fs::rename(&self.tmp, blob_path(layout, &digest)?)?;
+        Ok(Descriptor {
+            media_type: media_type.to_string(),
+            digest,
+            size: self.size,
+            platform: None,
+            annotations: BTreeMap::new(),
+        })
+    }
+}
+
+impl Write for BlobWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn write_blob(
+    layout: &Path,
+    media_type: &str,
+    data: &[u8],
+) -> io::Result<Descriptor> {
+    let mut blob = BlobWriter::new(layout)?;
+    blob.write_all(data)?;
+    blob.finish(layout, media_type)
+}
+
+/// One layer of an image written by `export`.
+#[derive(Debug, Serialize)]
+pub struct OciLayerSummary {
+    pub layer: String,
+    pub digest: String,
+    pub size: u64,
+    pub whiteouts: u64,
+}
+
+/// The result of `export`.
+#[derive(Debug, Serialize)]
+pub struct OciExportSummary {
+    pub tag: String,
+    /// Digest of the image manifest.
+    pub manifest: String,
+    pub layers: Vec<OciLayerSummary>,
+    pub duration_ms: u64,
+}
+
+impl fmt::Display for OciExportSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Wrote image {} ({}) with {} layers",
+            self.tag,
+            self.manifest,
+            self.layers.len()
+        )
+    }
+}
+
+/// Write a stack of layers, from the bottom up, as an image in an OCI image
+/// layout. Each layer becomes an image layer holding its differences from
+/// the one below it. Other images already in the layout are kept.
+pub(crate) fn export(
+    repo_basedir: &str,
+    layers: &[String],
+    tag: &str,
+    out: &Path,
+) -> Result<OciExportSummary, Box<dyn Error + Send + Sync>> {
+    let start = Instant::now();
+    fs::create_dir_all(out.join("blobs").join("sha256"))?;
+
+    let mut lower: Option<String> = None;
+    let mut descriptors = vec![];
+    let mut summary = OciExportSummary {
+        tag: tag.to_string(),
+        manifest: String::new(),
+        layers: vec![],
+        duration_ms: 0,
+    };
+    for layer in layers {
+        let hash = layer::resolve(repo_basedir, layer)?;
+        let state = FsState::load(repo_basedir, &hash)?;
+        let mtime = export::layer_mtime(repo_basedir, &hash)?;
+
+        let mut writer =
+            tar::Writer::new(io::BufWriter::new(BlobWriter::new(out)?));
+        let mut layer_summary = ExportSummary::default();
+        let delta = layer::diff(repo_basedir, lower.as_deref(), &hash)?;
+        export::write_oci_layer(
+            repo_basedir,
+            delta,
+            &state,
+            mtime,
+            &mut writer,
+            &mut layer_summary,
+        )?;
+        let blob =
+            writer.finish()?.into_inner().map_err(|e| e.into_error())?;
+        let desc = blob.finish(out, LAYER)?;
+
+        summary.layers.push(OciLayerSummary {
+            layer: hash.clone(),
+            digest: desc.digest.clone(),
+            size: desc.size,
+            whiteouts: layer_summary.whiteouts,
+        });
+        descriptors.push(desc);
+        lower = Some(hash);
+    }
+
+    // Layers are stored uncompressed, so their digests are their diff IDs.
+    let config = ImageConfig {
+        architecture: architecture().to_string(),
+        os: "linux".to_string(),
+        rootfs: RootFs {
+            typ: "layers".to_string(),
+            diff_ids: descriptors.iter().map(|d| d.digest.clone()).collect(),
+        },
+    };
+    let config = write_blob(out, CONFIG, &serde_json::to_vec(&config)?)?;
+    let manifest = Manifest {
+        schema_version: 2,
+        media_type: Some(MANIFEST.to_string()),
+        config,
+        layers: descriptors,
+    };
+    let mut manifest =
+        write_blob(out, MANIFEST, &serde_json::to_vec(&manifest)?)?;
+    manifest.annotations.insert(REF_NAME.to_string(), tag.to_string());
+    summary.manifest = manifest.digest.clone();
+
+    let index_path = out.join("index.json");
+    let mut index = match fs::read(&index_path) {
+        Ok(data) => serde_json::from_slice(&data)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Index {
+            schema_version: 2,
+            media_type: Some(INDEX.to_string()),
+            manifests: vec![],
+        },
+        Err(e) => return Err(e.into()),
+    };
+    index.manifests.retain(|d| {
+        d.annotations.get(REF_NAME).map(|s| s.as_str()) != Some(tag)
+    });
+    index.manifests.push(manifest);
+    fs::write(&index_path, serde_json::to_vec(&index)?)?;
+    fs::write(
+        out.join("oci-layout"),
+        serde_json::to_vec(&ImageLayout {
+            image_layout_version: LAYOUT_VERSION.to_string(),
+        })?,
+    )?;
+
+    summary.duration_ms = start.elapsed().as_millis() as u64;
+    Ok(summary)
+}
+
+/// The result of `import`.
+#[derive(Debug, Serialize)]
+pub struct OciImportSummary {
+    /// Digest of the image manifest.
+    pub manifest: String,
+    /// The layer stored for each image layer, from the bottom up. The last
+    /// one holds the whole image.
+    pub layers: Vec<String>,
+    pub dirs: u64,
+    pub files: u64,
+    pub links: u64,
+    pub bytes_hashed: u64,
+    pub bytes_stored: u64,
+    pub bytes_deduped: u64,
+    pub errors: u64,
+    pub duration_ms: u64,
+}
+
+impl fmt::Display for OciImportSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Imported {} layers:", self.layers.len())?;
+        for layer in &self.layers {
+            writeln!(f, "{}", layer)?;
+        }
+        write!(
+            f,
+            "Image has {} directories and {} objects",
+            self.dirs,
+            self.files + self.links
+        )
+    }
+}
+
+/// Pick the image manifest named by `tag` out of an index, descending into
+/// nested indexes for the platform we run on.
+fn select_manifest(
+    layout: &Path,
+    manifests: &[Descriptor],
+    tag: Option<&str>,
+) -> Result<Descriptor, Box<dyn Error + Send + Sync>> {
+    let name = |d: &Descriptor| d.annotations.get(REF_NAME).cloned();
+    let desc = match tag {
+        Some(tag) => manifests
+            .iter()
+            .find(|d| name(d).as_deref() == Some(tag))
+            .ok_or_else(|| format!("no image tagged {} in layout", tag))?,
+        None if manifests.len() == 1 => &manifests[0],
+        None => {
+            let names: Vec<_> = manifests.iter().filter_map(name).collect();
+            return Err(format!(
+                "layout holds {} images, pick one with --tag (tags: {})",
+                manifests.len(),
+                names.join(", ")
+            )
+            .into());
+        }
+    };
+
+    match desc.media_type.as_str() {
+        INDEX | DOCKER_LIST => {
+            let index: Index = read_json(layout, desc)?;
+            let ours: Vec<_> = index
+                .manifests
+                .into_iter()
+                .filter(|d| {
+                    d.platform.as_ref().is_none_or(|p| {
+                        p.os == "linux" && p.architecture == architecture()
+                    })
+                })
+                .collect();
+            match ours.first() {
+                Some(_) => select_manifest(layout, &ours[..1], None),
+                None => Err(format!(
+                    "no image for linux/{} in index",
+                    architecture()
+                )
+                .into()),
+            }
+        }
+        _ => Ok(desc.clone()),
+    }
+}
+
+/// Import an image from an OCI image layout as a stack of layers, one for
+/// each of its image layers, with whiteouts applied. Each layer applies to
+/// the whole tree below it, so that is kept in memory throughout.
+pub(crate) fn import(
+    repo_basedir: &str,
+    layout: &Path,
+    tag: Option<&str>,
+    options: &ImportOptions,
+) -> Result<OciImportSummary, Box<dyn Error + Send + Sync>> {
+    let version: ImageLayout = fs::read(layout.join("oci-layout"))
+        .map_err(|e| {
+            format!("{}: not an OCI image layout: {}", layout.display(), e)
+        })
+        .and_then(|data| {
+            serde_json::from_slice(&data).map_err(|e| e.to_string())
+        })?;
+    if !version.image_layout_version.starts_with("1.") {
+        return Err(format!(
+            "unsupported image layout version {}",
+            version.image_layout_version
+        )
+        .into());
+    }
+
+    let index: Index =
+        serde_json::from_slice(&fs::read(layout.join("index.json"))?)?;
+    let desc = select_manifest(layout, &index.manifests, tag)?;
+    let manifest: Manifest = read_json(layout, &desc)?;
+
+    let objectfd = object::open_store(repo_basedir)?;
+    let progress = Progress::new(options.progress, options.verbose);
+    let res = progress.report_while(|| {
+        let mut state = FsState::new();
+        let mut layers = vec![];
+        for blob in &manifest.layers {
+            let file = BufReader::new(open_blob(layout, blob)?);
+            let mut inp: Box<dyn Read> = match blob.media_type.as_str() {
+                LAYER | DOCKER_LAYER => Box::new(file),
+                LAYER_GZIP | DOCKER_LAYER_GZIP => {
+                    Box::new(GzDecoder::new(file))
+                }
+                other => {
+                    return Err(
+                        format!("unsupported layer type {}", other).into()
+                    )
+                }
+            };
+
+            let delta =
+                untar::read_archive(
+                    &mut inp,
+                    objectfd,
+                    &progress,
+                    true,
+                    &options.xattrs,
+                )
+                .map_err(|e| format!("{}: {}", blob.digest, e))?;
+            state.apply(&delta);
+            state.fill_parents(&progress);
+            layers.push(layer::store(repo_basedir, &state, None, &progress)?.layer);
+        }
+        Ok::<_, Box<dyn Error + Send + Sync>>((state, layers))
+    });
+    util::close(objectfd)?;
+    let (state, layers) = res?;
+
+    let stats = progress.snapshot();
+    Ok(OciImportSummary {
+        manifest: desc.digest,
+        layers,
+        dirs: state.dirs.len() as u64,
+        files: state.objects.len() as u64,
+        links: state.links.len() as u64,
+        bytes_hashed: stats.bytes_hashed,
+        bytes_stored: stats.bytes_stored,
+        bytes_deduped: stats.bytes_deduped,
+        errors: stats.errors,
+        duration_ms: stats.elapsed_ms,
+    })
+}
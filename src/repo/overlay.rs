@@ -0,0 +1,314 @@
+//! Overlay semantics for stacking layers. A `Delta` records how one tree
+//! differs from the one below it, the same way an OCI image layer or an
+//! overlayfs upper directory does.
+
+use std::collections::BTreeSet;
+use std::ffi::CString;
+
+use serde::{Deserialize, Serialize};
+
+use crate::progress::Progress;
+use crate::repo::layer::{under, DirState, Entry, FsState, StatxInfo};
+use crate::util::PString;
+
+/// The changes that turn a lower `FsState` into an upper one.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Delta {
+    /// Entries that were added or modified, with their new contents.
+    pub(crate) upper: FsState,
+    /// Entries that were removed, along with everything below them.
+    pub(crate) whiteouts: BTreeSet<PString>,
+    /// Directories whose lower contents are hidden entirely.
+    pub(crate) opaque: BTreeSet<PString>,
+}
+
+/// The parent directory of a key, or `None` for the root.
+fn parent(key: &PString) -> Option<&[u8]> {
+    let key = key.as_bytes();
+    key.iter().rposition(|&c| c == b'/').map(|i| &key[..i])
+}
+
+impl FsState {
+    /// Add a copy of a borrowed entry, replacing whatever was at `key`.
+    pub(crate) fn insert(&mut self, key: PString, entry: Entry) {
+        match entry {
+            Entry::Dir(dir) => {
+                self.dirs.insert(key, dir.clone());
+            }
+            Entry::File(obj) => {
+                self.objects.insert(key, obj.clone());
+            }
+            Entry::Link(target) => {
+                self.links.insert(key, target.clone());
+            }
+        }
+    }
+
+    /// Remove everything below the directory `dir`, but not `dir` itself.
+    pub(crate) fn remove_children(&mut self, dir: &PString) {
+        let prefix = dir.append_path(&CString::default());
+        let dirs: Vec<_> =
+            under(&self.dirs, &prefix).map(|e| e.0.clone()).collect();
+        for key in dirs {
+            self.dirs.remove(&key);
+        }
+        let objects: Vec<_> =
+            under(&self.objects, &prefix).map(|e| e.0.clone()).collect();
+        for key in objects {
+            self.objects.remove(&key);
+        }
+        let links: Vec<_> =
+            under(&self.links, &prefix).map(|e| e.0.clone()).collect();
+        for key in links {
+            self.links.remove(&key);
+        }
+    }
+
+    /// Remove the entry at `key`, and its contents if it is a directory.
+    pub(crate) fn remove_tree(&mut self, key: &PString) {
+        self.dirs.remove(key);
+        self.objects.remove(key);
+        self.links.remove(key);
+        self.remove_children(key);
+    }
+
+    /// Work out the changes that turn `lower` into `self`.
+    pub(crate) fn diff(&self, lower: &FsState) -> Delta {
+        let root = PString::from_str(".");
+        let mut delta = Delta::default();
+        for (key, entry) in self.children(&root, true) {
+            if lower.get(key) != Some(entry) {
+                delta.upper.insert(key.clone(), entry);
+            }
+        }
+
+        // Removing a directory takes its contents with it, and replacing
+        // one with something else does too, so only the topmost removed
+        // entry needs a whiteout.
+        for (key, _) in lower.children(&root, true) {
+            if self.get(key).is_some() {
+                continue;
+            }
+            let parent = parent(key).expect("keys have a parent");
+            let parent_kept = parent == b"."
+                || PString::from_vec(parent.to_vec())
+                    .is_ok_and(|p| self.dirs.contains_key(&p));
+            if parent_kept {
+                delta.whiteouts.insert(key.clone());
+            }
+        }
+        delta
+    }
+
+    /// Put `delta` on top of this tree.
+    pub(crate) fn apply(&mut self, delta: &Delta) {
+        for dir in &delta.opaque {
+            self.remove_children(dir);
+        }
+        for key in &delta.whiteouts {
+            self.remove_tree(key);
+        }
+
+        let root = PString::from_str(".");
+        for (key, entry) in delta.upper.children(&root, true) {
+            // A directory merges with the one below it, anything else
+            // replaces what was there outright.
+            let merges = matches!(
+                (entry, self.get(key)),
+                (Entry::Dir(_), Some(Entry::Dir(_)))
+            );
+            if !merges {
+                self.remove_tree(key);
+            }
+            self.insert(key.clone(), entry);
+        }
+    }
+
+    /// Create any directories that are only implied by the entries below
+    /// them, as archives don't have to list every directory.
+    pub(crate) fn fill_parents(&mut self, progress: &Progress) {
+        let mut implied = vec![];
+        let keys = self
+            .dirs
+            .keys()
+            .chain(self.objects.keys())
+            .chain(self.links.keys());
+        for key in keys {
+            let mut key = key.as_bytes();
+            while let Some(i) = key.iter().rposition(|&c| c == b'/') {
+                key = &key[..i];
+                if key == b"." {
+                    break;
+                }
+                implied.push(key.to_vec());
+            }
+        }
+
+        for key in implied {
+            let key = PString::from_vec(key).expect("keys contain no nul");
+            if self.get(&key).is_none() {
+                Progress::add(&progress.dirs, 1);
+                self.dirs.insert(
+                    key,
+                    DirState {
+                        perms: 0o755,
+                        uid: 0,
+                        gid: 0,
+                        xattrs: None,
+                        statx: StatxInfo::default(),
+                    },
+                );
+            }
+        }
+    }
+
+    /// Check that everything is in a directory, which storing a whole tree
+    /// does along the way, but storing a delta doesn't.
+    pub(crate) fn check_parents(&self) -> Result<(), String> {
+        let keys = self
+            .dirs
+            .keys()
+            .chain(self.objects.keys())
+            .chain(self.links.keys());
+        for key in keys {
+            let parent = parent(key).expect("keys have a parent");
+            let in_dir = parent == b"."
+                || PString::from_vec(parent.to_vec())
+                    .is_ok_and(|p| self.dirs.contains_key(&p));
+            if !in_dir {
+                return Err(format!("{}: parent is not a directory", key));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::layer::{Link, Object};
+
+    /// A tree of `(path, entry)` pairs, where entries are `/` for a
+    /// directory, `@target` for a symlink, and a file's hash otherwise.
+    fn tree(entries: &[(&str, &str)]) -> FsState {
+        let mut state = FsState::default();
+        for &(path, entry) in entries {
+            let key = PString::from_str(path);
+            if entry == "/" {
+                let dir = DirState {
+                    perms: 0o755,
+                    uid: 0,
+                    gid: 0,
+                    xattrs: None,
+                    statx: StatxInfo::default(),
+                };
+                state.dirs.insert(key, dir);
+            } else if let Some(target) = entry.strip_prefix('@') {
+                let link = Link::bare(PString::from_str(target));
+                state.links.insert(key, link);
+            } else {
+                let obj = Object {
+                    hash: entry.to_string(),
+                    perms: 0o644,
+                    uid: 0,
+                    gid: 0,
+                    xattrs: None,
+                    statx: StatxInfo::default(),
+                    inode: None,
+                };
+                state.objects.insert(key, obj);
+            }
+        }
+        state
+    }
+
+    fn keys(state: &FsState) -> Vec<String> {
+        let root = PString::from_str(".");
+        let children = state.children(&root, true);
+        children.into_iter().map(|(key, _)| key.to_string()).collect()
+    }
+
+    fn set(keys: &[&str]) -> BTreeSet<PString> {
+        keys.iter().map(|key| PString::from_str(key)).collect()
+    }
+
+    #[test]
+    fn diff_and_apply() {
+        let lower = tree(&[
+            ("./a", "/"),
+            ("./a/x", "one"),
+            ("./a/sub", "/"),
+            ("./a/sub/y", "two"),
+            ("./b", "three"),
+            ("./c", "/"),
+            ("./c/z", "four"),
+            ("./keep", "five"),
+        ]);
+        let upper = tree(&[
+            ("./a", "/"),
+            ("./a/x", "changed"),
+            // A file turned into a directory, and the other way round.
+            ("./b", "/"),
+            ("./b/new", "six"),
+            ("./c", "seven"),
+            ("./d", "@a/x"),
+            ("./keep", "five"),
+        ]);
+
+        let delta = upper.diff(&lower);
+        assert_eq!(
+            keys(&delta.upper),
+            ["./a/x", "./b", "./b/new", "./c", "./d"]
+        );
+        // Only a/sub is gone with its parent still there; c/z goes when c
+        // is replaced.
+        assert_eq!(delta.whiteouts, set(&["./a/sub"]));
+        assert!(delta.opaque.is_empty());
+
+        let mut applied = lower.clone();
+        applied.apply(&delta);
+        assert_eq!(applied, upper);
+
+        // Nothing changed, nothing to record.
+        let delta = upper.diff(&upper);
+        assert_eq!(delta.upper, FsState::default());
+        assert!(delta.whiteouts.is_empty());
+    }
+
+    #[test]
+    fn opaque_directories() {
+        let lower = tree(&[
+            ("./var", "/"),
+            ("./var/cache", "/"),
+            ("./var/cache/old", "one"),
+            ("./var/cache/sub", "/"),
+            ("./var/cache/sub/deep", "two"),
+            ("./var/log", "three"),
+        ]);
+        let delta = Delta {
+            upper: tree(&[("./var/cache", "/"), ("./var/cache/new", "four")]),
+            whiteouts: set(&["./var/log"]),
+            opaque: set(&["./var/cache"]),
+        };
+
+        let mut applied = lower;
+        applied.apply(&delta);
+        assert_eq!(
+            keys(&applied),
+            ["./var", "./var/cache", "./var/cache/new"]
+        );
+
+        // Without the directory being opaque, its old contents show
+        // through.
+        let mut merged = tree(&[
+            ("./var", "/"),
+            ("./var/cache", "/"),
+            ("./var/cache/old", "one"),
+        ]);
+        merged.apply(&Delta { opaque: BTreeSet::new(), ..delta });
+        assert_eq!(
+            keys(&merged),
+            ["./var", "./var/cache", "./var/cache/new", "./var/cache/old"]
+        );
+    }
+}
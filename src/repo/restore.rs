@@ -0,0 +1,644 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::fs::{self, File, Permissions};
+use std::io;
+use std::os::raw::c_int;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::repo::config::Config;
+use crate::repo::layer::{self, Entry, FsState};
+use crate::repo::object;
+use crate::util::xattr::{self, Kind, Refusal, XattrFilter};
+use crate::util::{self, glob, PString};
+
+/// The result of `restore`.
+#[derive(Debug, Default, Serialize)]
+pub struct RestoreSummary {
+    pub layer: String,
+    pub dirs: u64,
+    pub files: u64,
+    pub links: u64,
+    pub bytes: u64,
+    /// Of those bytes, the ones sharing blocks with the repository's objects.
+    pub bytes_cloned: u64,
+    /// Entries whose owner could not be set, usually for lack of privileges.
+    pub ownership_skipped: u64,
+    /// Entries whose immutable, append-only or nodump flags could not be
+    /// set, for lack of privileges or of support in the target filesystem.
+    pub flags_skipped: u64,
+    /// Extended attributes set, and those the target wouldn't take.
+    pub xattrs: u64,
+    pub xattrs_skipped: Vec<XattrsSkipped>,
+    pub duration_ms: u64,
+}
+
+/// Extended attributes of one kind that couldn't be restored, and why.
+#[derive(Debug, Serialize)]
+pub struct XattrsSkipped {
+    pub kind: String,
+    pub reason: String,
+    pub count: u64,
+}
+
+impl fmt::Display for RestoreSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Restored {} directories, {} files and {} links ({} bytes)",
+            self.dirs, self.files, self.links, self.bytes
+        )?;
+        if self.bytes_cloned != 0 {
+            let cloned = self.bytes_cloned;
+            write!(f, "\nCloned {} bytes from the repository", cloned)?;
+        }
+        if self.ownership_skipped != 0 {
+            write!(
+                f,
+                "\nCould not set owner of {} entries",
+                self.ownership_skipped
+            )?;
+        }
+        if self.flags_skipped != 0 {
+            write!(
+                f,
+                "\nCould not set inode flags of {} entries",
+                self.flags_skipped
+            )?;
+        }
+        for skipped in &self.xattrs_skipped {
+            write!(
+                f,
+                "\nCould not restore {} {} xattrs: {}",
+                skipped.count, skipped.kind, skipped.reason
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Attach the offending path to an error.
+fn at(path: &PString, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", path, e))
+}
+
+/// The parent directory of a key, or `None` for the root.
+fn parent(key: &[u8]) -> Option<&[u8]> {
+    key.iter().rposition(|&c| c == b'/').map(|i| &key[..i])
+}
+
+/// Work out which keys to restore. Every selected directory brings its
+/// whole subtree along, and every selected entry brings its parents.
+fn select<'a>(
+    state: &'a FsState,
+    paths: &[&[u8]],
+) -> Result<BTreeSet<&'a PString>, Box<dyn Error + Send + Sync>> {
+    let mut selected = BTreeSet::new();
+    let mut add = |key: &'a PString, entry: Entry| {
+        selected.insert(key);
+        if let Entry::Dir(_) = entry {
+            for (child, _) in state.children(key, true) {
+                selected.insert(child);
+            }
+        }
+    };
+
+    for path in paths {
+        let key = layer::layer_key(path)?;
+
+        if glob::is_glob(key.as_bytes()) {
+            // Only scan below the part of the pattern that is literal.
+            let base = PString::from_vec(
+                glob::literal_prefix(key.as_bytes()).to_vec(),
+            )?;
+            let mut matched = false;
+            for (child, entry) in state.children(&base, true) {
+                if glob::matches(key.as_bytes(), child.as_bytes()) {
+                    add(child, entry);
+                    matched = true;
+                }
+            }
+            if !matched {
+                return Err(format!("{} did not match anything", key).into());
+            }
+        } else if key.as_bytes() == b"." {
+            for (child, entry) in state.children(&key, false) {
+                add(child, entry);
+            }
+        } else {
+            let (key, entry) = state.get_key_value(&key).ok_or_else(|| {
+                format!("{}: no such file or directory in layer", key)
+            })?;
+            add(key, entry);
+        }
+    }
+
+    // Parents sort before their children, so the set stays in an order we
+    // can create entries in.
+    let mut parents = vec![];
+    for key in &selected {
+        let mut key = key.as_bytes();
+        while let Some(up) = parent(key).filter(|&up| up != b".") {
+            let dir = PString::from_vec(up.to_vec())?;
+            match state.dirs.get_key_value(&dir) {
+                Some((dir, _)) => parents.push(dir),
+                None => break,
+            }
+            key = up;
+        }
+    }
+    selected.extend(parents);
+
+    Ok(selected)
+}
+
+type Xattrs = Option<BTreeMap<CString, Vec<u8>>>;
+
+/// The `-1` of a failed call as an error.
+fn check(ret: c_int) -> io::Result<()> {
+    match ret {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+struct Restorer<'a> {
+    repo_basedir: &'a str,
+    /// The directory restored into, which everything is created beneath.
+    target: File,
+    /// The directory the last entry went into, which its siblings will too.
+    parent: Option<(Vec<u8>, File)>,
+    filter: &'a XattrFilter,
+    /// Clone objects where the filesystem can, rather than copy them.
+    reflink: bool,
+    skipped: BTreeMap<(Kind, Refusal), u64>,
+    summary: RestoreSummary,
+}
+
+impl Restorer<'_> {
+    /// Open the restored directory `key`, never following a symlink or
+    /// leaving the target on the way, whatever the layer has put there.
+    fn open_dir(&self, key: &[u8]) -> io::Result<File> {
+        let path = CString::new(key)?;
+        let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC;
+        let target = self.target.as_raw_fd();
+        let fd = util::open_path_beneath(target, &path, flags)?;
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// The directory an entry goes into, and its name in there.
+    fn place(&mut self, key: &PString) -> io::Result<(RawFd, CString)> {
+        let key = key.as_bytes();
+        let (dir, name) = match parent(key) {
+            Some(dir) => (dir, &key[dir.len() + 1..]),
+            None => (&b"."[..], key),
+        };
+        if name == b".." || name == b"." {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a name an entry can have",
+            ));
+        }
+        if !matches!(&self.parent, Some((open, _)) if open == dir) {
+            self.parent = Some((dir.to_vec(), self.open_dir(dir)?));
+        }
+        let fd = self.parent.as_ref().expect("just opened").1.as_raw_fd();
+        Ok((fd, CString::new(name)?))
+    }
+
+    /// Set the owner of a restored entry, tolerating a lack of privileges.
+    fn chown(
+        &mut self,
+        dirfd: RawFd,
+        name: &CStr,
+        uid: u32,
+        gid: u32,
+        flags: c_int,
+    ) -> io::Result<()> {
+        let ret =
+            unsafe { libc::fchownat(dirfd, name.as_ptr(), uid, gid, flags) };
+        match check(ret) {
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => {
+                self.summary.ownership_skipped += 1;
+                Ok(())
+            }
+            res => res,
+        }
+    }
+
+    /// Set the owner of a restored entry that is open as `file`.
+    fn fchown(&mut self, file: &File, uid: u32, gid: u32) -> io::Result<()> {
+        let empty = CStr::from_bytes_with_nul(b"\0").unwrap();
+        self.chown(file.as_raw_fd(), empty, uid, gid, libc::AT_EMPTY_PATH)
+    }
+
+    /// Set the inode flags of a restored entry, which has to come after
+    /// everything else since an immutable entry can't be changed at all.
+    /// Immutable and append-only take `CAP_LINUX_IMMUTABLE`, so refusals are
+    /// counted rather than failing the restore.
+    fn set_flags(&mut self, fd: RawFd, flags: u32) -> io::Result<()> {
+        if flags == 0 {
+            return Ok(());
+        }
+        match util::set_inode_flags(fd, flags, util::KEPT_ATTRS) {
+            Err(e)
+                if matches!(
+                    e.raw_os_error(),
+                    Some(libc::EPERM)
+                        | Some(libc::EACCES)
+                        | Some(libc::EOPNOTSUPP)
+                        | Some(libc::ENOTTY)
+                ) =>
+            {
+                self.summary.flags_skipped += 1;
+                Ok(())
+            }
+            res => res,
+        }
+    }
+
+    /// Set those extended attributes of an entry that the filter allows and
+    /// `pick` selects, counting the ones the target won't take rather than
+    /// failing on them.
+    fn set_xattrs(
+        &mut self,
+        on: xattr::Target,
+        xattrs: &Xattrs,
+        pick: impl Fn(Kind) -> bool,
+    ) -> io::Result<()> {
+        for (name, value) in xattrs.iter().flatten() {
+            let kind = Kind::of(name.as_bytes());
+            if !pick(kind) || !self.filter.allows(name.as_bytes()) {
+                continue;
+            }
+            match xattr::set(on, name, value) {
+                Ok(()) => self.summary.xattrs += 1,
+                Err(e) => match Refusal::of(&e) {
+                    Some(refusal) => {
+                        *self.skipped.entry((kind, refusal)).or_default() += 1
+                    }
+                    None => {
+                        let name = name.to_string_lossy();
+                        return Err(io::Error::new(
+                            e.kind(),
+                            format!("xattr {}: {}", name, e),
+                        ));
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Set every extended attribute but file capabilities. ACLs go after
+    /// the rest, which could otherwise include ones that override them.
+    fn set_xattrs_before_owner(
+        &mut self,
+        on: xattr::Target,
+        xattrs: &Xattrs,
+    ) -> io::Result<()> {
+        self.set_xattrs(on, xattrs, |k| {
+            k != Kind::Acl && k != Kind::Capability
+        })?;
+        self.set_xattrs(on, xattrs, |k| k == Kind::Acl)
+    }
+
+    fn restore(&mut self, key: &PString, entry: Entry) -> io::Result<()> {
+        let (dirfd, name) = self.place(key)?;
+        match entry {
+            // Directories are created writable, and get their real metadata
+            // once everything inside them has been restored.
+            Entry::Dir(_) => {
+                let ret =
+                    unsafe { libc::mkdirat(dirfd, name.as_ptr(), 0o700) };
+                match check(ret) {
+                    Err(e) if e.kind() != io::ErrorKind::AlreadyExists => {
+                        return Err(e)
+                    }
+                    _ => {}
+                }
+                self.summary.dirs += 1;
+            }
+            Entry::File(obj) => {
+                let mut object = PathBuf::from(self.repo_basedir);
+                object.push("objects");
+                object.push(&obj.hash);
+                let src = File::open(object)?;
+                // `name` is a single component, so `O_NOFOLLOW` is all it
+                // takes to stay in the directory.
+                let flags = libc::O_WRONLY
+                    | libc::O_CREAT
+                    | libc::O_TRUNC
+                    | libc::O_NOFOLLOW
+                    | libc::O_CLOEXEC;
+                let fd = unsafe {
+                    libc::openat(dirfd, name.as_ptr(), flags, 0o600)
+                };
+                check(fd)?;
+                let dst = unsafe { File::from_raw_fd(fd) };
+                let (bytes, cloned) = object::copy(&src, &dst, self.reflink)?;
+                self.summary.bytes += bytes;
+                if cloned {
+                    self.summary.bytes_cloned += bytes;
+                }
+                // Attributes go on while the file is still writable, apart
+                // from capabilities, which changing the owner would clear.
+                let fd = xattr::Target::Fd(dst.as_raw_fd());
+                self.set_xattrs_before_owner(fd, &obj.xattrs)?;
+                dst.set_permissions(Permissions::from_mode(obj.perms))?;
+                self.fchown(&dst, obj.uid, obj.gid)?;
+                self.set_xattrs(fd, &obj.xattrs, |k| k == Kind::Capability)?;
+                self.set_flags(dst.as_raw_fd(), obj.statx.flags)?;
+                self.summary.files += 1;
+            }
+            Entry::Link(link) => {
+                let ret = unsafe { libc::unlinkat(dirfd, name.as_ptr(), 0) };
+                match check(ret) {
+                    Err(e)
+                        if !matches!(
+                            e.raw_os_error(),
+                            Some(libc::ENOENT) | Some(libc::EISDIR)
+                        ) =>
+                    {
+                        return Err(e)
+                    }
+                    _ => {}
+                }
+                let target = CString::new(link.target.as_bytes())?;
+                check(unsafe {
+                    libc::symlinkat(target.as_ptr(), dirfd, name.as_ptr())
+                })?;
+                // Links can't be opened, so their attributes are set by a
+                // path through the directory's descriptor, without
+                // following the link. Without `/proc` there is no such
+                // path, so they are counted as skipped.
+                match util::fd_path(dirfd, &name) {
+                    Some(path) => {
+                        let on = xattr::Target::Link(&path);
+                        self.set_xattrs_before_owner(on, &link.xattrs)?;
+                    }
+                    None => {
+                        for (attr, _) in link.xattrs.iter().flatten() {
+                            let kind = Kind::of(attr.as_bytes());
+                            if self.filter.allows(attr.as_bytes()) {
+                                let refusal = Refusal::NoProc;
+                                *self
+                                    .skipped
+                                    .entry((kind, refusal))
+                                    .or_default() += 1;
+                            }
+                        }
+                    }
+                }
+                let nofollow = libc::AT_SYMLINK_NOFOLLOW;
+                self.chown(dirfd, &name, link.uid, link.gid, nofollow)?;
+                let times = [
+                    libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+                    libc::timespec {
+                        tv_sec: link.mtime,
+                        tv_nsec: link.mtime_nsec as i64,
+                    },
+                ];
+                check(unsafe {
+                    libc::utimensat(
+                        dirfd,
+                        name.as_ptr(),
+                        times.as_ptr(),
+                        nofollow,
+                    )
+                })?;
+                self.summary.links += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Restore a layer, or only the entries matching `paths`, into `target`.
+///
+/// Entries are created relative to the directory they go in, which is
+/// opened without following symlinks, so a layer with a link where a
+/// directory should be can't have anything written outside of `target`.
+pub fn restore(
+    repo_basedir: &str,
+    layer: &str,
+    paths: &[&[u8]],
+    target: &Path,
+    xattrs: &XattrFilter,
+) -> Result<RestoreSummary, Box<dyn Error + Send + Sync>> {
+    let start = Instant::now();
+    let paths = if paths.is_empty() { &[b"." as &[u8]] } else { paths };
+    let hash = layer::resolve(repo_basedir, layer)?;
+
+    // Only load the parts of the layer that could match.
+    let mut keys = vec![];
+    for path in paths {
+        let key = layer::layer_key(path)?;
+        if glob::is_glob(key.as_bytes()) {
+            let base = glob::literal_prefix(key.as_bytes()).to_vec();
+            keys.push(PString::from_vec(base)?);
+        } else {
+            keys.push(key);
+        }
+    }
+    let state = FsState::load_paths(repo_basedir, &hash, &keys, true)?;
+
+    let selected = select(&state, paths)?;
+
+    fs::create_dir_all(target)?;
+    let target = File::open(target)?;
+    let mut restorer = Restorer {
+        repo_basedir,
+        target,
+        parent: None,
+        filter: xattrs,
+        reflink: Config::load(repo_basedir)?.reflink,
+        skipped: BTreeMap::new(),
+        summary: RestoreSummary { layer: hash, ..Default::default() },
+    };
+
+    for key in &selected {
+        let entry = state.get(key).expect("selected keys exist");
+        restorer.restore(key, entry).map_err(|e| at(key, e))?;
+    }
+
+    // Children come after their parents, so going backwards we never
+    // make a directory read-only before we're done with its contents.
+    for key in selected.iter().rev() {
+        if let Some(dir) = state.dirs.get(*key) {
+            restorer.parent = None;
+            let file =
+                restorer.open_dir(key.as_bytes()).map_err(|e| at(key, e))?;
+            let fd = xattr::Target::Fd(file.as_raw_fd());
+            restorer
+                .set_xattrs_before_owner(fd, &dir.xattrs)
+                .map_err(|e| at(key, e))?;
+            file.set_permissions(Permissions::from_mode(dir.perms))
+                .map_err(|e| at(key, e))?;
+            restorer
+                .fchown(&file, dir.uid, dir.gid)
+                .map_err(|e| at(key, e))?;
+            restorer
+                .set_flags(file.as_raw_fd(), dir.statx.flags)
+                .map_err(|e| at(key, e))?;
+        }
+    }
+
+    restorer.summary.xattrs_skipped = restorer
+        .skipped
+        .iter()
+        .map(|(&(kind, refusal), &count)| XattrsSkipped {
+            kind: kind.to_string(),
+            reason: refusal.describe(kind),
+            count,
+        })
+        .collect();
+    restorer.summary.duration_ms = start.elapsed().as_millis() as u64;
+    Ok(restorer.summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::{Progress, ProgressMode};
+    use crate::repo::config;
+    use crate::repo::layer::{DirState, Link, Object};
+
+    fn state(dirs: &[&str], files: &[&str], links: &[&str]) -> FsState {
+        let mut state = FsState::new();
+        for dir in dirs {
+            let meta = DirState {
+                perms: 0o755,
+                uid: 0,
+                gid: 0,
+                xattrs: None,
+                statx: Default::default(),
+            };
+            state.dirs.insert(PString::from_str(dir), meta);
+        }
+        for file in files {
+            let obj = Object {
+                hash: "hash".to_string(),
+                perms: 0o644,
+                uid: 0,
+                gid: 0,
+                xattrs: None,
+                statx: Default::default(),
+                inode: None,
+            };
+            state.objects.insert(PString::from_str(file), obj);
+        }
+        for link in links {
+            let link_to = Link::bare(PString::from_str("target"));
+            state.links.insert(PString::from_str(link), link_to);
+        }
+        state
+    }
+
+    fn selected(state: &FsState, paths: &[&str]) -> Vec<String> {
+        let paths: Vec<&[u8]> = paths.iter().map(|p| p.as_bytes()).collect();
+        let selected = select(state, &paths).unwrap();
+        selected.iter().map(|key| key.to_string()).collect()
+    }
+
+    #[test]
+    fn selection() {
+        let state = state(
+            &["./a", "./a/sub", "./a/sub/deep", "./b"],
+            &["./a/x.c", "./a/y.h", "./a/sub/z.c", "./a/sub/deep/w.c"],
+            &["./b/link"],
+        );
+
+        // A directory brings along everything in it, and anything brings
+        // along its parents.
+        assert_eq!(
+            selected(&state, &["a/sub"]),
+            [
+                "./a",
+                "./a/sub",
+                "./a/sub/deep",
+                "./a/sub/deep/w.c",
+                "./a/sub/z.c",
+            ]
+        );
+        assert_eq!(selected(&state, &["/b/link"]), ["./b", "./b/link"]);
+        assert_eq!(selected(&state, &["."]).len(), 9);
+
+        // A glob's `*` stays in its directory, but `**` doesn't.
+        assert_eq!(selected(&state, &["a/*.c"]), ["./a", "./a/x.c"]);
+        assert_eq!(
+            selected(&state, &["a/**.c"]),
+            [
+                "./a",
+                "./a/sub",
+                "./a/sub/deep",
+                "./a/sub/deep/w.c",
+                "./a/sub/z.c",
+                "./a/x.c",
+            ]
+        );
+        assert_eq!(
+            selected(&state, &["a/*.h", "b"]),
+            ["./a", "./a/y.h", "./b", "./b/link"]
+        );
+
+        let paths: [&[u8]; 1] = [b"a/*.rs"];
+        let err = select(&state, &paths).unwrap_err();
+        assert!(err.to_string().contains("did not match"), "{}", err);
+        let paths: [&[u8]; 1] = [b"a/missing"];
+        let err = select(&state, &paths).unwrap_err();
+        assert!(err.to_string().contains("no such file"), "{}", err);
+    }
+
+    #[test]
+    fn links_dont_lead_out() {
+        let repo = tempfile::tempdir().unwrap();
+        let repo_dir = repo.path().to_str().unwrap();
+        config::init(repo_dir, config::Config::default()).unwrap();
+        let repofd = object::open_store(repo_dir).unwrap();
+        let hash = object::import_stream(&mut &b"data"[..], repofd)
+            .unwrap()
+            .hash;
+        util::close(repofd).unwrap();
+        let progress = Progress::new(ProgressMode::None, 0);
+        let base = layer::store(repo_dir, &FsState::new(), None, &progress)
+            .unwrap()
+            .layer;
+
+        // A delta isn't checked for entries under links, so an old or
+        // crafted one can have `./a/file` with `./a` a link elsewhere.
+        let outside = tempfile::tempdir().unwrap();
+        let mut state = state(&[], &["./a/file"], &[]);
+        for obj in state.objects.values_mut() {
+            obj.hash = hash.clone();
+        }
+        state.links.insert(
+            PString::from_str("./a"),
+            Link::bare(PString::from_str(outside.path().to_str().unwrap())),
+        );
+        let layer = layer::store(repo_dir, &state, Some(&base), &progress)
+            .unwrap()
+            .layer;
+
+        // Both have to be asked for, since `./a` is no directory.
+        let target = tempfile::tempdir().unwrap();
+        let paths: [&[u8]; 2] = [b"a", b"a/file"];
+        let err = restore(
+            repo_dir,
+            &layer,
+            &paths,
+            target.path(),
+            &XattrFilter::NONE,
+        )
+        .unwrap_err();
+        assert!(err.to_string().starts_with("./a/file: "), "{}", err);
+        assert!(fs::read_link(target.path().join("a")).is_ok());
+        assert_eq!(fs::read_dir(outside.path()).unwrap().count(), 0);
+    }
+}
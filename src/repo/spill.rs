@@ -0,0 +1,371 @@
+//! Sorted runs of layer entries, spilled to disk while importing so that
+//! memory use doesn't grow with the size of the tree. Each worker sorts and
+//! writes out what it has found every `RUN_LEN` entries, and the runs are
+//! merged back into a single sorted stream to build the layer's trees from.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::repo::layer::{DirState, Entry, Link, Object};
+use crate::util::PString;
+
+/// Entries a worker collects before spilling them as a run. Tests use far
+/// fewer, so that their small trees still spill and merge in several passes.
+#[cfg(not(test))]
+pub(crate) const RUN_LEN: usize = 1 << 16;
+#[cfg(test)]
+pub(crate) const RUN_LEN: usize = 16;
+/// Runs merged at once. More than this are merged in several passes, to
+/// keep the number of open files down.
+#[cfg(not(test))]
+const FAN_IN: usize = 64;
+#[cfg(test)]
+const FAN_IN: usize = 4;
+
+/// An owned entry of any kind.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum Record {
+    Dir(DirState),
+    File(Object),
+    Link(Link),
+}
+
+impl From<Entry<'_>> for Record {
+    fn from(entry: Entry) -> Record {
+        match entry {
+            Entry::Dir(dir) => Record::Dir(dir.clone()),
+            Entry::File(obj) => Record::File(obj.clone()),
+            Entry::Link(link) => Record::Link(link.clone()),
+        }
+    }
+}
+
+/// Order keys component by component, rather than bytewise, which puts
+/// every directory right before its contents.
+pub(crate) fn tree_order(a: &PString, b: &PString) -> Ordering {
+    let a = a.as_bytes().split(|&c| c == b'/');
+    a.cmp(b.as_bytes().split(|&c| c == b'/'))
+}
+
+/// A directory of runs, removed along with them when dropped.
+pub(crate) struct Spill {
+    dir: PathBuf,
+    next: AtomicU64,
+    runs: Mutex<Vec<PathBuf>>,
+}
+
+impl Spill {
+    /// Make a new spill directory under the repository's `tmp`.
+    pub(crate) fn new(repo_basedir: &str) -> io::Result<Spill> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let mut dir = PathBuf::from(repo_basedir);
+        dir.push("tmp");
+        fs::create_dir_all(&dir)?;
+        dir.push(format!(
+            "import.{}.{}",
+            std::process::id(),
+            NEXT.fetch_add(1, AtomicOrdering::Relaxed)
+        ));
+        fs::create_dir(&dir)?;
+        Ok(Spill { dir, next: AtomicU64::new(0), runs: Mutex::new(vec![]) })
+    }
+
+    fn create_run(&self) -> io::Result<(PathBuf, BufWriter<File>)> {
+        let n = self.next.fetch_add(1, AtomicOrdering::Relaxed);
+        let path = self.dir.join(format!("run.{}", n));
+        let file = BufWriter::new(File::create(&path)?);
+        Ok((path, file))
+    }
+
+    /// Sort `entries` and write them out as a run, leaving `entries` empty.
+    pub(crate) fn spill(
+        &self,
+        entries: &mut Vec<(PString, Record)>,
+    ) -> io::Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        entries.sort_unstable_by(|a, b| tree_order(&a.0, &b.0));
+
+        let (path, mut out) = self.create_run()?;
+        for entry in entries.drain(..) {
+            write_record(&mut out, &entry)?;
+        }
+        finish_run(out)?;
+        self.runs.lock().unwrap().push(path);
+        Ok(())
+    }
+
+    /// Merge every run spilled so far into one sorted stream. Entries with
+    /// the same key come out in the order their runs were spilled.
+    pub(crate) fn merge(&self) -> io::Result<Merge> {
+        let mut runs = std::mem::take(&mut *self.runs.lock().unwrap());
+        // Each pass merges neighbouring runs, which keeps them in order.
+        while runs.len() > FAN_IN {
+            let mut merged = Vec::with_capacity(runs.len().div_ceil(FAN_IN));
+            for group in runs.chunks(FAN_IN) {
+                if let [run] = group {
+                    merged.push(run.clone());
+                    continue;
+                }
+                let (path, mut out) = self.create_run()?;
+                for entry in Merge::open(group)? {
+                    write_record(&mut out, &entry?)?;
+                }
+                finish_run(out)?;
+                for run in group {
+                    fs::remove_file(run)?;
+                }
+                merged.push(path);
+            }
+            runs = merged;
+        }
+        Merge::open(&runs)
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+// Each record in a run is preceded by a 1, and the run ends with a 0, so
+// that a truncated run can't pass for a shorter one.
+
+fn write_record(
+    out: &mut BufWriter<File>,
+    entry: &(PString, Record),
+) -> io::Result<()> {
+    out.write_all(&[1])?;
+    bincode::serialize_into(&mut *out, entry).map_err(io::Error::other)
+}
+
+fn finish_run(mut out: BufWriter<File>) -> io::Result<()> {
+    out.write_all(&[0])?;
+    out.flush()
+}
+
+struct Run {
+    inp: BufReader<File>,
+}
+
+impl Run {
+    fn next(&mut self) -> io::Result<Option<(PString, Record)>> {
+        let mut tag = [0u8];
+        self.inp.read_exact(&mut tag)?;
+        if tag[0] == 0 {
+            return Ok(None);
+        }
+        bincode::deserialize_from(&mut self.inp)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// The head of a run, ordered so that `BinaryHeap` pops the smallest key.
+struct Head {
+    entry: (PString, Record),
+    run: usize,
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Head) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Head) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Head) -> Ordering {
+        tree_order(&other.entry.0, &self.entry.0)
+            .then(other.run.cmp(&self.run))
+    }
+}
+
+/// Entries from several runs, in tree order.
+pub(crate) struct Merge {
+    runs: Vec<Run>,
+    heads: BinaryHeap<Head>,
+}
+
+impl Merge {
+    fn open(paths: &[PathBuf]) -> io::Result<Merge> {
+        let mut merge = Merge { runs: vec![], heads: BinaryHeap::new() };
+        for path in paths {
+            merge.runs.push(Run { inp: BufReader::new(File::open(path)?) });
+            merge.advance(merge.runs.len() - 1)?;
+        }
+        Ok(merge)
+    }
+
+    fn advance(&mut self, run: usize) -> io::Result<()> {
+        if let Some(entry) = self.runs[run].next()? {
+            self.heads.push(Head { entry, run });
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Merge {
+    type Item = io::Result<(PString, Record)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let head = self.heads.pop()?;
+        match self.advance(head.run) {
+            Ok(()) => Some(Ok(head.entry)),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A record that says which run it was spilled in.
+    fn record(run: usize) -> Record {
+        Record::Link(Link::bare(PString::from_str(&run.to_string())))
+    }
+
+    fn spill_runs(spill: &Spill, runs: &[Vec<&str>]) {
+        for (i, keys) in runs.iter().enumerate() {
+            let mut entries: Vec<_> = keys
+                .iter()
+                .map(|key| (PString::from_str(key), record(i)))
+                .collect();
+            spill.spill(&mut entries).unwrap();
+            assert!(entries.is_empty());
+        }
+    }
+
+    /// Every key the merge gives back, with the run it came from.
+    fn merged(spill: &Spill) -> Vec<(String, String)> {
+        spill
+            .merge()
+            .unwrap()
+            .map(|entry| match entry.unwrap() {
+                (key, Record::Link(link)) => {
+                    (key.to_string(), link.target.to_string())
+                }
+                (key, other) => panic!("{}: {:?}", key, other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn several_passes() {
+        let repo = tempfile::tempdir().unwrap();
+        let spill = Spill::new(repo.path().to_str().unwrap()).unwrap();
+        // Enough runs that they are merged down twice before the last
+        // merge.
+        let count = FAN_IN * FAN_IN + 1;
+        let names: Vec<Vec<String>> = (0..count)
+            .map(|run| {
+                (0..3).map(|i| format!("./d{}/f{}", i, run)).collect()
+            })
+            .collect();
+        let runs: Vec<Vec<&str>> = names
+            .iter()
+            .map(|keys| keys.iter().map(|k| k.as_str()).collect())
+            .collect();
+        spill_runs(&spill, &runs);
+
+        let found = merged(&spill);
+        assert_eq!(found.len(), 3 * count);
+        let keys: Vec<PString> =
+            found.iter().map(|(key, _)| PString::from_str(key)).collect();
+        assert!(keys.windows(2).all(|w| tree_order(&w[0], &w[1]).is_lt()));
+        // Only the runs of the last pass are left.
+        let left = fs::read_dir(&spill.dir).unwrap().count();
+        assert!(left <= FAN_IN, "{} runs left", left);
+    }
+
+    #[test]
+    fn duplicate_keys() {
+        let repo = tempfile::tempdir().unwrap();
+        let spill = Spill::new(repo.path().to_str().unwrap()).unwrap();
+        // The same keys in runs that end up in different groups of the
+        // first pass, and at either end of a group.
+        let mut runs = vec![vec!["./other"]; 2 * FAN_IN + 2];
+        for run in [0, FAN_IN - 1, FAN_IN + 1, 2 * FAN_IN + 1] {
+            runs[run] = vec!["./a", "./a/b", "./z"];
+        }
+        spill_runs(&spill, &runs);
+
+        let found = merged(&spill);
+        let of = |key: &str| -> Vec<String> {
+            found
+                .iter()
+                .filter(|(k, _)| k == key)
+                .map(|(_, run)| run.clone())
+                .collect()
+        };
+        let order: Vec<String> = [0, FAN_IN - 1, FAN_IN + 1, 2 * FAN_IN + 1]
+            .iter()
+            .map(|run| run.to_string())
+            .collect();
+        assert_eq!(of("./a"), order);
+        assert_eq!(of("./a/b"), order);
+        assert_eq!(of("./z"), order);
+        assert_eq!(of("./other").len(), 2 * FAN_IN + 2 - 4);
+        assert_eq!(found.len(), 3 * 4 + 2 * FAN_IN + 2 - 4);
+    }
+
+    #[test]
+    fn every_kind_from_every_run() {
+        let repo = tempfile::tempdir().unwrap();
+        let spill = Spill::new(repo.path().to_str().unwrap()).unwrap();
+        let dir = DirState {
+            perms: 0o755,
+            uid: 0,
+            gid: 0,
+            xattrs: None,
+            statx: Default::default(),
+        };
+        let obj = Object {
+            hash: "hash".to_string(),
+            perms: 0o644,
+            uid: 0,
+            gid: 0,
+            xattrs: None,
+            statx: Default::default(),
+            inode: None,
+        };
+        // As each walker spills what it found: some of everything.
+        for run in 0..2 * FAN_IN + 1 {
+            let key =
+                |name: &str| PString::from_str(&format!("./{}{}", name, run));
+            let mut entries = vec![
+                (key("dir"), Record::Dir(dir.clone())),
+                (key("file"), Record::File(obj.clone())),
+                (key("link"), record(run)),
+            ];
+            spill.spill(&mut entries).unwrap();
+        }
+
+        let mut counts = [0; 3];
+        for entry in spill.merge().unwrap() {
+            match entry.unwrap().1 {
+                Record::Dir(_) => counts[0] += 1,
+                Record::File(_) => counts[1] += 1,
+                Record::Link(_) => counts[2] += 1,
+            }
+        }
+        assert_eq!(counts, [2 * FAN_IN + 1; 3]);
+    }
+}
@@ -0,0 +1,611 @@
+//! Layers stored as a Merkle tree: one content-addressed tree object per
+//! directory, listing its entries by name and referring to the trees of its
+//! subdirectories by hash. Subtrees that didn't change are shared between
+//! layers, and can be skipped without reading them when comparing layers.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::repo::layer::{DirState, FsState, Link, Object};
+use crate::repo::overlay::Delta;
+use crate::repo::spill::{tree_order, Record};
+use crate::util::PString;
+
+/// An entry in a tree. A directory's own metadata lives in its parent's
+/// entry for it, so the hash of a tree only depends on what is inside it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Node {
+    Dir { tree: String, meta: DirState },
+    File(Object),
+    Symlink(Link),
+}
+
+/// The entries of one directory, sorted by name.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Tree {
+    pub(crate) entries: Vec<(PString, Node)>,
+}
+
+impl Tree {
+    fn get(&self, name: &[u8]) -> Option<&Node> {
+        self.entries
+            .binary_search_by(|(n, _)| n.as_bytes().cmp(name))
+            .ok()
+            .map(|i| &self.entries[i].1)
+    }
+}
+
+/// How many trees `write` stored, and how many it found already stored.
+#[derive(Debug, Default)]
+pub(crate) struct TreeStats {
+    pub(crate) stored: u64,
+    pub(crate) deduped: u64,
+}
+
+fn trees_dir(repo_basedir: &str) -> PathBuf {
+    let mut path = PathBuf::from(repo_basedir);
+    path.push("trees");
+    path
+}
+
+fn write_tree(
+    repo_basedir: &str,
+    tree: &Tree,
+    stats: &mut TreeStats,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let ser = bincode::serialize(tree)?;
+    let hash = base64::encode_config(
+        blake3::hash(&ser).as_bytes(),
+        base64::URL_SAFE_NO_PAD,
+    );
+
+    let mut path = trees_dir(repo_basedir);
+    path.push(&hash);
+    match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(mut file) => {
+            file.write_all(&ser)?;
+            stats.stored += 1;
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            stats.deduped += 1;
+        }
+        Err(e) => return Err(e.into()),
+    }
+    Ok(hash)
+}
+
+pub(crate) fn read_tree(
+    repo_basedir: &str,
+    hash: &str,
+) -> Result<Tree, Box<dyn Error + Send + Sync>> {
+    let mut path = trees_dir(repo_basedir);
+    path.push(hash);
+    let ser = fs::read(path).map_err(|e| {
+        io::Error::new(e.kind(), format!("tree {}: {}", hash, e))
+    })?;
+    Ok(bincode::deserialize(&ser)?)
+}
+
+/// The last component of a key.
+fn name(key: &[u8]) -> &[u8] {
+    match key.iter().rposition(|&c| c == b'/') {
+        Some(i) => &key[i + 1..],
+        None => key,
+    }
+}
+
+/// Store `state` as trees, returning the hash of the root tree.
+pub(crate) fn write(
+    repo_basedir: &str,
+    state: &FsState,
+    stats: &mut TreeStats,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let mut entries = state.children(&PString::from_str("."), true);
+    entries.sort_unstable_by(|a, b| tree_order(a.0, b.0));
+    let entries = entries
+        .into_iter()
+        .map(|(key, entry)| Ok((key.clone(), Record::from(entry))));
+    write_sorted(repo_basedir, entries, stats)
+}
+
+/// A directory we're inside of while writing trees, and what we've found
+/// in it so far.
+struct OpenDir {
+    key: PString,
+    meta: Option<DirState>,
+    tree: Tree,
+}
+
+/// Store entries given in `tree_order` as trees, returning the hash of the
+/// root tree. Only the trees of the directories on the path to the current
+/// entry are kept in memory.
+pub(crate) fn write_sorted(
+    repo_basedir: &str,
+    entries: impl Iterator<Item = io::Result<(PString, Record)>>,
+    stats: &mut TreeStats,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    fs::create_dir_all(trees_dir(repo_basedir))?;
+
+    let mut open = vec![OpenDir {
+        key: PString::from_str("."),
+        meta: None,
+        tree: Tree::default(),
+    }];
+    let close = |open: &mut Vec<OpenDir>,
+                 stats: &mut TreeStats|
+     -> Result<(), Box<dyn Error + Send + Sync>> {
+        let dir = open.pop().expect("root is never closed");
+        let hash = write_tree(repo_basedir, &dir.tree, stats)?;
+        let name = PString::from_vec(name(dir.key.as_bytes()).to_vec())?;
+        let meta = dir.meta.expect("only the root has no metadata");
+        let parent = open.last_mut().expect("root is never closed");
+        parent.tree.entries.push((name, Node::Dir { tree: hash, meta }));
+        Ok(())
+    };
+
+    for entry in entries {
+        let (key, record) = entry?;
+        loop {
+            let dir = &open.last().expect("root is never closed").key;
+            let inside = key
+                .as_bytes()
+                .strip_prefix(dir.as_bytes())
+                .and_then(|rest| rest.strip_prefix(b"/"))
+                .is_some_and(|rest| !rest.contains(&b'/'));
+            if inside {
+                break;
+            }
+            if open.len() == 1 {
+                return Err(
+                    format!("{}: parent directory is missing", key).into()
+                );
+            }
+            close(&mut open, stats)?;
+        }
+
+        let name = PString::from_vec(name(key.as_bytes()).to_vec())?;
+        let tree = &mut open.last_mut().unwrap().tree;
+        match record {
+            Record::Dir(meta) => open.push(OpenDir {
+                key,
+                meta: Some(meta),
+                tree: Tree::default(),
+            }),
+            Record::File(obj) => tree.entries.push((name, Node::File(obj))),
+            Record::Link(link) => {
+                tree.entries.push((name, Node::Symlink(link)))
+            }
+        }
+    }
+    while open.len() > 1 {
+        close(&mut open, stats)?;
+    }
+
+    let root = open.pop().unwrap();
+    write_tree(repo_basedir, &root.tree, stats)
+}
+
+/// Add a single node to `state`, and everything below it if it is a
+/// directory.
+fn insert_node(
+    repo_basedir: &str,
+    key: PString,
+    node: &Node,
+    state: &mut FsState,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match node {
+        Node::Dir { tree, meta } => {
+            state.dirs.insert(key.clone(), meta.clone());
+            load_into(repo_basedir, tree, &key, state)?;
+        }
+        Node::File(obj) => {
+            state.objects.insert(key, obj.clone());
+        }
+        Node::Symlink(link) => {
+            state.links.insert(key, link.clone());
+        }
+    }
+    Ok(())
+}
+
+/// Add everything in the tree `hash`, which is the directory `dir`.
+fn load_into(
+    repo_basedir: &str,
+    hash: &str,
+    dir: &PString,
+    state: &mut FsState,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let tree = read_tree(repo_basedir, hash)?;
+    for (name, node) in &tree.entries {
+        insert_node(
+            repo_basedir,
+            dir.append_path(name.as_ref()),
+            node,
+            state,
+        )?;
+    }
+    Ok(())
+}
+
+/// Read a whole tree back into an `FsState`.
+pub(crate) fn load(
+    repo_basedir: &str,
+    root: &str,
+) -> Result<FsState, Box<dyn Error + Send + Sync>> {
+    let mut state = FsState::new();
+    load_into(repo_basedir, root, &PString::from_str("."), &mut state)?;
+    Ok(state)
+}
+
+/// Read only the parts of a tree needed to look at `keys`: each key's
+/// parent directories, and the key itself with everything below it, or
+/// unless `recursive` is set, only what is immediately below it. Keys that
+/// don't exist are left out.
+pub(crate) fn load_paths(
+    repo_basedir: &str,
+    root: &str,
+    keys: &[PString],
+    recursive: bool,
+) -> Result<FsState, Box<dyn Error + Send + Sync>> {
+    let mut state = FsState::new();
+    for key in keys {
+        let mut hash = root.to_string();
+        let mut dir = PString::from_str(".");
+        let rest = match key.as_bytes().strip_prefix(b"./") {
+            Some(rest) => rest,
+            None if recursive => return load(repo_basedir, root),
+            None => {
+                load_children(repo_basedir, &hash, &dir, &mut state)?;
+                continue;
+            }
+        };
+
+        let mut components = rest.split(|&c| c == b'/').peekable();
+        while let Some(component) = components.next() {
+            let tree = read_tree(repo_basedir, &hash)?;
+            let node = match tree.get(component) {
+                Some(node) => node,
+                None => break,
+            };
+            let name = PString::from_vec(component.to_vec())?;
+            let key = dir.append_path(name.as_ref());
+            match node {
+                Node::Dir { tree, meta } => {
+                    state.dirs.insert(key.clone(), meta.clone());
+                    hash = tree.clone();
+                    dir = key;
+                }
+                _ => {
+                    if components.peek().is_none() {
+                        insert_node(repo_basedir, key, node, &mut state)?;
+                    }
+                    break;
+                }
+            }
+            if components.peek().is_none() {
+                if recursive {
+                    load_into(repo_basedir, &hash, &dir, &mut state)?;
+                } else {
+                    load_children(repo_basedir, &hash, &dir, &mut state)?;
+                }
+            }
+        }
+    }
+    Ok(state)
+}
+
+/// Trees a `Lookup` keeps before starting over.
+#[cfg(not(test))]
+const LOOKUP_CACHE: usize = 4096;
+#[cfg(test)]
+const LOOKUP_CACHE: usize = 4;
+
+/// Looks up single entries in a tree, from any number of threads, keeping
+/// the trees it reads so that neighbouring entries don't read them again.
+pub(crate) struct Lookup {
+    repo_basedir: String,
+    root: String,
+    /// Trees read so far, by hash.
+    cache: Mutex<HashMap<String, Arc<Tree>>>,
+}
+
+impl Lookup {
+    pub(crate) fn new(repo_basedir: &str, root: &str) -> Lookup {
+        Lookup {
+            repo_basedir: repo_basedir.to_string(),
+            root: root.to_string(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn tree(
+        &self,
+        hash: &str,
+    ) -> Result<Arc<Tree>, Box<dyn Error + Send + Sync>> {
+        if let Some(tree) = self.cache.lock().unwrap().get(hash) {
+            return Ok(tree.clone());
+        }
+        let tree = Arc::new(read_tree(&self.repo_basedir, hash)?);
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= LOOKUP_CACHE {
+            cache.clear();
+        }
+        cache.insert(hash.to_string(), tree.clone());
+        Ok(tree)
+    }
+
+    /// The entry at `key`, if there is one.
+    pub(crate) fn get(
+        &self,
+        key: &PString,
+    ) -> Result<Option<Node>, Box<dyn Error + Send + Sync>> {
+        let rest = match key.as_bytes().strip_prefix(b"./") {
+            Some(rest) => rest,
+            None => return Ok(None),
+        };
+        let mut hash = self.root.clone();
+        let mut components = rest.split(|&c| c == b'/').peekable();
+        while let Some(component) = components.next() {
+            let tree = self.tree(&hash)?;
+            match tree.get(component) {
+                Some(node) if components.peek().is_none() => {
+                    return Ok(Some(node.clone()));
+                }
+                Some(Node::Dir { tree, .. }) => hash = tree.clone(),
+                _ => break,
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Add the entries of the tree `hash`, which is the directory `dir`, but
+/// not what is inside the directories among them.
+fn load_children(
+    repo_basedir: &str,
+    hash: &str,
+    dir: &PString,
+    state: &mut FsState,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let tree = read_tree(repo_basedir, hash)?;
+    for (name, node) in tree.entries {
+        let key = dir.append_path(name.as_ref());
+        match node {
+            Node::Dir { meta, .. } => {
+                state.dirs.insert(key, meta);
+            }
+            Node::File(obj) => {
+                state.objects.insert(key, obj);
+            }
+            Node::Symlink(link) => {
+                state.links.insert(key, link);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Work out the changes between two trees, only reading the subtrees
+/// whose hashes differ.
+pub(crate) fn diff(
+    repo_basedir: &str,
+    lower: &str,
+    upper: &str,
+) -> Result<Delta, Box<dyn Error + Send + Sync>> {
+    let mut delta = Delta::default();
+    diff_into(
+        repo_basedir,
+        lower,
+        upper,
+        &PString::from_str("."),
+        &mut delta,
+    )?;
+    Ok(delta)
+}
+
+fn diff_into(
+    repo_basedir: &str,
+    lower: &str,
+    upper: &str,
+    dir: &PString,
+    delta: &mut Delta,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if lower == upper {
+        return Ok(());
+    }
+    let lower = read_tree(repo_basedir, lower)?;
+    let upper = read_tree(repo_basedir, upper)?;
+
+    let mut lower = lower.entries.iter().peekable();
+    let mut upper = upper.entries.iter().peekable();
+    loop {
+        let order = match (lower.peek(), upper.peek()) {
+            (None, None) => return Ok(()),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (Some(l), Some(u)) => l.0.as_bytes().cmp(u.0.as_bytes()),
+        };
+        match order {
+            std::cmp::Ordering::Less => {
+                let (name, _) = lower.next().unwrap();
+                delta.whiteouts.insert(dir.append_path(name.as_ref()));
+            }
+            std::cmp::Ordering::Greater => {
+                let (name, node) = upper.next().unwrap();
+                let key = dir.append_path(name.as_ref());
+                insert_node(repo_basedir, key, node, &mut delta.upper)?;
+            }
+            std::cmp::Ordering::Equal => {
+                let (name, l) = lower.next().unwrap();
+                let (_, u) = upper.next().unwrap();
+                let key = dir.append_path(name.as_ref());
+                match (l, u) {
+                    (
+                        Node::Dir { tree: ltree, meta: lmeta },
+                        Node::Dir { tree: utree, meta: umeta },
+                    ) => {
+                        if lmeta != umeta {
+                            delta
+                                .upper
+                                .dirs
+                                .insert(key.clone(), umeta.clone());
+                        }
+                        diff_into(repo_basedir, ltree, utree, &key, delta)?;
+                    }
+                    (l, u) if l != u => {
+                        insert_node(repo_basedir, key, u, &mut delta.upper)?;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::layer::{Entry, StatxInfo};
+
+    /// A tree holding `paths`, where those ending in `/` are directories
+    /// and the rest files whose hash is their own path.
+    fn state(paths: &[&str]) -> FsState {
+        let mut state = FsState::new();
+        for path in paths {
+            match path.strip_suffix('/') {
+                Some(dir) => {
+                    let meta = DirState {
+                        perms: 0o755,
+                        uid: 0,
+                        gid: 0,
+                        xattrs: None,
+                        statx: StatxInfo::default(),
+                    };
+                    state.dirs.insert(PString::from_str(dir), meta);
+                }
+                None => {
+                    let obj = Object {
+                        hash: path.to_string(),
+                        perms: 0o644,
+                        uid: 0,
+                        gid: 0,
+                        xattrs: None,
+                        statx: StatxInfo::default(),
+                        inode: None,
+                    };
+                    state.objects.insert(PString::from_str(path), obj);
+                }
+            }
+        }
+        state
+    }
+
+    /// The hash of the tree for `dir`, a child of the root.
+    fn subtree(repo: &str, root: &str, dir: &str) -> String {
+        match read_tree(repo, root).unwrap().get(dir.as_bytes()) {
+            Some(Node::Dir { tree, .. }) => tree.clone(),
+            other => panic!("{} is {:?}", dir, other),
+        }
+    }
+
+    #[test]
+    fn diff_skips_unchanged_subtrees() {
+        let repo = tempfile::tempdir().unwrap();
+        let repo = repo.path().to_str().unwrap();
+        let mut stats = TreeStats::default();
+        let same = ["./same/", "./same/deep/", "./same/deep/file"];
+        let lower = [&same[..], &["./old/", "./old/a", "./b"]].concat();
+        let upper = [&same[..], &["./old/", "./new", "./b/"]].concat();
+        let (lower, upper) = (state(&lower), state(&upper));
+        let lower_root = write(repo, &lower, &mut stats).unwrap();
+        let upper_root = write(repo, &upper, &mut stats).unwrap();
+
+        // Both layers share the unchanged subtree, so it can go without
+        // the diff noticing: it never reads it.
+        let shared = subtree(repo, &lower_root, "same");
+        assert_eq!(shared, subtree(repo, &upper_root, "same"));
+        let deep = read_tree(repo, &shared).unwrap();
+        let deep = match deep.get(b"deep") {
+            Some(Node::Dir { tree, .. }) => tree.clone(),
+            other => panic!("deep is {:?}", other),
+        };
+        for hash in [&shared, &deep] {
+            fs::remove_file(trees_dir(repo).join(hash)).unwrap();
+        }
+
+        let delta = diff(repo, &lower_root, &upper_root).unwrap();
+        let want = upper.diff(&lower);
+        assert_eq!(delta.upper, want.upper);
+        assert_eq!(delta.whiteouts, want.whiteouts);
+        // Loading either in full does need it, though.
+        assert!(load(repo, &upper_root).is_err());
+    }
+
+    #[test]
+    fn lookups() {
+        let repo = tempfile::tempdir().unwrap();
+        let repo = repo.path().to_str().unwrap();
+        // More directories than the cache holds, so it starts over while
+        // we look.
+        let mut paths = vec![];
+        for dir in ["a", "b", "c", "d", "e", "f"] {
+            paths.push(format!("./{}/", dir));
+            paths.push(format!("./{}/sub/", dir));
+            paths.push(format!("./{}/sub/file", dir));
+            paths.push(format!("./{}/file", dir));
+        }
+        let paths: Vec<&str> = paths.iter().map(|p| p.as_str()).collect();
+        let state = state(&paths);
+        let root = write(repo, &state, &mut TreeStats::default()).unwrap();
+
+        let lookup = Lookup::new(repo, &root);
+        let check = |key: &str| {
+            let key = PString::from_str(key);
+            let found = lookup.get(&key).unwrap();
+            match (state.get(&key), found) {
+                (None, None) => {}
+                (Some(Entry::Dir(want)), Some(Node::Dir { meta, .. })) => {
+                    assert_eq!(&meta, want)
+                }
+                (Some(Entry::File(want)), Some(Node::File(obj))) => {
+                    assert_eq!(&obj, want)
+                }
+                (want, found) => panic!("{}: {:?} {:?}", key, want, found),
+            }
+        };
+        // From several threads at once, and in an order that keeps going
+        // back to trees the cache has let go of.
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..3 {
+                        for path in paths.iter().rev() {
+                            check(path.trim_end_matches('/'));
+                        }
+                    }
+                });
+            }
+        });
+        assert!(lookup.cache.lock().unwrap().len() <= LOOKUP_CACHE);
+        for missing in [".", "./z", "./a/z", "./a/file/z", "./a/sub/z"] {
+            check(missing);
+        }
+
+        // What is cached is used: with every tree gone from disk, lookups
+        // along the path just taken still work.
+        let lookup = Lookup::new(repo, &root);
+        let file = PString::from_str("./f/sub/file");
+        let found = lookup.get(&file).unwrap();
+        fs::remove_dir_all(trees_dir(repo)).unwrap();
+        assert_eq!(lookup.get(&file).unwrap(), found);
+        let near = PString::from_str("./f/file");
+        assert!(lookup.get(&near).unwrap().is_some());
+        assert!(lookup.get(&PString::from_str("./a/file")).is_err());
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::ffi::CString;
+use std::io::{self, Read};
+use std::os::unix::prelude::RawFd;
+use std::path::Path;
+
+use crate::progress::Progress;
+use crate::repo::layer::{
+    self, DirState, FsState, ImportSummary, Link, Object, StatxInfo,
+};
+use crate::repo::object;
+use crate::repo::overlay::Delta;
+use crate::util::tar::{self, Kind};
+use crate::util::xattr::XattrFilter;
+use crate::util::{self, PString};
+
+/// Turn an archive path into a layer key, refusing anything that would
+/// escape the root.
+fn member_key(path: &[u8]) -> io::Result<PString> {
+    let mut key = b".".to_vec();
+    for component in path.split(|&c| c == b'/') {
+        match component {
+            b"" | b"." => {}
+            b".." => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "path escapes the archive root",
+                ))
+            }
+            component => {
+                key.push(b'/');
+                key.extend_from_slice(component);
+            }
+        }
+    }
+    PString::from_vec(key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// The name of an entry that hides another entry in lower layers.
+const WHITEOUT: &[u8] = b".wh.";
+/// Names with this prefix are reserved for whiteout bookkeeping.
+const WHITEOUT_META: &[u8] = b".wh..wh.";
+/// The name of an entry that hides everything in lower layers below the
+/// directory it is in.
+const WHITEOUT_OPAQUE: &[u8] = b".wh..wh..opq";
+
+/// Split a key into its parent directory and its name.
+fn split_key(key: &PString) -> (&[u8], &[u8]) {
+    let key = key.as_bytes();
+    let i = key.iter().rposition(|&c| c == b'/').unwrap_or(0);
+    (&key[..i], &key[i + 1..])
+}
+
+fn perms(header: &tar::Header) -> u32 {
+    header.mode & (libc::S_IRWXU | libc::S_IRWXG | libc::S_IRWXO)
+}
+
+fn xattrs(
+    header: &tar::Header,
+    filter: &XattrFilter,
+) -> io::Result<Option<BTreeMap<CString, Vec<u8>>>> {
+    let mut xattrs = BTreeMap::new();
+    for (name, value) in &header.xattrs {
+        if !filter.allows(name) {
+            continue;
+        }
+        let name = CString::new(name.clone())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        xattrs.insert(name, value.clone());
+    }
+    Ok(if xattrs.is_empty() { None } else { Some(xattrs) })
+}
+
+/// Make way for the member at `key`. Like extracting the archive would, a
+/// later member replaces an earlier one at the same path, and everything
+/// under it, except that a directory over a directory merges with it.
+fn make_way(state: &mut FsState, key: &PString, dir: bool) {
+    if !(dir && state.dirs.contains_key(key)) {
+        state.remove_tree(key);
+    }
+}
+
+/// Read every member of a tar archive, storing file contents in the object
+/// store as they stream past. With `whiteouts`, `.wh.` entries are taken to
+/// hide entries in lower layers, as in an OCI image layer. Only the
+/// extended attributes `xattrs` allows are kept.
+pub(crate) fn read_archive(
+    inp: &mut dyn Read,
+    objectfd: RawFd,
+    progress: &Progress,
+    whiteouts: bool,
+    xattrs: &XattrFilter,
+) -> Result<Delta, Box<dyn Error + Send + Sync>> {
+    let mut reader = tar::Reader::new(inp);
+    let mut delta = Delta::default();
+    let state = &mut delta.upper;
+    let mut inodes = 0;
+
+    while let Some(header) = reader.next()? {
+        let key = member_key(&header.path)?;
+        let name = key.to_string();
+        let at = |e: io::Error| {
+            io::Error::new(e.kind(), format!("{}: {}", name, e))
+        };
+
+        if whiteouts {
+            let (dir, base) = split_key(&key);
+            if base == WHITEOUT_OPAQUE {
+                delta.opaque.insert(PString::from_vec(dir.to_vec())?);
+                continue;
+            } else if base.starts_with(WHITEOUT_META) {
+                // Other aufs bookkeeping, which has no meaning here.
+                continue;
+            } else if let Some(hidden) = base.strip_prefix(WHITEOUT) {
+                let mut hidden_key = dir.to_vec();
+                hidden_key.push(b'/');
+                hidden_key.extend_from_slice(hidden);
+                delta.whiteouts.insert(PString::from_vec(hidden_key)?);
+                continue;
+            }
+        }
+
+        match header.kind {
+            // The root itself isn't part of a layer.
+            Kind::Dir if key.as_bytes() == b"." => {}
+            Kind::Dir => {
+                Progress::add(&progress.dirs, 1);
+                if progress.logging(2) {
+                    progress.log(format_args!("D {}", key));
+                }
+                make_way(state, &key, true);
+                state.dirs.insert(
+                    key,
+                    DirState {
+                        perms: perms(&header),
+                        uid: header.uid,
+                        gid: header.gid,
+                        xattrs: self::xattrs(&header, xattrs).map_err(at)?,
+                        statx: StatxInfo::default(),
+                    },
+                );
+            }
+            Kind::File => {
+                let imported = object::import_stream(&mut reader, objectfd)
+                    .map_err(at)?;
+                Progress::add(&progress.files, 1);
+                Progress::add(&progress.bytes_hashed, imported.size);
+                if imported.stored {
+                    Progress::add(&progress.bytes_stored, imported.size);
+                } else {
+                    Progress::add(&progress.bytes_deduped, imported.size);
+                }
+                if progress.logging(1) {
+                    let status = if imported.stored { "A" } else { "=" };
+                    progress.log(format_args!("{} {}", status, key));
+                }
+                make_way(state, &key, false);
+                state.objects.insert(
+                    key,
+                    Object {
+                        hash: imported.hash,
+                        perms: perms(&header),
+                        uid: header.uid,
+                        gid: header.gid,
+                        xattrs: self::xattrs(&header, xattrs).map_err(at)?,
+                        statx: StatxInfo::default(),
+                        inode: None,
+                    },
+                );
+            }
+            // A hard link shares its inode, and so all of its metadata,
+            // with a file earlier in the archive.
+            Kind::Hardlink => {
+                let target = member_key(&header.link).map_err(at)?;
+                let object =
+                    state.objects.get_mut(&target).ok_or_else(|| {
+                        at(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("hard link to unknown file {}", target),
+                        ))
+                    })?;
+                // Archives have no inode numbers, so number them ourselves
+                // on a device that can't exist.
+                if object.inode.is_none() {
+                    inodes += 1;
+                    object.inode = Some((0, inodes));
+                }
+                let object = object.clone();
+                Progress::add(&progress.files, 1);
+                if progress.logging(1) {
+                    progress.log(format_args!("= {}", key));
+                }
+                make_way(state, &key, false);
+                state.objects.insert(key, object);
+            }
+            Kind::Symlink => {
+                let target =
+                    PString::from_vec(header.link.clone()).map_err(|e| {
+                        at(io::Error::new(io::ErrorKind::InvalidData, e))
+                    })?;
+                Progress::add(&progress.links, 1);
+                if progress.logging(2) {
+                    progress.log(format_args!("L {} -> {}", key, target));
+                }
+                let link = Link {
+                    target,
+                    uid: header.uid,
+                    gid: header.gid,
+                    mtime: header.mtime as i64,
+                    mtime_nsec: 0,
+                    xattrs: self::xattrs(&header, xattrs).map_err(at)?,
+                    statx: StatxInfo::default(),
+                };
+                make_way(state, &key, false);
+                state.links.insert(key, link);
+            }
+            // Layers can't hold devices, fifos or sockets. An import from
+            // disk fails on them, but archives of whole systems are full of
+            // device nodes, so here they are only left out and counted.
+            Kind::Other(_) => {
+                make_way(state, &key, false);
+                Progress::add(&progress.errors, 1);
+                if progress.logging(1) {
+                    progress.log(format_args!(
+                        "error: {}: unsupported file type",
+                        key
+                    ));
+                }
+            }
+        }
+    }
+
+    Ok(delta)
+}
+
+/// Import a tar archive, read from `path` or from stdin if it is `-`.
+///
+/// Unlike a directory, the tree is built in memory rather than spilled in
+/// sorted runs, since any member may replace an earlier one.
+pub fn import(
+    path: &Path,
+    repo_basedir: &str,
+    options: &layer::ImportOptions,
+) -> Result<ImportSummary, Box<dyn Error + Send + Sync>> {
+    let parent = options
+        .delta_from
+        .as_deref()
+        .map(|parent| layer::resolve(repo_basedir, parent))
+        .transpose()?;
+    let inp: Box<dyn Read> = if path.as_os_str() == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(std::fs::File::open(path)?)
+    };
+    let mut inp = io::BufReader::new(inp);
+
+    let objectfd = object::open_store(repo_basedir)?;
+    let progress = Progress::new(options.progress, options.verbose);
+    let delta = progress.report_while(|| {
+        read_archive(&mut inp, objectfd, &progress, false, &options.xattrs)
+    });
+    util::close(objectfd)?;
+
+    let mut state = delta?.upper;
+    state.fill_parents(&progress);
+    state.check_parents()?;
+    layer::store(repo_basedir, &state, parent.as_deref(), &progress)
+}
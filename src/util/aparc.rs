@@ -69,7 +69,7 @@ impl<T> APArc<T> {
 
     pub fn load(&self) -> Option<Arc<T>> {
         let ptr = self.ptr.load(Ordering::Relaxed);
-        if ptr == null_mut::<T>() {
+        if ptr.is_null() {
             None
         } else {
             let n = unsafe { Arc::from_raw(ptr) };
@@ -86,7 +86,7 @@ impl<T> APArc<T> {
 
     pub fn clear(&self) {
         unsafe {
-            Arc::<T>::from_raw(self.ptr.load(Ordering::Acquire) as *mut T)
+            Arc::<T>::from_raw(self.ptr.load(Ordering::Acquire))
         };
         self.ptr.store(null_mut::<T>(), Ordering::Release);
     }
@@ -127,7 +127,7 @@ impl<T> Default for APArc<T> {
 impl<T> Drop for APArc<T> {
     fn drop(&mut self) {
         let ptr = self.ptr.load(Ordering::Relaxed);
-        if ptr != null_mut::<T>() {
+        if !ptr.is_null() {
             unsafe {
                 Arc::from_raw(ptr);
             }
@@ -0,0 +1,707 @@
+//! A minimal speaker of the FUSE kernel protocol over `/dev/fuse`.
+//!
+//! This only covers what a read-only filesystem needs; see
+//! `include/uapi/linux/fuse.h` for the full protocol.
+
+use std::ffi::{CStr, CString, OsStr};
+use std::io;
+use std::mem::size_of;
+use std::os::unix::prelude::RawFd;
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::{close, open};
+
+/// Set once we've been asked to stop serving requests.
+static STOP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_: libc::c_int) {
+    STOP.store(true, Ordering::Relaxed);
+}
+
+const STOP_SIGNALS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+
+/// Make SIGINT and SIGTERM end `Session::next` rather than kill us, so the
+/// filesystem gets unmounted on the way out.
+///
+/// They stay blocked on this thread except while `Session::next` waits for
+/// a request, so that one can't arrive after we last checked `STOP` and
+/// leave us waiting for good.
+pub(crate) fn stop_on_signals() -> io::Result<()> {
+    for signal in STOP_SIGNALS {
+        // No SA_RESTART, so that a blocked wait returns EINTR.
+        let ret = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_signal as *const () as usize;
+            libc::sigaction(signal, &action, std::ptr::null_mut())
+        };
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    let ret = unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for signal in STOP_SIGNALS {
+            libc::sigaddset(&mut set, signal);
+        }
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut())
+    };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    Ok(())
+}
+
+/// Wait for `fd` to become readable, with the signals `stop_on_signals`
+/// blocked let through for just that long.
+fn wait_readable(fd: RawFd) -> io::Result<()> {
+    // SAFETY: `sigset_t` is plain data, and is filled in before use.
+    let mut mask: libc::sigset_t = unsafe { std::mem::zeroed() };
+    let ret = unsafe {
+        libc::pthread_sigmask(libc::SIG_BLOCK, std::ptr::null(), &mut mask)
+    };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    for signal in STOP_SIGNALS {
+        unsafe { libc::sigdelset(&mut mask, signal) };
+    }
+
+    let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    let ret = unsafe { libc::ppoll(&mut pollfd, 1, std::ptr::null(), &mask) };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub(crate) const ROOT_ID: u64 = 1;
+
+pub(crate) const KERNEL_VERSION: u32 = 7;
+pub(crate) const KERNEL_MINOR_VERSION: u32 = 31;
+
+/// Largest request we ask the kernel to send us, not counting headers.
+pub(crate) const MAX_READ: u32 = 128 * 1024;
+
+pub(crate) const FUSE_LOOKUP: u32 = 1;
+pub(crate) const FUSE_FORGET: u32 = 2;
+pub(crate) const FUSE_GETATTR: u32 = 3;
+pub(crate) const FUSE_READLINK: u32 = 5;
+pub(crate) const FUSE_OPEN: u32 = 14;
+pub(crate) const FUSE_READ: u32 = 15;
+pub(crate) const FUSE_STATFS: u32 = 17;
+pub(crate) const FUSE_RELEASE: u32 = 18;
+pub(crate) const FUSE_GETXATTR: u32 = 22;
+pub(crate) const FUSE_LISTXATTR: u32 = 23;
+pub(crate) const FUSE_FLUSH: u32 = 25;
+pub(crate) const FUSE_INIT: u32 = 26;
+pub(crate) const FUSE_OPENDIR: u32 = 27;
+pub(crate) const FUSE_READDIR: u32 = 28;
+pub(crate) const FUSE_RELEASEDIR: u32 = 29;
+pub(crate) const FUSE_INTERRUPT: u32 = 36;
+pub(crate) const FUSE_DESTROY: u32 = 38;
+pub(crate) const FUSE_BATCH_FORGET: u32 = 42;
+
+/// `FUSE_INIT` flags we may accept from the kernel.
+pub(crate) const FUSE_ASYNC_READ: u32 = 1 << 0;
+pub(crate) const FUSE_MAX_PAGES: u32 = 1 << 22;
+
+/// Reply flag for `FUSE_OPEN`: keep the page cache between opens, since
+/// the contents of a layer can never change.
+pub(crate) const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct InHeader {
+    pub len: u32,
+    pub opcode: u32,
+    pub unique: u64,
+    pub nodeid: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct OutHeader {
+    pub len: u32,
+    pub error: i32,
+    pub unique: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct InitIn {
+    pub major: u32,
+    pub minor: u32,
+    pub max_readahead: u32,
+    pub flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct InitOut {
+    pub major: u32,
+    pub minor: u32,
+    pub max_readahead: u32,
+    pub flags: u32,
+    pub max_background: u16,
+    pub congestion_threshold: u16,
+    pub max_write: u32,
+    pub time_gran: u32,
+    pub max_pages: u16,
+    pub map_alignment: u16,
+    pub flags2: u32,
+    pub unused: [u32; 7],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Attr {
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+    pub atimensec: u32,
+    pub mtimensec: u32,
+    pub ctimensec: u32,
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u32,
+    pub blksize: u32,
+    pub flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct EntryOut {
+    pub nodeid: u64,
+    pub generation: u64,
+    pub entry_valid: u64,
+    pub attr_valid: u64,
+    pub entry_valid_nsec: u32,
+    pub attr_valid_nsec: u32,
+    pub attr: Attr,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct AttrOut {
+    pub attr_valid: u64,
+    pub attr_valid_nsec: u32,
+    pub dummy: u32,
+    pub attr: Attr,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct OpenIn {
+    pub flags: u32,
+    pub open_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct OpenOut {
+    pub fh: u64,
+    pub open_flags: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ReadIn {
+    pub fh: u64,
+    pub offset: u64,
+    pub size: u32,
+    pub read_flags: u32,
+    pub lock_owner: u64,
+    pub flags: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ReleaseIn {
+    pub fh: u64,
+    pub flags: u32,
+    pub release_flags: u32,
+    pub lock_owner: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct GetxattrIn {
+    pub size: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct GetxattrOut {
+    pub size: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct StatfsOut {
+    pub blocks: u64,
+    pub bfree: u64,
+    pub bavail: u64,
+    pub files: u64,
+    pub ffree: u64,
+    pub bsize: u32,
+    pub namelen: u32,
+    pub frsize: u32,
+    pub padding: u32,
+    pub spare: [u32; 6],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct Dirent {
+    ino: u64,
+    off: u64,
+    namelen: u32,
+    typ: u32,
+}
+
+/// Marker for the plain-old-data structs above, which can be viewed as and
+/// read from raw bytes.
+///
+/// # Safety
+/// Implementors must be `repr(C)`, without padding, and valid for any bit
+/// pattern.
+pub(crate) unsafe trait Pod: Copy {
+    fn as_bytes(&self) -> &[u8] {
+        // SAFETY: guaranteed by the implementor
+        unsafe {
+            std::slice::from_raw_parts(
+                (self as *const Self).cast::<u8>(),
+                size_of::<Self>(),
+            )
+        }
+    }
+}
+
+unsafe impl Pod for InHeader {}
+unsafe impl Pod for OutHeader {}
+unsafe impl Pod for InitIn {}
+unsafe impl Pod for InitOut {}
+unsafe impl Pod for Attr {}
+unsafe impl Pod for EntryOut {}
+unsafe impl Pod for AttrOut {}
+unsafe impl Pod for OpenIn {}
+unsafe impl Pod for OpenOut {}
+unsafe impl Pod for ReadIn {}
+unsafe impl Pod for ReleaseIn {}
+unsafe impl Pod for GetxattrIn {}
+unsafe impl Pod for GetxattrOut {}
+unsafe impl Pod for StatfsOut {}
+unsafe impl Pod for Dirent {}
+
+/// A single request read from the kernel.
+pub(crate) struct Request<'a> {
+    pub header: InHeader,
+    /// Everything after the header.
+    pub body: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    /// Read a `T` from the start of the body.
+    pub fn arg<T: Pod>(&self) -> io::Result<T> {
+        if self.body.len() < size_of::<T>() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        // SAFETY: checked the length, and `T` is valid for any bytes
+        Ok(unsafe { std::ptr::read_unaligned(self.body.as_ptr().cast()) })
+    }
+
+    /// The nul-terminated name following a `T` in the body.
+    pub fn name_after<T: Pod>(&self) -> io::Result<&'a [u8]> {
+        let rest = self.body.get(size_of::<T>()..).unwrap_or(&[]);
+        match rest.iter().position(|&c| c == 0) {
+            Some(end) => Ok(&rest[..end]),
+            None => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+
+    /// The nul-terminated name that makes up the whole body.
+    pub fn name(&self) -> io::Result<&'a [u8]> {
+        match self.body.iter().position(|&c| c == 0) {
+            Some(end) => Ok(&self.body[..end]),
+            None => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+}
+
+/// Append a directory entry to a `FUSE_READDIR` reply, returning false if
+/// it would not fit in `max` bytes.
+pub(crate) fn push_dirent(
+    buf: &mut Vec<u8>,
+    max: usize,
+    ino: u64,
+    off: u64,
+    typ: u8,
+    name: &[u8],
+) -> bool {
+    let len = size_of::<Dirent>() + name.len();
+    let padded = (len + 7) & !7;
+    if buf.len() + padded > max {
+        return false;
+    }
+    let dirent =
+        Dirent { ino, off, namelen: name.len() as u32, typ: typ as u32 };
+    buf.extend_from_slice(dirent.as_bytes());
+    buf.extend_from_slice(name);
+    buf.resize(buf.len() + padded - len, 0);
+    true
+}
+
+/// An open connection to the kernel for a single mount.
+pub(crate) struct Session {
+    fd: RawFd,
+    mountpoint: CString,
+    /// The `fusermount` that mounted us, and so has to unmount us too.
+    fusermount: Option<&'static str>,
+}
+
+/// Setuid helpers that mount FUSE filesystems for unprivileged users, in
+/// the order we try them.
+const FUSERMOUNT: [&str; 2] = ["fusermount3", "fusermount"];
+
+impl Session {
+    /// Mount a read-only FUSE filesystem at `mountpoint`. This calls
+    /// mount(2) directly if we may, and otherwise asks `fusermount` to do
+    /// it for us.
+    pub fn mount(
+        mountpoint: &Path,
+        fsname: &str,
+        allow_other: bool,
+    ) -> io::Result<Session> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = CString::new(mountpoint.as_os_str().as_bytes())?;
+        let err = match mount_direct(&path, fsname, allow_other) {
+            Ok(fd) => {
+                return Ok(Session { fd, mountpoint: path, fusermount: None })
+            }
+            // Without privileges we may not even open `/dev/fuse`, which
+            // fusermount does for us too.
+            Err(err)
+                if matches!(
+                    err.raw_os_error(),
+                    Some(libc::EPERM) | Some(libc::EACCES)
+                ) =>
+            {
+                err
+            }
+            Err(err) => return Err(err),
+        };
+
+        for program in FUSERMOUNT {
+            let fd =
+                mount_fusermount(program, mountpoint, fsname, allow_other)?;
+            if let Some(fd) = fd {
+                return Ok(Session {
+                    fd,
+                    mountpoint: path,
+                    fusermount: Some(program),
+                });
+            }
+        }
+        Err(io::Error::new(
+            err.kind(),
+            format!("{} (and no fusermount to fall back on)", err),
+        ))
+    }
+
+    /// Read the next request into `buf`, returning `None` once the
+    /// filesystem has been unmounted.
+    pub fn next<'a>(
+        &self,
+        buf: &'a mut [u8],
+    ) -> io::Result<Option<Request<'a>>> {
+        loop {
+            if STOP.load(Ordering::Relaxed) {
+                return Ok(None);
+            }
+            if let Err(err) = wait_readable(self.fd) {
+                match err.raw_os_error() {
+                    Some(libc::EINTR) => continue,
+                    _ => return Err(err),
+                }
+            }
+
+            let ret = unsafe {
+                libc::read(self.fd, buf.as_mut_ptr().cast(), buf.len())
+            };
+            if ret == -1 {
+                let err = io::Error::last_os_error();
+                match err.raw_os_error() {
+                    // The request was interrupted before we read it.
+                    Some(libc::ENOENT) | Some(libc::EINTR)
+                    | Some(libc::EAGAIN) => continue,
+                    Some(libc::ENODEV) => return Ok(None),
+                    _ => return Err(err),
+                }
+            }
+
+            let buf = &buf[..ret as usize];
+            if buf.len() < size_of::<InHeader>() {
+                return Err(io::Error::from_raw_os_error(libc::EIO));
+            }
+            // SAFETY: checked the length above
+            let header: InHeader =
+                unsafe { std::ptr::read_unaligned(buf.as_ptr().cast()) };
+            return Ok(Some(Request {
+                header,
+                body: &buf[size_of::<InHeader>()..],
+            }));
+        }
+    }
+
+    /// Send a successful reply made up of `parts`.
+    pub fn reply(&self, unique: u64, parts: &[&[u8]]) -> io::Result<()> {
+        let len = size_of::<OutHeader>()
+            + parts.iter().map(|p| p.len()).sum::<usize>();
+        let header = OutHeader { len: len as u32, error: 0, unique };
+
+        let mut iov = Vec::with_capacity(parts.len() + 1);
+        for part in std::iter::once(&header.as_bytes()).chain(parts) {
+            iov.push(libc::iovec {
+                iov_base: part.as_ptr() as *mut libc::c_void,
+                iov_len: part.len(),
+            });
+        }
+        self.send(&iov)
+    }
+
+    /// Send an error reply.
+    pub fn reply_err(&self, unique: u64, errno: i32) -> io::Result<()> {
+        let header = OutHeader {
+            len: size_of::<OutHeader>() as u32,
+            error: -errno,
+            unique,
+        };
+        let bytes = header.as_bytes();
+        self.send(&[libc::iovec {
+            iov_base: bytes.as_ptr() as *mut libc::c_void,
+            iov_len: bytes.len(),
+        }])
+    }
+
+    fn send(&self, iov: &[libc::iovec]) -> io::Result<()> {
+        let ret =
+            unsafe { libc::writev(self.fd, iov.as_ptr(), iov.len() as i32) };
+        if ret == -1 {
+            let err = io::Error::last_os_error();
+            // The request was interrupted and is no longer wanted.
+            if err.raw_os_error() == Some(libc::ENOENT) {
+                return Ok(());
+            }
+            return Err(err);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        use std::os::unix::ffi::OsStrExt;
+
+        // This fails harmlessly if it was already unmounted from outside.
+        match self.fusermount {
+            None => unsafe {
+                libc::umount2(self.mountpoint.as_ptr(), libc::MNT_DETACH);
+            },
+            Some(program) => {
+                let mountpoint =
+                    OsStr::from_bytes(self.mountpoint.as_bytes());
+                let _ = Command::new(program)
+                    .args(["-u", "-z", "-q", "--"])
+                    .arg(mountpoint)
+                    .status();
+            }
+        }
+        let _ = close(self.fd);
+    }
+}
+
+/// Mount with mount(2), which needs `CAP_SYS_ADMIN`, returning the fd for
+/// `/dev/fuse`.
+fn mount_direct(
+    mountpoint: &CStr,
+    fsname: &str,
+    allow_other: bool,
+) -> io::Result<RawFd> {
+    let fd = open(
+        &CString::new("/dev/fuse").unwrap(),
+        libc::O_RDWR | libc::O_CLOEXEC,
+    )?;
+
+    let mut options = format!(
+        "fd={},rootmode=40000,user_id={},group_id={},default_permissions",
+        fd,
+        unsafe { libc::getuid() },
+        unsafe { libc::getgid() },
+    );
+    if allow_other {
+        options.push_str(",allow_other");
+    }
+    let options = CString::new(options).unwrap();
+    let fsname = CString::new(fsname)?;
+    let fstype = CString::new("fuse.banyan").unwrap();
+
+    let ret = unsafe {
+        libc::mount(
+            fsname.as_ptr(),
+            mountpoint.as_ptr(),
+            fstype.as_ptr(),
+            libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV,
+            options.as_ptr().cast(),
+        )
+    };
+    if ret == -1 {
+        let err = io::Error::last_os_error();
+        let _ = close(fd);
+        return Err(err);
+    }
+    Ok(fd)
+}
+
+/// Have `program` mount us, returning the fd for `/dev/fuse` it passes
+/// back over the socket named in `_FUSE_COMMFD`, or `None` if we can't run
+/// it at all.
+fn mount_fusermount(
+    program: &str,
+    mountpoint: &Path,
+    fsname: &str,
+    allow_other: bool,
+) -> io::Result<Option<RawFd>> {
+    use std::os::unix::process::CommandExt;
+
+    let mut fds = [-1; 2];
+    let ret = unsafe {
+        libc::socketpair(
+            libc::AF_UNIX,
+            libc::SOCK_STREAM | libc::SOCK_CLOEXEC,
+            0,
+            fds.as_mut_ptr(),
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let [ours, theirs] = fds;
+
+    // fusermount fills in fd, rootmode, user_id and group_id itself.
+    let mut options = format!(
+        "ro,nosuid,nodev,default_permissions,fsname={},subtype=banyan",
+        fsname,
+    );
+    if allow_other {
+        options.push_str(",allow_other");
+    }
+    let mut command = Command::new(program);
+    command
+        .args(["-o", &options, "--"])
+        .arg(mountpoint)
+        .env("_FUSE_COMMFD", theirs.to_string());
+    // SAFETY: fcntl is async-signal-safe.
+    unsafe {
+        command.pre_exec(move || {
+            if libc::fcntl(theirs, libc::F_SETFD, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let child = command.spawn();
+    // Closing our copy of their end means we see EOF if they fail.
+    let _ = close(theirs);
+    let mut child = match child {
+        Ok(child) => child,
+        // A directory on `PATH` we can't search gives EACCES rather than
+        // ENOENT, so either just means there's no program to be had.
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied
+            ) =>
+        {
+            let _ = close(ours);
+            return Ok(None);
+        }
+        Err(e) => {
+            let _ = close(ours);
+            return Err(e);
+        }
+    };
+
+    let fd = receive_fd(ours);
+    let _ = close(ours);
+    let status = child.wait()?;
+    match fd {
+        Ok(fd) if status.success() => Ok(Some(fd)),
+        Ok(fd) => {
+            let _ = close(fd);
+            Err(io::Error::other(format!("{} failed: {}", program, status)))
+        }
+        Err(_) if !status.success() => {
+            Err(io::Error::other(format!("{} failed: {}", program, status)))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Receive a single fd sent with `SCM_RIGHTS` over `sock`.
+fn receive_fd(sock: RawFd) -> io::Result<RawFd> {
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr().cast(),
+        iov_len: byte.len(),
+    };
+    // Room for one fd, aligned as `cmsghdr` needs.
+    let mut control = [0u64; 4];
+    let space = unsafe { libc::CMSG_SPACE(size_of::<RawFd>() as u32) };
+    // SAFETY: `msghdr` is plain data, for which zeroes are fine.
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = space as usize;
+
+    loop {
+        let ret =
+            unsafe { libc::recvmsg(sock, &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if ret != -1 {
+            break;
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINTR) {
+            return Err(err);
+        }
+    }
+
+    // SAFETY: `msg` was filled in by recvmsg, and `control` outlives it.
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        {
+            return Err(io::Error::other("no fd from fusermount"));
+        }
+        Ok(std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast()))
+    }
+}
@@ -0,0 +1,196 @@
+/// Returns true if `pattern` contains any glob metacharacters.
+pub(crate) fn is_glob(pattern: &[u8]) -> bool {
+    pattern.iter().any(|c| matches!(c, b'*' | b'?' | b'['))
+}
+
+/// The longest leading run of whole path components in `pattern` that
+/// contain no metacharacters, e.g. `./a/b` for `./a/b/*.c/d`.
+pub(crate) fn literal_prefix(pattern: &[u8]) -> &[u8] {
+    let first = match pattern.iter().position(|c| matches!(c, b'*' | b'?' | b'[')) {
+        Some(i) => i,
+        None => return pattern,
+    };
+    match pattern[..first].iter().rposition(|&c| c == b'/') {
+        Some(i) => &pattern[..i],
+        None => &[],
+    }
+}
+
+/// Match `path` against a shell-style glob over raw bytes.
+///
+/// `?` and `*` match within a single path component, `**` matches across
+/// components, and `[...]` matches a set (`[!...]` negates it) which may
+/// contain ranges like `a-z`.
+///
+/// Only the last `*`, and the last `**` before it, are ever backtracked to,
+/// so this takes at most `pattern.len() * path.len()` steps.
+pub(crate) fn matches(pattern: &[u8], path: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Where matching resumes if the last `*` or `**` takes another byte:
+    // just past it in the pattern, and where it stops in the path.
+    let mut star: Option<(usize, usize)> = None;
+    let mut globstar: Option<(usize, usize)> = None;
+    loop {
+        if p == pattern.len() && s == path.len() {
+            return true;
+        }
+        if pattern.get(p) == Some(&b'*') {
+            if pattern.get(p + 1) == Some(&b'*') {
+                p += 2;
+                globstar = Some((p, s));
+                star = None;
+            } else {
+                p += 1;
+                star = Some((p, s));
+            }
+            continue;
+        }
+        if p < pattern.len() && s < path.len() {
+            if let Some(next) = match_one(pattern, p, path[s]) {
+                p = next;
+                s += 1;
+                continue;
+            }
+        }
+
+        // A `*` can't take a `/`, but a `**` can.
+        match (star, globstar) {
+            (Some((after, end)), _)
+                if end < path.len() && path[end] != b'/' =>
+            {
+                star = Some((after, end + 1));
+                p = after;
+                s = end + 1;
+            }
+            (_, Some((after, end))) if end < path.len() => {
+                star = None;
+                globstar = Some((after, end + 1));
+                p = after;
+                s = end + 1;
+            }
+            _ => return false,
+        }
+    }
+}
+
+/// Match the single byte `c` against whatever isn't a star at `pattern[p]`,
+/// returning where the pattern continues if it matched.
+fn match_one(pattern: &[u8], p: usize, c: u8) -> Option<usize> {
+    match pattern[p] {
+        b'?' => (c != b'/').then_some(p + 1),
+        b'[' if c == b'/' => None,
+        b'[' => match match_set(&pattern[p + 1..], c) {
+            Some((true, rest)) => Some(pattern.len() - rest.len()),
+            Some((false, _)) => None,
+            // An unterminated set is just a literal `[`
+            None => (c == b'[').then_some(p + 1),
+        },
+        x => (x == c).then_some(p + 1),
+    }
+}
+
+/// Match `c` against the set starting just after a `[`, returning whether it
+/// matched and the rest of the pattern after the closing `]`.
+fn match_set(pattern: &[u8], c: u8) -> Option<(bool, &[u8])> {
+    let (negate, mut pattern) = match pattern.split_first() {
+        Some((b'!', rest)) | Some((b'^', rest)) => (true, rest),
+        _ => (false, pattern),
+    };
+
+    let mut found = false;
+    let mut first = true;
+    loop {
+        match pattern {
+            [] => return None,
+            // A `]` right at the start is part of the set.
+            [b']', rest @ ..] if !first => return Some((found != negate, rest)),
+            [lo, b'-', hi, rest @ ..] if *hi != b']' => {
+                found |= (*lo..=*hi).contains(&c);
+                pattern = rest;
+            }
+            [x, rest @ ..] => {
+                found |= *x == c;
+                pattern = rest;
+            }
+        }
+        first = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn m(pattern: &str, path: &str) -> bool {
+        matches(pattern.as_bytes(), path.as_bytes())
+    }
+
+    #[test]
+    fn literals_and_wildcards() {
+        assert!(m("./a/b", "./a/b"));
+        assert!(!m("./a/b", "./a/bc"));
+        assert!(!m("./a/bc", "./a/b"));
+        assert!(m("./a/?", "./a/b"));
+        assert!(!m("./a?b", "./a/b"));
+        assert!(m("./*.c", "./main.c"));
+        assert!(m("./*.c", "./.c"));
+        assert!(!m("./*.c", "./src/main.c"));
+        assert!(m("./*/*.c", "./src/main.c"));
+        assert!(m("./a*b*c", "./abxbyc"));
+        assert!(!m("./a*b*c", "./abxbyd"));
+    }
+
+    #[test]
+    fn globstar() {
+        assert!(m("./**.c", "./main.c"));
+        assert!(m("./**.c", "./src/deep/main.c"));
+        assert!(m("./**/main.c", "./src/deep/main.c"));
+        assert!(!m("./**/main.c", "./src/deep/main.h"));
+        assert!(m("./src/**", "./src/a/b"));
+        // After a `**`, a `*` still stays within a component, but the `**`
+        // can take more to make it fit.
+        assert!(m("./**/x*.c", "./a/x/xy.c"));
+        assert!(!m("./**/x*.c", "./a/x/y.c"));
+        assert!(m("./**a*/b", "./q/a/za/b"));
+    }
+
+    #[test]
+    fn sets() {
+        assert!(m("./[abc]", "./b"));
+        assert!(!m("./[abc]", "./d"));
+        assert!(m("./[a-c]x", "./bx"));
+        assert!(m("./[!a-c]x", "./dx"));
+        assert!(!m("./[!a-c]x", "./ax"));
+        assert!(m("./[]]", "./]"));
+        assert!(m("./[a-]", "./-"));
+        assert!(!m("./a[/]b", "./a/b"));
+        // An unterminated set is taken literally.
+        assert!(m("./[ab", "./[ab"));
+        assert!(!m("./[ab", "./a"));
+    }
+
+    #[test]
+    fn raw_bytes() {
+        assert!(matches(b"./caf?", b"./caf\xe9"));
+        assert!(matches(b"./[\xe0-\xff]*", b"./\xe9t\xe9"));
+    }
+
+    #[test]
+    fn many_stars() {
+        // Trying every split of the path between the stars would take
+        // forever here.
+        let path = format!("./{}", "a".repeat(200));
+        assert!(!m("./a*a*a*a*a*a*a*a*a*a*b", &path));
+        assert!(!m("./**a**a**a**a**a**a**a**a**b", &path));
+        assert!(m("./a*a*a*a*a*a*a*a*a*a*a", &path));
+    }
+
+    #[test]
+    fn prefixes() {
+        assert!(is_glob(b"./a/*.c"));
+        assert!(!is_glob(b"./a/b.c"));
+        assert_eq!(literal_prefix(b"./a/b/*.c/d"), b"./a/b");
+        assert_eq!(literal_prefix(b"./a/b"), b"./a/b");
+        assert_eq!(literal_prefix(b"*"), b"");
+    }
+}
@@ -1,10 +1,12 @@
 mod unix;
 pub(crate) use unix::*;
 
-mod utfpath;
-pub use utfpath::{joinpath, os_to_utf};
-
+pub(crate) mod fuse;
+pub(crate) mod glob;
 pub(crate) mod queue;
+pub(crate) mod tar;
+pub(crate) mod uring;
+pub(crate) mod xattr;
 
 mod aparc;
 
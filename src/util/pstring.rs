@@ -1,19 +1,29 @@
 use std::{
-    ffi::{CStr, CString, OsStr},
+    ffi::{CStr, CString, NulError, OsStr},
     ops::Add,
     os::unix::prelude::OsStrExt,
     path::Path,
-    str::Utf8Error,
 };
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd)]
+/// A nul-terminated path that keeps track of its own length.
+///
+/// Linux filenames are arbitrary bytes (other than `/` and nul), so a
+/// `PString` makes no assumptions about encoding; use `Display` to get a
+/// printable, escaped form.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct PString {
     length: usize,
     cstr: CString,
 }
 
+impl PartialOrd for PString {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl Ord for PString {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         self.cstr.cmp(&other.cstr)
@@ -38,12 +48,6 @@ impl AsRef<CStr> for PString {
     }
 }
 
-impl AsRef<str> for PString {
-    fn as_ref(&self) -> &str {
-        unsafe { std::str::from_utf8_unchecked(self.cstr.as_bytes()) }
-    }
-}
-
 impl<'a> Add<&'a PString> for PString {
     type Output = PString;
 
@@ -53,12 +57,12 @@ impl<'a> Add<&'a PString> for PString {
         let cstr = unsafe {
             let mut x: Vec<u8> = Vec::with_capacity(length + 1);
             std::ptr::copy_nonoverlapping::<u8>(
-                self.cstr.as_ptr() as *const u8,
+                self.cstr.as_ptr().cast(),
                 x.as_mut_ptr(),
                 self.length,
             );
             std::ptr::copy_nonoverlapping::<u8>(
-                rhs.cstr.as_ptr() as *const u8,
+                rhs.cstr.as_ptr().cast(),
                 x.as_mut_ptr().add(self.length),
                 rhs.length + 1,
             );
@@ -78,12 +82,12 @@ impl<'a> Add<&'a CStr> for PString {
         let cstr = unsafe {
             let mut x: Vec<u8> = Vec::with_capacity(len_with_nul);
             std::ptr::copy_nonoverlapping::<u8>(
-                self.cstr.as_ptr() as *const u8,
+                self.cstr.as_ptr().cast(),
                 x.as_mut_ptr(),
                 self.length,
             );
             std::ptr::copy_nonoverlapping::<u8>(
-                rhs.as_ptr() as *const u8,
+                rhs.as_ptr().cast(),
                 x.as_mut_ptr().add(self.length),
                 rhs_len_with_nul,
             );
@@ -94,12 +98,36 @@ impl<'a> Add<&'a CStr> for PString {
         PString { length: len_with_nul - 1, cstr }
     }
 }
+
 impl std::fmt::Debug for PString {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.cstr.fmt(f)
     }
 }
 
+/// Escaped form for user-facing output: valid UTF-8 is printed as-is,
+/// control characters and backslashes are escaped, and any bytes that are
+/// not UTF-8 are printed as `\xNN`.
+impl std::fmt::Display for PString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use std::fmt::Write;
+
+        for chunk in self.cstr.as_bytes().utf8_chunks() {
+            for c in chunk.valid().chars() {
+                if c == '\\' || c.is_control() {
+                    write!(f, "{}", c.escape_default())?;
+                } else {
+                    f.write_char(c)?;
+                }
+            }
+            for b in chunk.invalid() {
+                write!(f, "\\x{:02x}", b)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 impl PString {
     pub fn from_str(s: &str) -> PString {
         let length = s.len();
@@ -108,16 +136,20 @@ impl PString {
         PString { length, cstr }
     }
 
-    pub fn from_cstring(cstr: CString) -> Result<PString, Utf8Error> {
-        std::str::from_utf8(cstr.to_bytes())?;
-        Ok(unsafe { PString::from_cstring_unchecked(cstr) })
-    }
-
-    pub unsafe fn from_cstring_unchecked(cstr: CString) -> PString {
+    pub fn from_cstring(cstr: CString) -> PString {
         let length = cstr.to_bytes().len();
         PString { length, cstr }
     }
 
+    /// Create a PString from raw bytes, failing if they contain a nul.
+    pub fn from_vec(bytes: Vec<u8>) -> Result<PString, NulError> {
+        Ok(PString::from_cstring(CString::new(bytes)?))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.cstr.as_bytes()
+    }
+
     pub fn append_path(&self, filename: &CStr) -> PString {
         let bytes = self.cstr.as_bytes();
         let filebytes = filename.to_bytes();
@@ -128,17 +160,16 @@ impl PString {
 
         let cstr = unsafe {
             let mut res: Vec<u8> = Vec::with_capacity(len + 1);
-            res.set_len(len + 1);
             std::ptr::copy_nonoverlapping::<u8>(
-                bytes.as_ptr() as *const u8,
+                bytes.as_ptr(),
                 res.as_mut_ptr(),
                 self.length,
             );
             if addsep {
-                res[self.length] = b'/'
+                *res.as_mut_ptr().add(self.length) = b'/';
             };
             std::ptr::copy_nonoverlapping::<u8>(
-                filebytes.as_ptr() as *const u8,
+                filebytes.as_ptr(),
                 res.as_mut_ptr().add(self.length).add(addsep as usize),
                 filebytes.len() + 1,
             );
@@ -1,14 +1,12 @@
-use super::aparc::APArc;
-use super::{openat, PString};
+use super::{first_extent, lstatat, open_beneath, openat, PString};
+use clap::ArgEnum;
 use std::ffi::CStr;
-use std::mem::MaybeUninit;
-use std::os::unix::prelude::RawFd;
-use std::ptr::{null, self};
-use std::sync::atomic::{AtomicIsize, AtomicUsize, AtomicPtr, Ordering};
+use std::os::unix::prelude::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicIsize, AtomicPtr, AtomicUsize, Ordering};
 use std::sync::{Arc};
-use std::time::Duration;
 use std::os::raw::{c_char, c_long};
-use parking_lot::Mutex;
+use parking_lot::{Condvar, Mutex};
 
 const NODE_LEN: usize = 4096 - (3 * 64);
 
@@ -18,9 +16,110 @@ const GETDENTS64: c_long = 217;
 #[cfg(all(target_os="linux", target_arch="aarch64"))]
 const GETDENTS64: c_long = 61;
 
+/// Which entries the queue hands out first.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Traversal {
+    /// Every entry of a directory before those of its subdirectories, which
+    /// holds more of the tree in memory the wider it is
+    Breadth,
+    /// The entries of a subdirectory as soon as it is found, which holds
+    /// little more than the directories on the way to it
+    Depth,
+}
+
+/// The order each directory's entries are handed out in, and so the order
+/// its files are read in.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FileOrder {
+    /// The order the filesystem lists them in
+    Dirent,
+    /// By inode number, which most filesystems allocate near the data
+    Inode,
+    /// By where on disk each file's data starts, as FIEMAP says, which
+    /// costs an open per file
+    Extent,
+}
+
+/// How a queue hands out entries, and what it keeps open for them.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct QueueOptions {
+    /// Directories to keep open at once for their entries.
+    pub(crate) max_open: usize,
+    pub(crate) traversal: Traversal,
+    pub(crate) file_order: FileOrder,
+}
+
+/// How many directories the queue may keep open for their entries.
+#[derive(Debug)]
+struct Budget {
+    max: usize,
+    open: AtomicUsize,
+}
+
+impl Budget {
+    /// Keep `dir` open for its entries if there's room for it, or give it
+    /// back to be closed once they've been read.
+    fn keep(self: &Arc<Budget>, dir: OwnedFd) -> Result<Arc<Dir>, OwnedFd> {
+        let taken = self
+            .open
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+                (open < self.max).then_some(open + 1)
+            })
+            .is_ok();
+        match taken {
+            true => Ok(Arc::new(Dir { fd: dir, budget: self.clone() })),
+            false => Err(dir),
+        }
+    }
+
+    /// The descriptor to read a directory from, and where its entries
+    /// should be looked up.
+    fn parts(
+        dir: &Result<Arc<Dir>, OwnedFd>,
+    ) -> Result<(RawFd, DirRef), std::io::Error> {
+        match dir {
+            Ok(dir) => Ok((dir.fd.as_raw_fd(), DirRef::Open(dir.clone()))),
+            Err(fd) => {
+                let dot = CStr::from_bytes_with_nul(b".\0").unwrap();
+                let stat = lstatat(fd.as_raw_fd(), dot)?;
+                let closed = DirRef::Closed(stat.st_dev, stat.st_ino);
+                Ok((fd.as_raw_fd(), closed))
+            }
+        }
+    }
+}
+
+/// A directory the queue keeps open until all of its entries have been
+/// dealt with, counted against its budget until then.
+#[derive(Debug)]
+pub(crate) struct Dir {
+    fd: OwnedFd,
+    budget: Arc<Budget>,
+}
+
+impl Drop for Dir {
+    fn drop(&mut self) {
+        self.budget.open.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Where the entries of a node are looked up.
+#[derive(Clone, Debug)]
+pub(crate) enum DirRef {
+    /// In their directory, which the queue keeps open for them.
+    Open(Arc<Dir>),
+    /// In their directory opened again, which has to be the one they were
+    /// read from, with this device and inode number. It could have been
+    /// moved, and something else put in its place, since.
+    Closed(u64, u64),
+}
+
 #[derive(Debug)]
 pub(crate) struct NodeData {
     basedir: Arc<PString>,
+    /// The directory the entries were read from, to look them up relative
+    /// to, unless the queue was over budget and closed it straight away.
+    dir: DirRef,
     offset: AtomicIsize,
     data: [u8; NODE_LEN],
     size: isize,
@@ -76,7 +175,7 @@ impl<'a> NodeSlice {
     #[inline]
     pub(crate) fn filetype(&self) -> u8 {
         unsafe {
-            *(self.node.data.as_ptr().offset(self.start + 18) as *const u8)
+            *self.node.data.as_ptr().offset(self.start + 18)
         }
     }
 
@@ -84,7 +183,7 @@ impl<'a> NodeSlice {
     pub(crate) fn filename(&'a self) -> &'a CStr {
         unsafe {
             CStr::from_ptr(
-                self.node.data.as_ptr().offset(self.start + 19) as *const c_char
+                self.node.data.as_ptr().offset(self.start + 19).cast::<c_char>()
             )
         }
     }
@@ -93,15 +192,69 @@ impl<'a> NodeSlice {
     pub(crate) fn fullpath(&'a self) -> PString {
         self.node.basedir.append_path(self.filename())
     }
+
+    /// The directory this entry is in, open for as long as the entry is,
+    /// or `None` if the queue didn't keep it open.
+    #[inline]
+    pub(crate) fn dirfd(&self) -> Option<RawFd> {
+        match &self.node.dir {
+            DirRef::Open(dir) => Some(dir.fd.as_raw_fd()),
+            DirRef::Closed(..) => None,
+        }
+    }
+
+    /// The device and inode number the directory this entry is in had when
+    /// it was read, if the queue didn't keep it open, for checking that
+    /// opening it again finds the same directory.
+    #[inline]
+    pub(crate) fn closed_dir(&self) -> Option<(u64, u64)> {
+        match self.node.dir {
+            DirRef::Open(_) => None,
+            DirRef::Closed(dev, ino) => Some((dev, ino)),
+        }
+    }
+
+    /// The path of the directory this entry is in, shared by all of its
+    /// entries.
+    #[inline]
+    pub(crate) fn basedir(&self) -> &Arc<PString> {
+        &self.node.basedir
+    }
 }
 
-impl<'a> NodeData {
+impl NodeData {
+    fn empty(dir: &DirRef, path: Arc<PString>) -> NodeData {
+        NodeData {
+            basedir: path,
+            dir: dir.clone(),
+            offset: AtomicIsize::new(0),
+            data: [0u8; NODE_LEN],
+            size: 0,
+        }
+    }
+
+    /// Each `linux_dirent64` in the node, as raw bytes.
+    fn records(&self) -> impl Iterator<Item = &[u8]> {
+        let mut at = 0;
+        std::iter::from_fn(move || {
+            if at >= self.size as usize {
+                return None;
+            }
+            let len = [self.data[at + 16], self.data[at + 17]];
+            let len = u16::from_ne_bytes(len) as usize;
+            let record = &self.data[at..at + len];
+            at += len;
+            Some(record)
+        })
+    }
+
     #[inline]
     pub(crate) fn new(
         fd: RawFd,
+        dir: &DirRef,
         path: Arc<PString>,
     ) -> Result<Option<NodeData>, std::io::Error> {
-        let n = NodeData::new_internal(fd, path)?;
+        let n = NodeData::new_internal(fd, dir, path)?;
         if n.size == 0 {
             Ok(None)
         } else {
@@ -116,16 +269,16 @@ impl<'a> NodeData {
     /// open folder.
     fn new_internal(
         fd: RawFd,
+        dir: &DirRef,
         path: Arc<PString>,
     ) -> Result<NodeData, std::io::Error> {
         let mut n = NodeData {
             basedir: path.clone(),
+            dir: dir.clone(),
             offset: AtomicIsize::new(0),
-            // SAFETY: we don't care about the data here,
-            // and update it immediately after with the syscall.
-            data: unsafe {
-                MaybeUninit::<[u8; NODE_LEN]>::uninit().assume_init()
-            },
+            // We don't care about the data here, and update it immediately
+            // after with the syscall.
+            data: [0u8; NODE_LEN],
             // SAFETY: we should always validate for nulls
             size: 0,
         };
@@ -171,23 +324,120 @@ pub(crate) struct Node {
     pub(crate) next: AtomicPtr<Node>
 }
 
+/// Bookkeeping for workers waiting on the queue to get more work.
 #[derive(Default)]
+struct Park {
+    /// Workers taking entries off the queue.
+    workers: usize,
+    /// Workers waiting for more nodes.
+    idle: usize,
+    /// Bumped whenever a node is added, so sleepers can tell.
+    added: u64,
+    /// Set once the walk is over, for whatever reason.
+    closed: bool,
+}
+
 pub(crate) struct Queue {
     head: Mutex<usize>,
+    /// The last node, which only moves with `head` locked.
     tail: AtomicPtr<Node>,
+    park: Mutex<Park>,
+    wake: Condvar,
+    budget: Arc<Budget>,
+    traversal: Traversal,
+    file_order: FileOrder,
+}
+
+/// Read all the entries of the directory `fd`, sorted into `order`.
+fn read_nodes(
+    fd: RawFd,
+    dir: &DirRef,
+    path: Arc<PString>,
+    order: FileOrder,
+) -> Result<Vec<NodeData>, std::io::Error> {
+    let mut nodes = vec![];
+    while let Some(data) = NodeData::new(fd, dir, path.clone())? {
+        let endearly = data.size < 3072;
+        nodes.push(data);
+        if endearly {
+            break;
+        }
+    }
+    if order == FileOrder::Dirent || nodes.is_empty() {
+        return Ok(nodes);
+    }
+
+    // Entries without data on disk, or of a kind FIEMAP can't tell about,
+    // go after those with, by inode.
+    let key = |record: &[u8]| {
+        let mut ino = [0u8; 8];
+        ino.copy_from_slice(&record[..8]);
+        let ino = u64::from_ne_bytes(ino);
+        if order == FileOrder::Inode || record[18] != libc::DT_REG {
+            return (1, ino);
+        }
+        let name = match CStr::from_bytes_until_nul(&record[19..]) {
+            Ok(name) => name,
+            Err(_) => return (1, ino),
+        };
+        // Whatever goes wrong here, the walker runs into it again.
+        let flags = libc::O_RDONLY | libc::O_NONBLOCK;
+        let file = match open_beneath(fd, name, flags) {
+            // SAFETY: nothing else has the descriptor we just opened.
+            Ok(file) => unsafe { OwnedFd::from_raw_fd(file) },
+            Err(_) => return (1, ino),
+        };
+        match first_extent(file.as_raw_fd()) {
+            Ok(Some(physical)) => (0, physical),
+            _ => (1, ino),
+        }
+    };
+    let mut records: Vec<_> = nodes
+        .iter()
+        .flat_map(|node| node.records())
+        .map(|record| (key(record), record))
+        .collect();
+    records.sort_by_key(|(key, _)| *key);
+
+    // Pack the records into nodes again, in their new order.
+    let mut sorted = vec![NodeData::empty(dir, path.clone())];
+    for (_, record) in records {
+        let mut node = sorted.last_mut().unwrap();
+        if node.size as usize + record.len() > NODE_LEN {
+            sorted.push(NodeData::empty(dir, path.clone()));
+            node = sorted.last_mut().unwrap();
+        }
+        let at = node.size as usize;
+        node.data[at..at + record.len()].copy_from_slice(record);
+        node.size += record.len() as isize;
+    }
+    Ok(sorted)
 }
 
 impl Queue {
+    /// A queue of the entries of `dir`.
     pub fn new_with_folder(
-        dirfd: RawFd,
-        path: Arc<PString>
+        dir: OwnedFd,
+        path: Arc<PString>,
+        options: QueueOptions,
     ) -> Result<Queue, std::io::Error> {
-        let head_data = match NodeData::new(dirfd, path.clone())? {
-            Some(data) => data,
-            None => return Err(std::io::Error::new(std::io::ErrorKind::Other, "directory is empty."))
+        let budget = Arc::new(Budget {
+            max: options.max_open,
+            open: AtomicUsize::new(0),
+        });
+        let dir = budget.keep(dir);
+        let (fd, dir) = Budget::parts(&dir)?;
+        let mut nodes =
+            read_nodes(fd, &dir, path.clone(), options.file_order)?;
+        // An empty directory still needs a head, which just has nothing in
+        // it to hand out.
+        let head_data = if nodes.is_empty() {
+            NodeData::empty(&dir, path)
+        } else {
+            nodes.remove(0)
         };
-        
-        let mut head = Box::new(Node {
+
+        let head = Box::new(Node {
             data: Arc::new(head_data),
             next: AtomicPtr::default()
         });
@@ -196,31 +446,74 @@ impl Queue {
 
         let queue = Queue {
             head: Mutex::new(ptr as usize),
-            tail: AtomicPtr::new(ptr)
+            tail: AtomicPtr::new(ptr),
+            park: Mutex::default(),
+            wake: Condvar::new(),
+            budget,
+            traversal: options.traversal,
+            file_order: options.file_order,
         };
 
 
-        queue.add_folder(dirfd, path)?;
+        queue.add_nodes(nodes);
         Ok(queue)
     }
 
+    /// Read every entry of the directory `dir` into the queue, which keeps
+    /// it open until they have all been dealt with if its budget allows,
+    /// and closes it once they have been read otherwise.
     pub fn add_folder(
         &self,
-        dirfd: RawFd,
+        dir: OwnedFd,
         path: Arc<PString>,
     ) -> Result<(), std::io::Error> {
-        while let Some(data) = NodeData::new(dirfd, path.clone())? {
-            let endearly = data.size < 3072;
-            let node = Box::new(Node {
+        let dir = self.budget.keep(dir);
+        let (fd, dir) = Budget::parts(&dir)?;
+        self.add_nodes(read_nodes(fd, &dir, path, self.file_order)?);
+        Ok(())
+    }
+
+    /// Queue a directory's nodes where the traversal wants them: at the
+    /// back, or, depth-first, ahead of all but the node being taken from.
+    fn add_nodes(&self, nodes: Vec<NodeData>) {
+        if self.traversal == Traversal::Breadth {
+            for data in nodes {
+                self.add_node(Box::new(Node {
+                    data: Arc::new(data),
+                    next: AtomicPtr::default()
+                }));
+            }
+            return;
+        }
+        if nodes.is_empty() {
+            return;
+        }
+
+        // Link the nodes up, back to front, then splice them in after the
+        // head, which nobody moves on from without its lock.
+        let mut first: *mut Node = ptr::null_mut();
+        let mut last: *mut Node = ptr::null_mut();
+        for data in nodes.into_iter().rev() {
+            let node = Box::into_raw(Box::new(Node {
                 data: Arc::new(data),
-                next: AtomicPtr::default()
-            });
-            self.add_node(node);
-            if endearly {
-                break;
+                next: AtomicPtr::new(first),
+            }));
+            if last.is_null() {
+                last = node;
             }
+            first = node;
         }
-        Ok(())
+        {
+            let headlock = self.head.lock();
+            let head = unsafe { &*(*headlock as *const Node) };
+            let next = head.next.load(Ordering::Acquire);
+            unsafe { &*last }.next.store(next, Ordering::Release);
+            head.next.store(first, Ordering::Release);
+            if next.is_null() {
+                self.tail.store(last, Ordering::Release);
+            }
+        }
+        self.added();
     }
 
     pub fn add_path_at(
@@ -233,78 +526,98 @@ impl Queue {
         //                      |        /-> &PString -> &CStr
         let cpath: &CStr = path.as_ref().as_ref();
         let fd = openat(parentfd, cpath, libc::O_DIRECTORY)?;
-        self.add_folder(fd, path)?;
-        unsafe { libc::close(fd) };
-        Ok(())
+        // SAFETY: nothing else has the descriptor we just opened.
+        self.add_folder(unsafe { OwnedFd::from_raw_fd(fd) }, path)
     }
 
+    /// Append a node. The tail is only moved with the head's lock held,
+    /// since `advance` frees nodes once it has moved past them, and the
+    /// tail could otherwise still point at one.
     pub fn add_node(&self, next: Box<Node>) {
         let ptr = Box::into_raw(next);
+        {
+            let _headlock = self.head.lock();
+            // SAFETY: tail is never null, and never behind the head.
+            let tail = unsafe { &*self.tail.load(Ordering::Acquire) };
+            tail.next.store(ptr, Ordering::Release);
+            self.tail.store(ptr, Ordering::Release);
+        }
+
+        self.added();
+    }
+
+    /// Wake anyone waiting for work. A node holds many entries, which any
+    /// number of workers can share.
+    fn added(&self) {
+        let mut park = self.park.lock();
+        park.added += 1;
+        if park.idle > 0 {
+            self.wake.notify_all();
+        }
+    }
+
+    /// Set how many workers will call `next`. Must be called before any of
+    /// them start.
+    pub fn set_workers(&self, workers: usize) {
+        self.park.lock().workers = workers;
+    }
+
+    /// Take the next entry off the queue, sleeping while it is empty but
+    /// other workers might still add to it. Returns `None` once every worker
+    /// is waiting on an empty queue, since none of them can add more, or
+    /// once the queue has been closed.
+    pub fn next(&self) -> Option<NodeSlice> {
         loop {
-            // SAFETY: tail should never be null.
-            let tailptr = self.tail.load(Ordering::Relaxed);
-            let tail = unsafe { std::mem::transmute::<*mut Node, &Node>(tailptr) };
-
-            match tail.next.compare_exchange(
-                ptr::null_mut::<>(),
-                ptr,
-                Ordering::AcqRel,
-                Ordering::Acquire
-            ) {
-                    // Succeeded at updating tail.next
-                    Ok(_) => {
-                        // Try to update our tracked tail.
-                        // - success: we updated the tail!
-                        // - failure: we stalled, and someone else fixed
-                        //   it for us when we were not scheduled to run.
-                        self.tail.compare_exchange(
-                            tailptr, 
-                            ptr,
-                            Ordering::AcqRel,
-                            Ordering::Acquire
-                        );
-                        return;
-                    }
-                    // We get here one of two ways:
-                    // - we stalled between load and swap,
-                    // - another thread stalled between swap_null(next)
-                    //   and swap_existing(tail, next)
-                    // Address this by trying to update the real tail ourselves.
-                    Err(real_tail) => {
-                        // One of two things can happen here:
-                        // - success: thus fixing the stall
-                        // - failure: because either we stalled or another
-                        //   thread unstalled, fixing it for us.
-                        self.tail.compare_exchange(
-                            tailptr,
-                            real_tail,
-                            Ordering::AcqRel,
-                            Ordering::Acquire
-                        );
-                    }
+            if let Some(slice) = self.advance() {
+                return Some(slice);
+            }
+
+            let mut park = self.park.lock();
+            if park.closed {
+                return None;
             }
+            // Adding a node bumps `added` with the lock held, so if there's
+            // still nothing now, we can't miss the wakeup for the next one.
+            if let Some(slice) = self.advance() {
+                return Some(slice);
+            }
+            park.idle += 1;
+            if park.idle == park.workers {
+                park.closed = true;
+                self.wake.notify_all();
+                return None;
+            }
+            let seen = park.added;
+            while park.added == seen && !park.closed {
+                self.wake.wait(&mut park);
+            }
+            park.idle -= 1;
         }
     }
 
+    /// Stop handing out entries, waking every waiting worker.
+    pub fn close(&self) {
+        self.park.lock().closed = true;
+        self.wake.notify_all();
+    }
+
     pub fn advance(&self) -> Option<NodeSlice> {
         loop {
             let mut headlock = self.head.lock();
             let headptr = *headlock;
-            let head = unsafe { std::mem::transmute::<usize, &Node>(headptr) };
+            let head = unsafe { &*(headptr as *const Node) };
 
             match head.data.advance() {
                 Some(slice) => {
                     return Some(slice)
                 },
                 None => {
-                    let next = head.next.load(Ordering::Relaxed);
-                    if next == ptr::null_mut::<>() {
+                    let next = head.next.load(Ordering::Acquire);
+                    if next.is_null() {
                         return None;
-                    } else {
-                        if *headlock == headptr {
-                            unsafe { Box::from_raw(*headlock as *mut Node) };
-                            *headlock = next as usize;
-                        }
+                    } else if *headlock == headptr {
+                        drop(unsafe { Box::from_raw(*headlock as *mut Node) });
+                        *headlock = next as usize;
                     }
                 }
             }
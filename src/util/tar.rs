@@ -0,0 +1,575 @@
+//! Just enough of the POSIX tar format (ustar with pax extended headers) to
+//! stream layers in and out of archives.
+
+use std::io::{self, Read, Write};
+
+const BLOCK: usize = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Kind {
+    File,
+    Hardlink,
+    Symlink,
+    Dir,
+    /// Anything else (devices, fifos, ...), which we skip over.
+    Other(u8),
+}
+
+impl Kind {
+    fn typeflag(self) -> u8 {
+        match self {
+            Kind::File => b'0',
+            Kind::Hardlink => b'1',
+            Kind::Symlink => b'2',
+            Kind::Dir => b'5',
+            Kind::Other(c) => c,
+        }
+    }
+
+    fn from_typeflag(c: u8) -> Kind {
+        match c {
+            b'0' | 0 | b'7' => Kind::File,
+            b'1' => Kind::Hardlink,
+            b'2' => Kind::Symlink,
+            b'5' => Kind::Dir,
+            c => Kind::Other(c),
+        }
+    }
+}
+
+/// Metadata for a single archive member.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Header {
+    pub path: Vec<u8>,
+    pub kind: Kind,
+    /// Permission bits only.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub mtime: u64,
+    /// Target of a symlink or hard link.
+    pub link: Vec<u8>,
+    /// Extended attributes, stored as `SCHILY.xattr.*` pax records.
+    pub xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Header {
+    pub fn new(path: Vec<u8>, kind: Kind) -> Header {
+        Header {
+            path,
+            kind,
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            mtime: 0,
+            link: vec![],
+            xattrs: vec![],
+        }
+    }
+}
+
+/// Write `value` as a nul-terminated octal number into `field`, returning
+/// false if it doesn't fit.
+fn put_octal(field: &mut [u8], value: u64) -> bool {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    if digits.len() > field.len() - 1 {
+        return false;
+    }
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+    true
+}
+
+/// Append a single `length key=value\n` pax record to `buf`.
+fn pax_record(buf: &mut Vec<u8>, key: &[u8], value: &[u8]) {
+    // The length includes its own digits, so find a fixed point.
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    buf.extend_from_slice(len.to_string().as_bytes());
+    buf.push(b' ');
+    buf.extend_from_slice(key);
+    buf.push(b'=');
+    buf.extend_from_slice(value);
+    buf.push(b'\n');
+}
+
+/// A tar archive being written to `W`.
+pub(crate) struct Writer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(out: W) -> Writer<W> {
+        Writer { out }
+    }
+
+    fn write_padding(&mut self, len: u64) -> io::Result<()> {
+        let pad = (BLOCK - (len % BLOCK as u64) as usize) % BLOCK;
+        self.out.write_all(&[0u8; BLOCK][..pad])
+    }
+
+    fn write_block(
+        &mut self,
+        name: &[u8],
+        typeflag: u8,
+        header: &Header,
+        size: u64,
+        pax: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        let mut block = [0u8; BLOCK];
+
+        // Try to fit the path in the ustar name/prefix fields, falling back
+        // to a pax record if it doesn't.
+        let fits = if name.len() <= 100 {
+            block[..name.len()].copy_from_slice(name);
+            true
+        } else {
+            let split = name[..name.len().min(156)]
+                .iter()
+                .rposition(|&c| c == b'/')
+                .filter(|&i| name.len() - i - 1 <= 100 && i != 0);
+            match split {
+                Some(i) => {
+                    block[..name.len() - i - 1].copy_from_slice(&name[i + 1..]);
+                    block[345..345 + i].copy_from_slice(&name[..i]);
+                    true
+                }
+                None => false,
+            }
+        };
+        if !fits {
+            block[..100].copy_from_slice(&name[..100]);
+            pax_record(pax, b"path", name);
+            if std::str::from_utf8(name).is_err() {
+                pax_record(pax, b"hdrcharset", b"BINARY");
+            }
+        }
+
+        put_octal(&mut block[100..108], header.mode as u64);
+        if !put_octal(&mut block[108..116], header.uid as u64) {
+            pax_record(pax, b"uid", header.uid.to_string().as_bytes());
+        }
+        if !put_octal(&mut block[116..124], header.gid as u64) {
+            pax_record(pax, b"gid", header.gid.to_string().as_bytes());
+        }
+        if !put_octal(&mut block[124..136], size) {
+            pax_record(pax, b"size", size.to_string().as_bytes());
+        }
+        put_octal(&mut block[136..148], header.mtime);
+        block[156] = typeflag;
+        if header.link.len() <= 100 {
+            block[157..157 + header.link.len()].copy_from_slice(&header.link);
+        } else {
+            pax_record(pax, b"linkpath", &header.link);
+            if std::str::from_utf8(&header.link).is_err() {
+                pax_record(pax, b"hdrcharset", b"BINARY");
+            }
+        }
+        block[257..263].copy_from_slice(b"ustar\0");
+        block[263..265].copy_from_slice(b"00");
+
+        // The checksum is computed with its own field set to spaces.
+        block[148..156].copy_from_slice(b"        ");
+        let sum: u64 = block.iter().map(|&c| c as u64).sum();
+        put_octal(&mut block[148..155], sum);
+        block[155] = b' ';
+
+        self.out.write_all(&block)
+    }
+
+    /// Append a member, copying exactly `header.size` bytes from `data`.
+    pub fn append(
+        &mut self,
+        header: &Header,
+        data: &mut dyn Read,
+    ) -> io::Result<()> {
+        let mut pax = vec![];
+        for (name, value) in &header.xattrs {
+            let mut key = b"SCHILY.xattr.".to_vec();
+            key.extend_from_slice(name);
+            pax_record(&mut pax, &key, value);
+        }
+
+        // Work out which pax records we need by trying the real header
+        // first; it is only written once we know.
+        let mut probe = Writer::new(io::sink());
+        probe.write_block(
+            &header.path,
+            header.kind.typeflag(),
+            header,
+            header.size,
+            &mut pax,
+        )?;
+
+        if !pax.is_empty() {
+            let mut name = b"PaxHeaders/".to_vec();
+            let path = header.path.strip_suffix(b"/").unwrap_or(&header.path);
+            let base = path.rsplit(|&c| c == b'/').next().unwrap();
+            name.extend_from_slice(&base[..base.len().min(80)]);
+            let pax_header = Header::new(name.clone(), Kind::Other(b'x'));
+            self.write_block(
+                &name,
+                b'x',
+                &pax_header,
+                pax.len() as u64,
+                &mut vec![],
+            )?;
+            self.out.write_all(&pax)?;
+            self.write_padding(pax.len() as u64)?;
+        }
+
+        self.write_block(
+            &header.path,
+            header.kind.typeflag(),
+            header,
+            header.size,
+            &mut vec![],
+        )?;
+
+        let copied = io::copy(&mut data.take(header.size), &mut self.out)?;
+        if copied != header.size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file shrank while being archived",
+            ));
+        }
+        self.write_padding(header.size)
+    }
+
+    /// Write the end-of-archive marker and return the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.write_all(&[0u8; BLOCK * 2])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Parse a numeric header field, which is either octal text or, for large
+/// values, big-endian base-256 with the high bit set.
+fn get_number(field: &[u8]) -> io::Result<u64> {
+    if field.first().is_some_and(|&c| c & 0x80 != 0) {
+        let mut value: u64 = (field[0] & 0x7f) as u64;
+        for &c in &field[1..] {
+            value = value.checked_mul(256).ok_or_else(bad_header)? | c as u64;
+        }
+        return Ok(value);
+    }
+
+    let digits = field
+        .iter()
+        .copied()
+        .skip_while(|&c| c == b' ')
+        .take_while(|&c| c != 0 && c != b' ');
+    let mut value: u64 = 0;
+    for c in digits {
+        if !(b'0'..=b'7').contains(&c) {
+            return Err(bad_header());
+        }
+        value = value.checked_mul(8).ok_or_else(bad_header)? | (c - b'0') as u64;
+    }
+    Ok(value)
+}
+
+fn bad_header() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed tar header")
+}
+
+/// A nul-terminated string field.
+fn get_str(field: &[u8]) -> &[u8] {
+    match field.iter().position(|&c| c == 0) {
+        Some(end) => &field[..end],
+        None => field,
+    }
+}
+
+/// Overrides from pax records or GNU long name entries, which apply to the
+/// next real header.
+#[derive(Default)]
+struct Extensions {
+    path: Option<Vec<u8>>,
+    link: Option<Vec<u8>>,
+    size: Option<u64>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    mtime: Option<u64>,
+    xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Extensions {
+    fn parse_pax(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let space = data.iter().position(|&c| c == b' ').ok_or_else(bad_header)?;
+            let len: usize = std::str::from_utf8(&data[..space])
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .filter(|&len| len > space && len <= data.len())
+                .ok_or_else(bad_header)?;
+            let record = &data[space + 1..len];
+            let record = record.strip_suffix(b"\n").unwrap_or(record);
+            let eq = record.iter().position(|&c| c == b'=').ok_or_else(bad_header)?;
+            let (key, value) = (&record[..eq], &record[eq + 1..]);
+
+            let number = || -> io::Result<u64> {
+                // Fractional timestamps are allowed, but we only keep seconds.
+                let value = value.split(|&c| c == b'.').next().unwrap();
+                std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+                    .ok_or_else(bad_header)
+            };
+            match key {
+                b"path" => self.path = Some(value.to_vec()),
+                b"linkpath" => self.link = Some(value.to_vec()),
+                b"size" => self.size = Some(number()?),
+                b"uid" => self.uid = Some(number()? as u32),
+                b"gid" => self.gid = Some(number()? as u32),
+                b"mtime" => self.mtime = Some(number()?),
+                _ => {
+                    if let Some(name) = key.strip_prefix(b"SCHILY.xattr.") {
+                        self.xattrs.push((name.to_vec(), value.to_vec()));
+                    }
+                }
+            }
+            data = &data[len..];
+        }
+        Ok(())
+    }
+}
+
+/// A tar archive being read from `R`, one member at a time.
+pub(crate) struct Reader<R: Read> {
+    inp: R,
+    /// Unread bytes of the current member's data.
+    left: u64,
+    /// Padding after the current member's data.
+    padding: u64,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(inp: R) -> Reader<R> {
+        Reader { inp, left: 0, padding: 0 }
+    }
+
+    fn skip(&mut self, n: u64) -> io::Result<()> {
+        let skipped = io::copy(&mut (&mut self.inp).take(n), &mut io::sink())?;
+        if skipped != n {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "tar archive is truncated",
+            ));
+        }
+        Ok(())
+    }
+
+    fn read_data(&mut self, size: u64) -> io::Result<Vec<u8>> {
+        let mut data = vec![];
+        (&mut self.inp).take(size).read_to_end(&mut data)?;
+        if data.len() as u64 != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "tar archive is truncated",
+            ));
+        }
+        self.skip((BLOCK as u64 - size % BLOCK as u64) % BLOCK as u64)?;
+        Ok(data)
+    }
+
+    /// Move to the next member, skipping whatever is left of the current
+    /// one. Returns `None` at the end of the archive. The member's data can
+    /// then be read from the reader itself.
+    pub fn next(&mut self) -> io::Result<Option<Header>> {
+        self.skip(self.left + self.padding)?;
+        self.left = 0;
+        self.padding = 0;
+
+        let mut ext = Extensions::default();
+        loop {
+            let mut block = [0u8; BLOCK];
+            match self.inp.read_exact(&mut block) {
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(None)
+                }
+                res => res?,
+            }
+            if block.iter().all(|&c| c == 0) {
+                return Ok(None);
+            }
+
+            let checksum = get_number(&block[148..156])?;
+            let sum: u64 = block[..148]
+                .iter()
+                .chain(&[b' '; 8])
+                .chain(&block[156..])
+                .map(|&c| c as u64)
+                .sum();
+            if checksum != sum {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "tar header checksum mismatch",
+                ));
+            }
+
+            let size = get_number(&block[124..136])?;
+            let typeflag = block[156];
+            match typeflag {
+                b'x' => ext.parse_pax(&self.read_data(size)?)?,
+                // Global pax headers and GNU volume labels are ignored.
+                b'g' | b'V' => self.skip(size.div_ceil(BLOCK as u64) * BLOCK as u64)?,
+                b'L' => ext.path = Some(get_str(&self.read_data(size)?).to_vec()),
+                b'K' => ext.link = Some(get_str(&self.read_data(size)?).to_vec()),
+                _ => {
+                    let mut path = get_str(&block[..100]).to_vec();
+                    if &block[257..262] == b"ustar" {
+                        let prefix = get_str(&block[345..500]);
+                        if !prefix.is_empty() {
+                            let mut full = prefix.to_vec();
+                            full.push(b'/');
+                            full.extend_from_slice(&path);
+                            path = full;
+                        }
+                    }
+
+                    let header = Header {
+                        path: ext.path.unwrap_or(path),
+                        kind: Kind::from_typeflag(typeflag),
+                        mode: get_number(&block[100..108])? as u32 & 0o7777,
+                        uid: ext.uid.map_or_else(
+                            || get_number(&block[108..116]).map(|x| x as u32),
+                            Ok,
+                        )?,
+                        gid: ext.gid.map_or_else(
+                            || get_number(&block[116..124]).map(|x| x as u32),
+                            Ok,
+                        )?,
+                        size: ext.size.unwrap_or(size),
+                        mtime: ext.mtime.map_or_else(
+                            || get_number(&block[136..148]),
+                            Ok,
+                        )?,
+                        link: ext
+                            .link
+                            .unwrap_or_else(|| get_str(&block[157..257]).to_vec()),
+                        xattrs: ext.xattrs,
+                    };
+
+                    // Only regular files carry data, though other members
+                    // may still claim a size we need to skip.
+                    self.left = header.size;
+                    self.padding = (BLOCK as u64 - header.size % BLOCK as u64)
+                        % BLOCK as u64;
+                    return Ok(Some(header));
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = buf.len().min(self.left.min(usize::MAX as u64) as usize);
+        let n = self.inp.read(&mut buf[..max])?;
+        if n == 0 && max != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "tar archive is truncated",
+            ));
+        }
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `members` out and read them back.
+    fn round_trip(members: &[(Header, &[u8])]) -> Vec<(Header, Vec<u8>)> {
+        let mut writer = Writer::new(vec![]);
+        for (header, data) in members {
+            writer.append(header, &mut &data[..]).unwrap();
+        }
+        let archive = writer.finish().unwrap();
+        assert_eq!(archive.len() % BLOCK, 0);
+
+        let mut reader = Reader::new(&archive[..]);
+        let mut found = vec![];
+        while let Some(header) = reader.next().unwrap() {
+            let mut data = vec![];
+            reader.read_to_end(&mut data).unwrap();
+            found.push((header, data));
+        }
+        found
+    }
+
+    fn file(path: &[u8], data: &[u8]) -> Header {
+        let mut header = Header::new(path.to_vec(), Kind::File);
+        header.size = data.len() as u64;
+        header.mtime = 1_600_000_000;
+        header
+    }
+
+    #[test]
+    fn long_names() {
+        // Fits once split into the ustar prefix and name.
+        let split = [&[b'd'; 120][..], b"/", &[b'f'; 90]].concat();
+        // A single component too long for either.
+        let long = [b"dir/".as_ref(), &[b'n'; 150]].concat();
+        // Long, and not UTF-8 either.
+        let binary = [&[b'\xe9'; 130][..], b"/x"].concat();
+        let mut link = Header::new(b"link".to_vec(), Kind::Symlink);
+        link.link = [b"../".as_ref(), &[b't'; 200]].concat();
+        let mut hard = Header::new(b"hard".to_vec(), Kind::Hardlink);
+        hard.link = long.clone();
+
+        let members: [(Header, &[u8]); 5] = [
+            (file(&split, b"split"), b"split"),
+            (file(&long, b"long"), b"long"),
+            (file(&binary, b""), b""),
+            (link, b""),
+            (hard, b""),
+        ];
+        let found = round_trip(&members);
+        assert_eq!(found.len(), members.len());
+        for ((want, data), (got, got_data)) in members.iter().zip(&found) {
+            assert_eq!(got, want);
+            assert_eq!(&got_data[..], *data);
+        }
+    }
+
+    #[test]
+    fn xattrs_and_big_ids() {
+        let mut header = file(b"f", b"data");
+        header.xattrs = vec![
+            (b"user.plain".to_vec(), b"value".to_vec()),
+            // Values are raw bytes, and may hold the record syntax itself.
+            (b"user.tricky".to_vec(), b"a=b\n12 c=d\n\0\xff".to_vec()),
+            (b"security.capability".to_vec(), vec![0; 20]),
+        ];
+        // Too big for the octal fields.
+        header.uid = 1 << 30;
+        header.gid = u32::MAX;
+
+        let found = round_trip(&[(header.clone(), b"data")]);
+        assert_eq!(found, [(header, b"data".to_vec())]);
+    }
+
+    #[test]
+    fn pax_record_lengths() {
+        // The length prefix counts itself, which is fiddly where it gains
+        // a digit.
+        for len in 0..1200 {
+            let mut buf = vec![];
+            pax_record(&mut buf, b"path", &vec![b'x'; len]);
+            let space = buf.iter().position(|&c| c == b' ').unwrap();
+            let claimed: usize =
+                std::str::from_utf8(&buf[..space]).unwrap().parse().unwrap();
+            assert_eq!(claimed, buf.len(), "value of {} bytes", len);
+        }
+    }
+}
@@ -1,99 +1,11 @@
-use std::collections::BTreeMap;
-use std::ffi::{CStr, OsString};
+use std::ffi::{CStr, CString};
 use std::io;
 use std::mem::MaybeUninit;
-use std::os::raw::{c_int, c_char};
-use std::os::unix::prelude::{OsStringExt, RawFd};
-use std::path::PathBuf;
-use std::ptr;
+use std::os::raw::c_int;
+use std::os::unix::prelude::RawFd;
+use std::sync::OnceLock;
 
-pub(crate) fn xattrs(
-    fd: RawFd,
-) -> Result<Option<BTreeMap<String, Vec<u8>>>, std::io::Error> {
-    // Get size of list of xattrs
-    let size = unsafe {
-        let ret = libc::flistxattr(fd, ptr::null_mut(), 0);
-        if ret == -1 {
-            return Err(std::io::Error::last_os_error());
-        }
-        ret as usize
-    };
-
-    // If there are no keys, there must be no xattrs
-    if size == 0 {
-        return Ok(None);
-    };
-
-    let mut result = BTreeMap::new();
-
-    // Get the null-separated list of xattr keys
-    let mut xattrs: Vec<u8> = vec![0; size];
-    {
-        let size = unsafe {
-            libc::flistxattr(fd, xattrs.as_mut_ptr() as *mut c_char, size)
-        };
-        if size == -1 {
-            return Err(std::io::Error::last_os_error());
-        }
-        unsafe { xattrs.set_len(size as usize) };
-    };
-
-    let mut i: usize = 0;
-
-    // Step through each xattr key using libc::strlen to find nulls,
-    // since libc::strlen is almost guaranteed to be faster than
-    // looping in Rust (I hope?)
-    while i < xattrs.len() {
-        // Horrible, terrible, no good, very bad hack
-
-        // SAFETY: Kernel guarantees null-separated list
-        let len =
-            unsafe { libc::strlen(xattrs.as_ptr().add(i) as *const c_char) } + 1;
-
-        // SAFETY: We just found the null
-        let name = unsafe {
-            CStr::from_bytes_with_nul_unchecked(&xattrs[i..i + len])
-        };
-
-        // Get the size of the value of the xattr
-        let value_size = {
-            let ret = unsafe {
-                libc::fgetxattr(fd, name.as_ptr(), ptr::null_mut(), 0)
-            };
-            if ret == -1 {
-                return Err(std::io::Error::last_os_error());
-            }
-            ret as usize
-        };
-
-        // Allocate space for the xattr
-        let mut value: Vec<u8> = vec![0; value_size];
-
-        // Load the xattr
-        let value_size = unsafe {
-            libc::fgetxattr(
-                fd,
-                name.as_ptr(),
-                value.as_mut_ptr() as *mut libc::c_void,
-                value_size,
-            )
-        };
-        if value_size == -1 {
-            return Err(std::io::Error::last_os_error());
-        }
-        unsafe { value.set_len(size as usize) };
-
-        // Store in result!
-        // TODO: change to Vec<u8> because keys are not guaranteed
-        // to be valid UTF-8 on Linux
-        result.insert(name.to_string_lossy().to_string(), value);
-
-        // Move forward in the xattr set
-        i += len;
-    }
-
-    Ok(Some(result))
-}
+use super::PString;
 
 #[inline]
 pub(crate) fn open(
@@ -122,6 +34,133 @@ pub(crate) fn openat(
     }
 }
 
+/// How `open_beneath` resolves names with `openat2`: without following any
+/// symlink, and without leaving the directory it starts from.
+pub(crate) const RESOLVE: u64 =
+    libc::RESOLVE_BENEATH | libc::RESOLVE_NO_SYMLINKS;
+
+/// Whether the kernel has `openat2`, which came with Linux 5.6, and seccomp
+/// lets us use it.
+pub(crate) fn has_openat2() -> bool {
+    static HAS: OnceLock<bool> = OnceLock::new();
+    *HAS.get_or_init(|| {
+        // Too small an `open_how` is refused before anything is opened.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_openat2,
+                libc::AT_FDCWD,
+                b".\0".as_ptr(),
+                std::ptr::null::<libc::open_how>(),
+                0usize,
+            )
+        };
+        let e = io::Error::last_os_error().raw_os_error();
+        !(ret == -1 && matches!(e, Some(libc::ENOSYS) | Some(libc::EPERM)))
+    })
+}
+
+/// Open the entry `name` of the directory `dirfd`, never following a
+/// symlink to get to it, so that a tree being changed while it is walked
+/// can't send the walker outside of it. Without `openat2` this is `openat`
+/// with `O_NOFOLLOW`, which is as good as long as `name` has no slashes.
+pub(crate) fn open_beneath(
+    dirfd: RawFd,
+    name: &CStr,
+    oflag: c_int,
+) -> io::Result<RawFd> {
+    let oflag = oflag | libc::O_NOFOLLOW;
+    if has_openat2() {
+        // SAFETY: `open_how` is plain data, for which zeroes are fine.
+        let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+        how.flags = oflag as u64;
+        how.resolve = RESOLVE;
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_openat2,
+                dirfd,
+                name.as_ptr(),
+                &how as *const libc::open_how,
+                std::mem::size_of::<libc::open_how>(),
+            )
+        };
+        return if fd == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(fd as RawFd)
+        };
+    }
+    openat(dirfd, name, oflag)
+}
+
+/// Open `path`, relative to `dirfd`, like `open_beneath` does a single
+/// entry: without following a symlink anywhere along it, or going above
+/// `dirfd`. Without `openat2`, each directory on the way is opened in turn.
+pub(crate) fn open_path_beneath(
+    dirfd: RawFd,
+    path: &CStr,
+    oflag: c_int,
+) -> io::Result<RawFd> {
+    if has_openat2() {
+        return open_beneath(dirfd, path, oflag);
+    }
+    let mut names = path
+        .to_bytes()
+        .split(|&b| b == b'/')
+        .filter(|name| !name.is_empty() && *name != b".")
+        .peekable();
+    let here = CStr::from_bytes_with_nul(b".\0").unwrap();
+    let flags = |last: bool| if last { oflag } else { libc::O_DIRECTORY };
+    let mut fd = openat(dirfd, here, flags(names.peek().is_none()))?;
+    while let Some(name) = names.next() {
+        let res = match name {
+            b".." => Err(io::Error::from_raw_os_error(libc::EXDEV)),
+            _ => {
+                // A `CStr` has no nul in it, so neither have its parts.
+                let name = CString::new(name).unwrap();
+                open_beneath(fd, &name, flags(names.peek().is_none()))
+            }
+        };
+        close(fd)?;
+        fd = res?;
+    }
+    Ok(fd)
+}
+
+/// How many files this process may have open, after raising the soft
+/// limit as far as the hard limit if asked to.
+pub(crate) fn nofile_limit(raise: bool) -> io::Result<u64> {
+    let mut limit = MaybeUninit::uninit();
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, limit.as_mut_ptr()) }
+        == -1
+    {
+        return Err(io::Error::last_os_error());
+    }
+    let mut limit = unsafe { limit.assume_init() };
+    if raise && limit.rlim_cur < limit.rlim_max {
+        limit.rlim_cur = limit.rlim_max;
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(limit.rlim_cur)
+}
+
+/// A path to the entry `name` of the directory `dirfd` that goes through
+/// the descriptor rather than the directories above it, for calls that
+/// only take paths, or `None` without `/proc`.
+pub(crate) fn fd_path(dirfd: RawFd, name: &CStr) -> Option<CString> {
+    static HAS_PROC: OnceLock<bool> = OnceLock::new();
+    let has_proc = *HAS_PROC
+        .get_or_init(|| std::path::Path::new("/proc/self/fd").is_dir());
+    if !has_proc {
+        return None;
+    }
+    let mut path = format!("/proc/self/fd/{}/", dirfd).into_bytes();
+    path.extend_from_slice(name.to_bytes());
+    // `name` came from a `CStr`, so has no nul in it.
+    CString::new(path).ok()
+}
+
 #[inline]
 pub(crate) fn lstatat(
     dirfd: RawFd,
@@ -143,10 +182,112 @@ pub(crate) fn lstatat(
     }
 }
 
+/// Inode flags that layers keep, as `STATX_ATTR_*` bits, which for these
+/// are the same as the `FS_*_FL` bits of `FS_IOC_GETFLAGS`.
+pub(crate) const ATTR_IMMUTABLE: u32 = libc::STATX_ATTR_IMMUTABLE as u32;
+pub(crate) const ATTR_APPEND: u32 = libc::STATX_ATTR_APPEND as u32;
+pub(crate) const ATTR_NODUMP: u32 = libc::STATX_ATTR_NODUMP as u32;
+pub(crate) const KEPT_ATTRS: u32 = ATTR_IMMUTABLE | ATTR_APPEND | ATTR_NODUMP;
+
+/// What `statx` says about an entry: everything `stat` does, and more.
+#[derive(Clone, Copy)]
+pub(crate) struct Stat {
+    pub(crate) st: libc::stat,
+    /// When it was created, if the filesystem keeps track.
+    pub(crate) btime: Option<(i64, u32)>,
+    /// Its `STATX_ATTR_*` flags.
+    pub(crate) attributes: u64,
+}
+
+impl Stat {
+    pub(crate) fn from_statx(stx: &libc::statx) -> Stat {
+        // SAFETY: `stat` is plain data, for which zeroes are fine.
+        let mut st: libc::stat = unsafe { std::mem::zeroed() };
+        st.st_dev = libc::makedev(stx.stx_dev_major, stx.stx_dev_minor);
+        st.st_ino = stx.stx_ino;
+        st.st_nlink = stx.stx_nlink as _;
+        st.st_mode = stx.stx_mode as _;
+        st.st_uid = stx.stx_uid;
+        st.st_gid = stx.stx_gid;
+        st.st_rdev = libc::makedev(stx.stx_rdev_major, stx.stx_rdev_minor);
+        st.st_size = stx.stx_size as _;
+        st.st_blksize = stx.stx_blksize as _;
+        st.st_blocks = stx.stx_blocks as _;
+        st.st_atime = stx.stx_atime.tv_sec;
+        st.st_atime_nsec = stx.stx_atime.tv_nsec as _;
+        st.st_mtime = stx.stx_mtime.tv_sec;
+        st.st_mtime_nsec = stx.stx_mtime.tv_nsec as _;
+        st.st_ctime = stx.stx_ctime.tv_sec;
+        st.st_ctime_nsec = stx.stx_ctime.tv_nsec as _;
+
+        let has = |field| stx.stx_mask & field != 0;
+        let btime = (stx.stx_btime.tv_sec, stx.stx_btime.tv_nsec);
+        Stat {
+            st,
+            btime: has(libc::STATX_BTIME).then_some(btime),
+            attributes: stx.stx_attributes & stx.stx_attributes_mask,
+        }
+    }
+}
+
+/// The `statx` fields `lstatxat` asks for. Not the mount ID, which names
+/// a mount only until it goes away, so there'd be no point keeping it.
+pub(crate) const STATX_MASK: u32 = libc::STATX_BASIC_STATS | libc::STATX_BTIME;
+
+/// `lstatat` with `statx`, or with `fstatat` on kernels too old for it, in
+/// which case there's nothing beyond what `stat` has.
+pub(crate) fn lstatxat(dirfd: RawFd, path: &CStr) -> io::Result<Stat> {
+    let mut stx = MaybeUninit::uninit();
+    let ret = unsafe {
+        libc::statx(
+            dirfd,
+            path.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+            STATX_MASK,
+            stx.as_mut_ptr(),
+        )
+    };
+    if ret == 0 {
+        return Ok(Stat::from_statx(unsafe { &stx.assume_init() }));
+    }
+    let e = io::Error::last_os_error();
+    if e.raw_os_error() != Some(libc::ENOSYS) {
+        return Err(e);
+    }
+    Ok(Stat {
+        st: lstatat(dirfd, path)?,
+        btime: None,
+        attributes: 0,
+    })
+}
+
+/// Change those of an open file's inode flags that are in `mask` to what
+/// they are in `flags`, with `FS_IOC_SETFLAGS`.
+pub(crate) fn set_inode_flags(
+    fd: RawFd,
+    flags: u32,
+    mask: u32,
+) -> io::Result<()> {
+    // The kernel reads and writes an int, whatever the ioctl says.
+    let mut old: c_int = 0;
+    if unsafe { libc::ioctl(fd, libc::FS_IOC_GETFLAGS, &mut old) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let new = (old & !mask as c_int) | (flags & mask) as c_int;
+    if new == old {
+        return Ok(());
+    }
+    if unsafe { libc::ioctl(fd, libc::FS_IOC_SETFLAGS, &new) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
 /// A wrapper for libc::readlinkat that manages generating a buffer and figuring.
 ///
+/// The link target is returned as raw bytes, without any UTF-8 validation.
 #[inline]
-pub(crate) fn readlinkat(dirfd: RawFd, path: &CStr) -> io::Result<PathBuf> {
+pub(crate) fn readlinkat(dirfd: RawFd, path: &CStr) -> io::Result<PString> {
     let mut buf = Vec::with_capacity(256);
 
     loop {
@@ -171,7 +312,9 @@ pub(crate) fn readlinkat(dirfd: RawFd, path: &CStr) -> io::Result<PathBuf> {
         if ret != buf.capacity() {
             buf.shrink_to_fit();
 
-            return Ok(PathBuf::from(OsString::from_vec(buf)));
+            // SAFETY: the kernel never returns a link target containing a nul
+            let cstr = unsafe { CString::from_vec_unchecked(buf) };
+            return Ok(PString::from_cstring(cstr));
         }
 
         // Trigger the internal buffer resizing logic of `Vec` by requiring
@@ -189,3 +332,69 @@ pub(crate) fn close(fd: RawFd) -> io::Result<()> {
         Ok(())
     }
 }
+
+/// `FS_IOC_FIEMAP` and its structures, from `linux/fiemap.h`, which libc
+/// lacks.
+const FS_IOC_FIEMAP: u32 = 0xc020_660b;
+
+#[repr(C)]
+#[derive(Default)]
+struct FiemapExtent {
+    fe_logical: u64,
+    fe_physical: u64,
+    fe_length: u64,
+    fe_reserved64: [u64; 2],
+    fe_flags: u32,
+    fe_reserved: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Fiemap {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+    fm_extents: [FiemapExtent; 1],
+}
+
+/// Where on disk an open file's data starts, or `None` if it has none, or
+/// the filesystem won't say.
+pub(crate) fn first_extent(fd: RawFd) -> io::Result<Option<u64>> {
+    let mut map = Fiemap {
+        fm_length: u64::MAX,
+        fm_extent_count: 1,
+        ..Fiemap::default()
+    };
+    if unsafe { libc::ioctl(fd, FS_IOC_FIEMAP as _, &mut map) } == -1 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) => Ok(None),
+            _ => Err(e),
+        };
+    }
+    Ok((map.fm_mapped_extents > 0).then_some(map.fm_extents[0].fe_physical))
+}
+
+/// `FICLONE` from `linux/fs.h`, which older versions of libc lack.
+const FICLONE: u32 = 0x4004_9409;
+
+/// Make the file `dst` share all of `src`'s blocks, returning `false` if the
+/// filesystem can't: it doesn't support reflinks, or the files are on
+/// different filesystems.
+pub(crate) fn reflink(src: RawFd, dst: RawFd) -> io::Result<bool> {
+    let ret = unsafe { libc::ioctl(dst, FICLONE as _, src) };
+    if ret == 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EOPNOTSUPP)
+        | Some(libc::ENOTTY)
+        | Some(libc::EXDEV)
+        | Some(libc::EINVAL) => Ok(false),
+        _ => Err(e),
+    }
+}
@@ -0,0 +1,331 @@
+//! Batching syscalls through io_uring, with the `io-uring` feature. Each
+//! thread sets up its own ring the first time it needs one. Kernels that
+//! are too old, or have io_uring turned off by sysctl or seccomp, refuse to
+//! set one up, and callers go back to plain syscalls. So do those whose
+//! rings can't do every operation we queue, which came with Linux 5.6.
+//!
+//! There is no io_uring operation for `getdents64`, so directories are
+//! still read a syscall at a time.
+
+use std::io;
+
+use clap::ArgEnum;
+
+/// How the walker and importer make their syscalls.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum IoBackend {
+    /// io_uring if it is available, plain syscalls otherwise
+    Auto,
+    /// Plain syscalls, one at a time
+    Sync,
+    /// io_uring, failing if it isn't available
+    Uring,
+}
+
+impl IoBackend {
+    /// Whether to use io_uring.
+    pub(crate) fn use_uring(self) -> io::Result<bool> {
+        match self {
+            IoBackend::Sync => Ok(false),
+            IoBackend::Auto => Ok(probe().is_ok()),
+            IoBackend::Uring => probe().map(|_| true),
+        }
+    }
+}
+
+/// Whether this thread can have a ring, which it can't without the
+/// feature.
+#[cfg(not(feature = "io-uring"))]
+pub(crate) fn probe() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "io_uring support is not built in; build with --features io-uring",
+    ))
+}
+
+#[cfg(feature = "io-uring")]
+pub(crate) use ring::*;
+
+#[cfg(feature = "io-uring")]
+mod ring {
+    use std::cell::RefCell;
+    use std::ffi::CStr;
+    use std::mem::MaybeUninit;
+    use std::os::raw::c_int;
+    use std::os::unix::prelude::RawFd;
+
+    use io_uring::{opcode, squeue, types, IoUring, Probe};
+
+    use super::*;
+    use crate::util::{has_openat2, Stat, RESOLVE, STATX_MASK};
+
+    /// Operations in flight at once.
+    const ENTRIES: u32 = 64;
+
+    thread_local! {
+        /// This thread's ring, or why it can't have one, once it has tried.
+        static RING: RefCell<Option<io::Result<Ring>>> =
+            const { RefCell::new(None) };
+    }
+
+    /// Whether this thread can have a ring.
+    pub(crate) fn probe() -> io::Result<()> {
+        with(|_| Ok(()))
+    }
+
+    /// A ring, and the completions it has reaped that nobody has asked for
+    /// yet.
+    pub(crate) struct Ring {
+        ring: IoUring,
+        done: Vec<(u64, i32)>,
+        /// The tag for the next operation.
+        next: u64,
+    }
+
+    /// Run `f` with this thread's ring, setting it up if need be.
+    pub(crate) fn with<R>(
+        f: impl FnOnce(&mut Ring) -> io::Result<R>,
+    ) -> io::Result<R> {
+        RING.with(|ring| {
+            let mut ring = ring.borrow_mut();
+            let ring = ring.get_or_insert_with(|| {
+                let new = IoUring::new(ENTRIES)?;
+                supported(&new)?;
+                Ok(Ring { ring: new, done: vec![], next: 0 })
+            });
+            match ring {
+                Ok(ring) => f(ring),
+                Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+            }
+        })
+    }
+
+    /// Check that `ring` can do every operation we queue. Kernels before
+    /// 5.6 set rings up but fail most of them, one at a time, with
+    /// `EINVAL`, and can't be asked which they have either.
+    fn supported(ring: &IoUring) -> io::Result<()> {
+        let unsupported = |what: &str| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("io_uring {} on this kernel", what),
+            )
+        };
+        let mut probe = Probe::new();
+        ring.submitter()
+            .register_probe(&mut probe)
+            .map_err(|_| unsupported("can't be probed"))?;
+        let mut ops = vec![
+            opcode::Statx::CODE,
+            opcode::OpenAt::CODE,
+            opcode::Read::CODE,
+            opcode::Write::CODE,
+        ];
+        if has_openat2() {
+            ops.push(opcode::OpenAt2::CODE);
+        }
+        match ops.iter().all(|&op| probe.is_supported(op)) {
+            true => Ok(()),
+            false => Err(unsupported("lacks operations we need")),
+        }
+    }
+
+    fn result(res: i32) -> io::Result<i32> {
+        if res < 0 {
+            Err(io::Error::from_raw_os_error(-res))
+        } else {
+            Ok(res)
+        }
+    }
+
+    impl Ring {
+        /// Queue an operation, submitting everything queued if there's no
+        /// more room, and return the tag to wait for it with.
+        ///
+        /// # Safety
+        ///
+        /// Everything `entry` points to has to stay valid until it has been
+        /// waited for.
+        pub(crate) unsafe fn push(
+            &mut self,
+            entry: squeue::Entry,
+        ) -> io::Result<u64> {
+            let tag = self.next;
+            let entry = entry.user_data(tag);
+            // SAFETY: passed on to our caller.
+            while unsafe { self.ring.submission().push(&entry) }.is_err() {
+                match self.ring.submit() {
+                    Err(e) if e.kind() != io::ErrorKind::Interrupted => {
+                        return Err(e)
+                    }
+                    _ => {}
+                }
+            }
+            self.next = self.next.wrapping_add(1);
+            Ok(tag)
+        }
+
+        /// Wait for the operation queued under `tag`, returning its result.
+        /// The outer error means the ring failed, and the operation may
+        /// still be running.
+        pub(crate) fn wait(
+            &mut self,
+            tag: u64,
+        ) -> io::Result<io::Result<i32>> {
+            loop {
+                if let Some(i) = self.done.iter().position(|d| d.0 == tag) {
+                    return Ok(result(self.done.swap_remove(i).1));
+                }
+                match self.ring.submit_and_wait(1) {
+                    // A signal, or a full completion queue to empty first.
+                    Err(e)
+                        if e.kind() == io::ErrorKind::Interrupted
+                            || e.raw_os_error() == Some(libc::EBUSY)
+                            || e.raw_os_error() == Some(libc::EAGAIN) => {}
+                    Err(e) => return Err(e),
+                    Ok(_) => {}
+                }
+                let done = &mut self.done;
+                done.extend(
+                    self.ring
+                        .completion()
+                        .map(|c| (c.user_data(), c.result())),
+                );
+            }
+        }
+
+        /// Wait for the operation queued under `tag`, whose memory is about
+        /// to go away. If the ring fails first, the kernel could still write
+        /// to it after that, which nothing can be allowed to see, so this
+        /// aborts: returning or unwinding would both free it. The message
+        /// goes straight to stderr rather than through `output::emit_error`,
+        /// which needs the output format that nothing down here knows, and
+        /// in JSON mode the abnormal exit is what scripts go by anyway.
+        pub(crate) fn finish(&mut self, tag: u64) -> io::Result<i32> {
+            match self.wait(tag) {
+                Ok(res) => res,
+                Err(e) => {
+                    eprintln!(
+                        "io_uring failed with operations running: {}",
+                        e
+                    );
+                    std::process::abort();
+                }
+            }
+        }
+
+        /// Run a batch of operations, adding their results to `results` in
+        /// order. If the ring fails, `results` has those of the operations
+        /// that ran before it did.
+        ///
+        /// # Safety
+        ///
+        /// As for `push`, for every entry.
+        unsafe fn run(
+            &mut self,
+            entries: Vec<squeue::Entry>,
+            results: &mut Vec<io::Result<i32>>,
+        ) -> io::Result<()> {
+            for chunk in entries.chunks(ENTRIES as usize) {
+                let mut tags = Vec::with_capacity(chunk.len());
+                let mut failed = None;
+                for entry in chunk {
+                    // SAFETY: passed on to our caller.
+                    match unsafe { self.push(entry.clone()) } {
+                        Ok(tag) => tags.push(tag),
+                        Err(e) => {
+                            failed = Some(e);
+                            break;
+                        }
+                    }
+                }
+                // What was queued runs either way, and has to be done with
+                // its memory before we return.
+                for tag in tags {
+                    results.push(self.finish(tag));
+                }
+                if let Some(e) = failed {
+                    return Err(e);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Stat every path relative to its directory without following links,
+    /// in as few syscalls as possible. The outer error means the ring
+    /// failed.
+    pub(crate) fn lstat_all(
+        paths: &[(RawFd, &CStr)],
+    ) -> io::Result<Vec<io::Result<Stat>>> {
+        let mut bufs: Vec<MaybeUninit<libc::statx>> =
+            paths.iter().map(|_| MaybeUninit::uninit()).collect();
+        let entries = paths
+            .iter()
+            .zip(bufs.iter_mut())
+            .map(|((dirfd, path), buf)| {
+                opcode::Statx::new(
+                    types::Fd(*dirfd),
+                    path.as_ptr(),
+                    buf.as_mut_ptr().cast::<types::statx>(),
+                )
+                .flags(libc::AT_SYMLINK_NOFOLLOW)
+                .mask(STATX_MASK)
+                .build()
+            })
+            .collect();
+        let mut results = Vec::with_capacity(paths.len());
+        // SAFETY: the paths and buffers outlive the batch.
+        with(|ring| unsafe { ring.run(entries, &mut results) })?;
+        Ok(results
+            .into_iter()
+            .zip(bufs)
+            .map(|(res, buf)| {
+                // SAFETY: the kernel filled the buffer in if it succeeded.
+                res.map(|_| Stat::from_statx(unsafe { &buf.assume_init() }))
+            })
+            .collect())
+    }
+
+    /// Open every entry of a directory with its flags, like
+    /// `open_beneath`. The outer error means the ring failed.
+    pub(crate) fn open_all(
+        paths: &[(RawFd, &CStr, c_int)],
+    ) -> io::Result<Vec<io::Result<RawFd>>> {
+        let flags = |flags| flags | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+        // Rings came before `openat2`, so may not be able to do it either.
+        let openat2 = has_openat2();
+        let hows: Vec<types::OpenHow> = paths
+            .iter()
+            .map(|(_, _, oflag)| {
+                types::OpenHow::new()
+                    .flags(flags(*oflag) as u64)
+                    .resolve(RESOLVE)
+            })
+            .collect();
+        let entries = paths
+            .iter()
+            .zip(&hows)
+            .map(|((dirfd, path, oflag), how)| {
+                let dirfd = types::Fd(*dirfd);
+                if openat2 {
+                    opcode::OpenAt2::new(dirfd, path.as_ptr(), how).build()
+                } else {
+                    opcode::OpenAt::new(dirfd, path.as_ptr())
+                        .flags(flags(*oflag))
+                        .build()
+                }
+            })
+            .collect();
+        let mut fds = Vec::with_capacity(paths.len());
+        // SAFETY: the paths and `hows` outlive the batch.
+        if let Err(e) = with(|ring| unsafe { ring.run(entries, &mut fds) }) {
+            // Everything is opened again the slow way, so close what did
+            // open this way.
+            for fd in fds.into_iter().flatten() {
+                let _ = crate::util::close(fd);
+            }
+            return Err(e);
+        }
+        Ok(fds)
+    }
+}
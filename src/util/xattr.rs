@@ -0,0 +1,357 @@
+//! Reading and writing extended attributes, and choosing which of them to
+//! keep.
+//!
+//! Two kinds of attribute get special treatment. POSIX ACLs are stored by
+//! the kernel as `system.posix_acl_access` and `system.posix_acl_default`,
+//! outside the namespaces the other attributes live in. File capabilities
+//! in `security.capability` are cleared whenever a file changes owner, and
+//! setting them needs `CAP_SETFCAP`, so they have to be set last and fail
+//! for their own reasons.
+
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::io;
+use std::os::raw::c_char;
+use std::os::unix::prelude::RawFd;
+use std::ptr;
+use std::str::FromStr;
+
+pub(crate) const ACL_ACCESS: &[u8] = b"system.posix_acl_access";
+pub(crate) const ACL_DEFAULT: &[u8] = b"system.posix_acl_default";
+pub(crate) const CAPABILITY: &[u8] = b"security.capability";
+
+/// What an attribute is, as far as choosing and restoring it goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Kind {
+    User,
+    Trusted,
+    Security,
+    Acl,
+    Capability,
+    /// Anything else, such as other `system.*` attributes.
+    Other,
+}
+
+impl Kind {
+    pub(crate) fn of(name: &[u8]) -> Kind {
+        if name == CAPABILITY {
+            Kind::Capability
+        } else if name == ACL_ACCESS || name == ACL_DEFAULT {
+            Kind::Acl
+        } else if name.starts_with(b"user.") {
+            Kind::User
+        } else if name.starts_with(b"trusted.") {
+            Kind::Trusted
+        } else if name.starts_with(b"security.") {
+            Kind::Security
+        } else {
+            Kind::Other
+        }
+    }
+
+    /// Setting attributes of this kind needs privileges beyond owning the
+    /// file.
+    fn privilege(self) -> Option<&'static str> {
+        match self {
+            Kind::Trusted => Some("CAP_SYS_ADMIN"),
+            Kind::Capability => Some("CAP_SETFCAP"),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Kind::User => "user",
+            Kind::Trusted => "trusted",
+            Kind::Security => "security",
+            Kind::Acl => "acl",
+            Kind::Capability => "capability",
+            Kind::Other => "other",
+        })
+    }
+}
+
+/// Which attributes to keep, as given to `--xattrs`: a comma-separated
+/// list of `user`, `trusted`, `security` (which takes in capabilities),
+/// `acl`, or `all` or `none`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct XattrFilter {
+    user: bool,
+    trusted: bool,
+    security: bool,
+    acl: bool,
+    other: bool,
+}
+
+impl XattrFilter {
+    pub const ALL: XattrFilter = XattrFilter {
+        user: true,
+        trusted: true,
+        security: true,
+        acl: true,
+        other: true,
+    };
+    pub const NONE: XattrFilter = XattrFilter {
+        user: false,
+        trusted: false,
+        security: false,
+        acl: false,
+        other: false,
+    };
+
+    pub(crate) fn allows(&self, name: &[u8]) -> bool {
+        match Kind::of(name) {
+            Kind::User => self.user,
+            Kind::Trusted => self.trusted,
+            Kind::Security | Kind::Capability => self.security,
+            Kind::Acl => self.acl,
+            Kind::Other => self.other,
+        }
+    }
+}
+
+impl Default for XattrFilter {
+    fn default() -> XattrFilter {
+        XattrFilter::ALL
+    }
+}
+
+impl FromStr for XattrFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<XattrFilter, String> {
+        let mut filter = XattrFilter::NONE;
+        for part in s.split(',') {
+            match part {
+                "user" => filter.user = true,
+                "trusted" => filter.trusted = true,
+                "security" => filter.security = true,
+                "acl" => filter.acl = true,
+                "all" => filter = XattrFilter::ALL,
+                "none" => {}
+                _ => {
+                    return Err(format!(
+                        "unknown xattr namespace {:?}, expected user, \
+                         trusted, security, acl, all or none",
+                        part
+                    ))
+                }
+            }
+        }
+        Ok(filter)
+    }
+}
+
+fn unsupported(e: &io::Error) -> bool {
+    // ENOTSUP and EOPNOTSUPP are the same on Linux.
+    e.raw_os_error() == Some(libc::EOPNOTSUPP)
+}
+
+/// What to read or set attributes on: an open file, or a path whose last
+/// component is not followed, for symlinks, which can't be opened.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Target<'a> {
+    Fd(RawFd),
+    Link(&'a CStr),
+}
+
+impl Target<'_> {
+    /// `flistxattr` or `llistxattr`, asking for the size if `buf` is empty.
+    fn list(self, buf: &mut [u8]) -> isize {
+        let ptr = if buf.is_empty() {
+            ptr::null_mut()
+        } else {
+            buf.as_mut_ptr() as *mut c_char
+        };
+        unsafe {
+            match self {
+                Target::Fd(fd) => libc::flistxattr(fd, ptr, buf.len()),
+                Target::Link(path) => {
+                    libc::llistxattr(path.as_ptr(), ptr, buf.len())
+                }
+            }
+        }
+    }
+
+    /// `fgetxattr` or `lgetxattr`, asking for the size if `buf` is empty.
+    fn get(self, name: &CStr, buf: &mut [u8]) -> isize {
+        let ptr = if buf.is_empty() {
+            ptr::null_mut()
+        } else {
+            buf.as_mut_ptr() as *mut libc::c_void
+        };
+        unsafe {
+            match self {
+                Target::Fd(fd) => {
+                    libc::fgetxattr(fd, name.as_ptr(), ptr, buf.len())
+                }
+                Target::Link(path) => libc::lgetxattr(
+                    path.as_ptr(),
+                    name.as_ptr(),
+                    ptr,
+                    buf.len(),
+                ),
+            }
+        }
+    }
+}
+
+/// The null-separated names of every attribute on `target`.
+fn list(target: Target) -> io::Result<Vec<u8>> {
+    loop {
+        let size = target.list(&mut []);
+        if size == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if size == 0 {
+            return Ok(vec![]);
+        }
+
+        let mut names = vec![0u8; size as usize];
+        let size = target.list(&mut names);
+        if size == -1 {
+            let e = io::Error::last_os_error();
+            // An attribute was added since we asked for the size.
+            if e.raw_os_error() == Some(libc::ERANGE) {
+                continue;
+            }
+            return Err(e);
+        }
+        names.truncate(size as usize);
+        return Ok(names);
+    }
+}
+
+/// The value of one attribute, or `None` if it has gone away since it was
+/// listed.
+fn get(target: Target, name: &CStr) -> io::Result<Option<Vec<u8>>> {
+    loop {
+        let size = target.get(name, &mut []);
+        if size == -1 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ENODATA) {
+                return Ok(None);
+            }
+            return Err(e);
+        }
+        if size == 0 {
+            return Ok(Some(vec![]));
+        }
+
+        let mut value = vec![0u8; size as usize];
+        let size = target.get(name, &mut value);
+        if size == -1 {
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                // The value grew since we asked for its size.
+                Some(libc::ERANGE) => continue,
+                Some(libc::ENODATA) => return Ok(None),
+                _ => return Err(e),
+            }
+        }
+        value.truncate(size as usize);
+        return Ok(Some(value));
+    }
+}
+
+/// Read the attributes of `target` that `filter` allows. A filesystem
+/// without support for attributes has none, rather than being an error.
+pub(crate) fn read(
+    target: Target,
+    filter: &XattrFilter,
+) -> io::Result<Option<BTreeMap<CString, Vec<u8>>>> {
+    let names = match list(target) {
+        Err(e) if unsupported(&e) => return Ok(None),
+        res => res?,
+    };
+
+    let mut result = BTreeMap::new();
+    for name in names.split(|&c| c == 0).filter(|n| !n.is_empty()) {
+        if !filter.allows(name) {
+            continue;
+        }
+        // Keys are kept as raw bytes, since they are not guaranteed to be
+        // valid UTF-8 on Linux.
+        let name = CString::new(name).expect("split on nulls");
+        if let Some(value) = get(target, &name)? {
+            result.insert(name, value);
+        }
+    }
+
+    Ok(if result.is_empty() { None } else { Some(result) })
+}
+
+/// Set one attribute on `target`.
+pub(crate) fn set(
+    target: Target,
+    name: &CStr,
+    value: &[u8],
+) -> io::Result<()> {
+    let value_ptr = value.as_ptr() as *const libc::c_void;
+    let ret = unsafe {
+        match target {
+            Target::Fd(fd) => {
+                libc::fsetxattr(fd, name.as_ptr(), value_ptr, value.len(), 0)
+            }
+            Target::Link(path) => libc::lsetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value_ptr,
+                value.len(),
+                0,
+            ),
+        }
+    };
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Why attributes of some kind could not be restored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Refusal {
+    /// The target filesystem doesn't store them.
+    Unsupported,
+    /// We don't have the privileges to set them.
+    NotPermitted,
+    /// They are on symlinks, which without `/proc` have no path that
+    /// can't be sent somewhere else.
+    NoProc,
+}
+
+impl Refusal {
+    /// Errors that mean the attribute can't be set here at all, rather than
+    /// that something went wrong.
+    pub(crate) fn of(e: &io::Error) -> Option<Refusal> {
+        if unsupported(e) {
+            Some(Refusal::Unsupported)
+        } else if matches!(
+            e.raw_os_error(),
+            Some(libc::EPERM) | Some(libc::EACCES)
+        ) {
+            Some(Refusal::NotPermitted)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn describe(self, kind: Kind) -> String {
+        match (self, kind.privilege()) {
+            (Refusal::Unsupported, _) => {
+                "not supported by the target filesystem".to_string()
+            }
+            (Refusal::NotPermitted, Some(cap)) => {
+                format!("not permitted without {}", cap)
+            }
+            (Refusal::NotPermitted, None) => "not permitted".to_string(),
+            (Refusal::NoProc, _) => {
+                "not settable on symlinks without /proc".to_string()
+            }
+        }
+    }
+}
@@ -0,0 +1,724 @@
+//! Importing trees from the command line and reading them back.
+
+mod common;
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use common::{banyan, import, init, raw};
+
+#[test]
+fn non_utf8_names() {
+    let root = tempfile::tempdir().unwrap();
+    // Latin-1, as left behind by older systems.
+    let name = OsStr::from_bytes(b"caf\xe9");
+    fs::create_dir(root.path().join("dir")).unwrap();
+    fs::write(root.path().join("dir").join(name), b"latin-1").unwrap();
+    symlink(OsStr::from_bytes(b"\xff/x"), root.path().join("link")).unwrap();
+
+    let repo = init();
+    let layer = import(repo.path(), root.path());
+
+    // Listings escape what isn't UTF-8.
+    let res = banyan(repo.path(), &["ls", "-R", "--", &layer]).unwrap();
+    let entries: Vec<_> = res["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| (e["path"].as_str().unwrap(), e["target"].as_str()))
+        .collect();
+    assert_eq!(
+        entries,
+        [
+            ("dir", None),
+            ("dir/caf\\xe9", None),
+            ("link", Some("\\xff/x")),
+        ]
+    );
+
+    // The name itself is stored as it was, and finds the file again.
+    let path = [b"dir/".as_ref(), name.as_bytes()].concat();
+    let args = [
+        OsStr::new("cat"),
+        OsStr::new("--"),
+        OsStr::new(&layer),
+        OsStr::from_bytes(&path),
+    ];
+    assert_eq!(raw(repo.path(), &args), b"latin-1");
+}
+
+#[test]
+fn json_output() {
+    let repo = tempfile::tempdir().unwrap();
+    let path = repo.path().join("repo");
+    let res = banyan(&path, &["init", "--reflink"]).unwrap();
+    assert_eq!(res["repo"], path.to_str().unwrap());
+    assert_eq!(res["config"]["reflink"], true);
+
+    // Errors go to stderr, where they can't be mistaken for what a command
+    // streams to stdout.
+    let root = tempfile::tempdir().unwrap();
+    let layer = import(&path, root.path());
+    let out = Command::new(env!("CARGO_BIN_EXE_banyan"))
+        .arg("-r")
+        .arg(&path)
+        .args(["--output", "json", "cat", "--", &layer, "missing"])
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    assert!(out.stdout.is_empty());
+    let err: Value = serde_json::from_slice(&out.stderr).unwrap();
+    assert!(err["error"].as_str().unwrap().contains("missing"), "{}", err);
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Found {
+    Dir(u32),
+    File(u32, Vec<u8>),
+    Link(Vec<u8>),
+}
+
+/// Everything under `dir`, by its path relative to `dir` as raw bytes.
+fn tree(dir: &Path) -> BTreeMap<Vec<u8>, Found> {
+    fn walk(root: &Path, rel: &Path, into: &mut BTreeMap<Vec<u8>, Found>) {
+        for entry in fs::read_dir(root.join(rel)).unwrap() {
+            let entry = entry.unwrap();
+            let rel = rel.join(entry.file_name());
+            let meta = fs::symlink_metadata(entry.path()).unwrap();
+            let mode = meta.permissions().mode() & 0o7777;
+            let key = rel.as_os_str().as_bytes().to_vec();
+            if meta.file_type().is_symlink() {
+                let target = fs::read_link(entry.path()).unwrap();
+                let target = target.as_os_str().as_bytes().to_vec();
+                into.insert(key, Found::Link(target));
+            } else if meta.is_dir() {
+                into.insert(key, Found::Dir(mode));
+                walk(root, &rel, into);
+            } else {
+                let data = fs::read(entry.path()).unwrap();
+                into.insert(key, Found::File(mode, data));
+            }
+        }
+    }
+    let mut found = BTreeMap::new();
+    walk(dir, Path::new(""), &mut found);
+    found
+}
+
+#[test]
+fn restore_round_trips() {
+    let root = tempfile::tempdir().unwrap();
+    let dir = root.path().join(OsStr::from_bytes(b"d\xefr"));
+    fs::create_dir_all(dir.join("sub/empty")).unwrap();
+    fs::write(dir.join(OsStr::from_bytes(b"caf\xe9.c")), b"one").unwrap();
+    fs::write(dir.join("sub/two.c"), b"two").unwrap();
+    fs::write(dir.join("sub/three.h"), b"three").unwrap();
+    let script = root.path().join("script");
+    fs::write(&script, b"#!/bin/sh").unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o750)).unwrap();
+    let private = dir.join("sub");
+    fs::set_permissions(&private, fs::Permissions::from_mode(0o700)).unwrap();
+    symlink(OsStr::from_bytes(b"../\xff"), dir.join("link")).unwrap();
+
+    let repo = init();
+    let layer = import(repo.path(), root.path());
+    let out = tempfile::tempdir().unwrap();
+    let res = banyan(
+        repo.path(),
+        &["restore", "-t", out.path().to_str().unwrap(), "--", &layer],
+    )
+    .unwrap();
+    let counts = [&res["dirs"], &res["files"], &res["links"]];
+    assert_eq!(counts, [3, 4, 1]);
+    assert_eq!(tree(out.path()), tree(root.path()));
+
+    // Only what a glob picks out, with the directories on the way to it.
+    let out = tempfile::tempdir().unwrap();
+    let args: [&OsStr; 7] = [
+        "restore".as_ref(),
+        "-t".as_ref(),
+        out.path().as_os_str(),
+        "--path".as_ref(),
+        OsStr::from_bytes(b"d\xefr/**.c"),
+        "--".as_ref(),
+        layer.as_ref(),
+    ];
+    banyan(repo.path(), &args).unwrap();
+    let mut want = tree(root.path());
+    want.retain(|path, _| {
+        path.ends_with(b".c")
+            || path == b"d\xefr"
+            || path == b"d\xefr/sub"
+    });
+    assert_eq!(want.len(), 4);
+    assert_eq!(tree(out.path()), want);
+}
+
+/// Whether `dir` is a mount point, going by `/proc/self/mountinfo`.
+fn mounted(dir: &Path) -> bool {
+    let info = fs::read_to_string("/proc/self/mountinfo").unwrap();
+    let dir = dir.to_str().unwrap();
+    info.lines().any(|line| line.split(' ').nth(4) == Some(dir))
+}
+
+#[test]
+fn mount_serves_layer() {
+    let root = tempfile::tempdir().unwrap();
+    fs::create_dir(root.path().join("dir")).unwrap();
+    fs::write(root.path().join("dir/one"), b"first").unwrap();
+    fs::write(root.path().join("dir/two"), b"second").unwrap();
+    symlink("dir/one", root.path().join("link")).unwrap();
+
+    let repo = init();
+    let layer = import(repo.path(), root.path());
+    let mnt = tempfile::tempdir().unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_banyan"))
+        .arg("-r")
+        .arg(repo.path())
+        .args(["--output", "json", "mount", "--", &layer])
+        .arg(mnt.path())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !mounted(mnt.path()) {
+        if let Some(status) = child.try_wait().unwrap() {
+            // Neither privileges nor fusermount, so nothing to test.
+            let out = child.wait_with_output().unwrap();
+            eprintln!("skipping: mount {}: {:?}", status, out.stderr);
+            return;
+        }
+        assert!(Instant::now() < deadline, "mount never appeared");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    let served = (|| {
+        let mut names: Vec<_> = fs::read_dir(mnt.path().join("dir"))?
+            .map(|e| e.map(|e| e.file_name()))
+            .collect::<Result<_, _>>()?;
+        names.sort();
+        let data = fs::read(mnt.path().join("dir/two"))?;
+        let target = fs::read_link(mnt.path().join("link"))?;
+        let via_link = fs::read(mnt.path().join("link"))?;
+        std::io::Result::Ok((names, data, target, via_link))
+    })();
+
+    // Stopping it unmounts the layer on the way out.
+    unsafe { libc::kill(child.id() as i32, libc::SIGTERM) };
+    let out = child.wait_with_output().unwrap();
+    assert!(out.status.success(), "{:?}", out);
+    assert!(!mounted(mnt.path()));
+    let summary: Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(summary["layer"], layer.as_str());
+    assert!(summary["requests"].as_u64().unwrap() > 0);
+
+    let (names, data, target, via_link) = served.unwrap();
+    assert_eq!(names, ["one", "two"]);
+    assert_eq!(data, b"second");
+    assert_eq!(target, Path::new("dir/one"));
+    assert_eq!(via_link, b"first");
+}
+
+/// The path, type flag and link target of each member of a tar archive,
+/// skipping pax headers.
+fn members(archive: &[u8]) -> Vec<(String, u8, String)> {
+    let field = |block: &[u8], range: std::ops::Range<usize>| {
+        let bytes = &block[range];
+        let end = bytes.iter().position(|&c| c == 0).unwrap_or(bytes.len());
+        String::from_utf8(bytes[..end].to_vec()).unwrap()
+    };
+    let mut found = vec![];
+    let mut blocks = archive.chunks(512);
+    while let Some(block) = blocks.next() {
+        if block.iter().all(|&c| c == 0) {
+            break;
+        }
+        let size = u64::from_str_radix(field(block, 124..136).trim(), 8);
+        let typeflag = block[156];
+        if typeflag != b'x' {
+            let (path, link) = (field(block, 0..100), field(block, 157..257));
+            found.push((path, typeflag, link));
+        }
+        for _ in 0..size.unwrap().div_ceil(512) {
+            blocks.next();
+        }
+    }
+    found
+}
+
+#[test]
+fn export_to_stdout_reports_on_stderr() {
+    let root = tempfile::tempdir().unwrap();
+    fs::write(root.path().join("file"), b"data").unwrap();
+
+    let repo = init();
+    let layer = import(repo.path(), root.path());
+    let out = Command::new(env!("CARGO_BIN_EXE_banyan"))
+        .arg("-r")
+        .arg(repo.path())
+        .args(["--output", "json", "export", "-o", "-", "--", &layer])
+        .output()
+        .unwrap();
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(members(&out.stdout).len(), 1);
+    let summary: Value = serde_json::from_slice(&out.stderr).unwrap();
+    assert_eq!(summary["layer"], layer.as_str());
+    assert_eq!(summary["files"], 1);
+    assert_eq!(summary["bytes"], 4);
+}
+
+#[test]
+fn export_hard_links() {
+    let root = tempfile::tempdir().unwrap();
+    fs::create_dir(root.path().join("dir")).unwrap();
+    fs::write(root.path().join("dir/first"), b"shared").unwrap();
+    fs::hard_link(root.path().join("dir/first"), root.path().join("second"))
+        .unwrap();
+    fs::write(root.path().join("third"), b"shared").unwrap();
+
+    let repo = init();
+    let layer = import(repo.path(), root.path());
+    let archive = raw(repo.path(), &["export", "-o", "-", "--", &layer]);
+    let member = |path: &str, flag, target: &str| {
+        (path.to_string(), flag, target.to_string())
+    };
+    assert_eq!(
+        members(&archive),
+        [
+            member("dir/", b'5', ""),
+            member("dir/first", b'0', ""),
+            member("second", b'1', "dir/first"),
+            // Same contents, but a file of its own.
+            member("third", b'0', ""),
+        ]
+    );
+
+    // Importing the archive again links the same paths up.
+    let tar = tempfile::NamedTempFile::new().unwrap();
+    fs::write(tar.path(), &archive).unwrap();
+    let args: [&OsStr; 5] = [
+        "import".as_ref(),
+        "--from-tar".as_ref(),
+        tar.path().as_os_str(),
+        "--progress".as_ref(),
+        "none".as_ref(),
+    ];
+    let res = banyan(repo.path(), &args).unwrap();
+    let again = res["layer"].as_str().unwrap();
+    let archive = raw(repo.path(), &["export", "-o", "-", "--", again]);
+    assert_eq!(members(&archive)[2], member("second", b'1', "dir/first"));
+    let args = ["cat", "--", again, "second"];
+    assert_eq!(raw(repo.path(), &args), b"shared");
+}
+
+/// `ls -l -R` of a layer, without the layer's own hash.
+fn listing(repo: &Path, layer: &str) -> Value {
+    let res = banyan(repo, &["ls", "-l", "-R", "--", layer]).unwrap();
+    res["entries"].clone()
+}
+
+#[test]
+fn import_from_tar() {
+    let root = tempfile::tempdir().unwrap();
+    fs::create_dir_all(root.path().join("a/b")).unwrap();
+    fs::write(root.path().join("a/b/file"), b"contents").unwrap();
+    let script = root.path().join("a/script");
+    fs::write(&script, b"#!/bin/sh").unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o750)).unwrap();
+    fs::write(root.path().join(OsStr::from_bytes(b"\xe9")), b"").unwrap();
+    symlink("a/b/file", root.path().join("link")).unwrap();
+
+    let repo = init();
+    let layer = import(repo.path(), root.path());
+    let archive = raw(repo.path(), &["export", "-o", "-", "--", &layer]);
+    let tar = tempfile::NamedTempFile::new().unwrap();
+    fs::write(tar.path(), &archive).unwrap();
+
+    // From a file, which gives back the same entries.
+    let args: [&OsStr; 5] = [
+        "import".as_ref(),
+        "--from-tar".as_ref(),
+        tar.path().as_os_str(),
+        "--progress".as_ref(),
+        "none".as_ref(),
+    ];
+    let res = banyan(repo.path(), &args).unwrap();
+    assert_eq!(res["files"], 3);
+    let from_file = res["layer"].as_str().unwrap().to_string();
+    let want = listing(repo.path(), &layer);
+    assert_eq!(listing(repo.path(), &from_file), want);
+
+    // And from stdin.
+    let out = Command::new(env!("CARGO_BIN_EXE_banyan"))
+        .arg("-r")
+        .arg(repo.path())
+        .args(["--output", "json", "import", "--from-tar", "-"])
+        .args(["--progress", "none"])
+        .stdin(fs::File::open(tar.path()).unwrap())
+        .output()
+        .unwrap();
+    assert!(out.status.success(), "{:?}", out);
+    let res: Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(res["layer"], from_file);
+
+    // Options that only make sense for walking a directory are refused.
+    for option in [
+        &["--same-device"][..],
+        &["--walk-threads", "2"],
+        &["--hash-threads", "2"],
+        &["--io-backend", "sync"],
+        &["--honor-nodump"],
+        &["--max-open-files", "64"],
+        &["--raise-fd-limit"],
+        &["--traversal", "depth"],
+        &["--file-order", "inode"],
+    ] {
+        let out = Command::new(env!("CARGO_BIN_EXE_banyan"))
+            .arg("-r")
+            .arg(repo.path())
+            .args(["import", "--from-tar"])
+            .arg(tar.path())
+            .args(option)
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(2), "{:?}", option);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("cannot be used with"), "{}", stderr);
+    }
+}
+
+#[test]
+fn import_tar_duplicates() {
+    let first = tempfile::tempdir().unwrap();
+    fs::create_dir(first.path().join("a")).unwrap();
+    fs::write(first.path().join("a/old"), b"old").unwrap();
+    let second = tempfile::tempdir().unwrap();
+    fs::write(second.path().join("a"), b"new").unwrap();
+    let third = tempfile::tempdir().unwrap();
+    symlink("/tmp", third.path().join("a")).unwrap();
+
+    let repo = init();
+    let tar = tempfile::NamedTempFile::new().unwrap();
+    let import_tar = |parts: &[(&Path, &str)], parent: Option<&str>| {
+        let mut command = Command::new("tar");
+        command.arg("-cf").arg(tar.path());
+        for (dir, member) in parts {
+            command.arg("-C").arg(dir).arg(member);
+        }
+        assert!(command.status().unwrap().success());
+        let mut args = vec![
+            "import".to_string(),
+            "--from-tar".to_string(),
+            tar.path().to_str().unwrap().to_string(),
+        ];
+        args.extend(parent.map(|p| format!("--delta-from={}", p)));
+        banyan(repo.path(), &args)
+    };
+
+    // A later member replaces an earlier one, along with what was in it.
+    let parts = [(first.path(), "a"), (second.path(), "a")];
+    let res = import_tar(&parts, None).unwrap();
+    assert_eq!(res["files"], 1);
+    let layer = res["layer"].as_str().unwrap().to_string();
+    let entries = listing(repo.path(), &layer);
+    let entries: Vec<_> = entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| (e["path"].as_str().unwrap(), e["size"].as_u64()))
+        .collect();
+    assert_eq!(entries, [("a", Some(3))]);
+
+    // Nor does a delta get to put anything under a link.
+    let parts = [(third.path(), "a"), (first.path(), "a/old")];
+    let err = import_tar(&parts, Some(&layer)).unwrap_err();
+    assert!(err.contains("./a/old: parent is not a directory"), "{}", err);
+}
+
+#[test]
+fn squash_delta_chain() {
+    let root = tempfile::tempdir().unwrap();
+    let path = |p: &str| root.path().join(p);
+    fs::create_dir_all(path("a/sub")).unwrap();
+    fs::create_dir_all(path("b")).unwrap();
+    fs::write(path("a/x"), b"one").unwrap();
+    fs::write(path("a/sub/y"), b"two").unwrap();
+    fs::write(path("b/z"), b"three").unwrap();
+
+    let repo = init();
+    let delta_from = |parent: &str| {
+        // Hashes can start with a dash, so they can't be a separate arg.
+        let delta_from = format!("--delta-from={}", parent);
+        let args: [&OsStr; 5] = [
+            "import".as_ref(),
+            root.path().as_os_str(),
+            delta_from.as_ref(),
+            "--progress".as_ref(),
+            "none".as_ref(),
+        ];
+        let res = banyan(repo.path(), &args).unwrap();
+        res["layer"].as_str().unwrap().to_string()
+    };
+    let base = import(repo.path(), root.path());
+
+    // Remove a directory, change a file and turn a directory into one.
+    fs::remove_dir_all(path("a/sub")).unwrap();
+    fs::write(path("a/x"), b"changed").unwrap();
+    fs::remove_dir_all(path("b")).unwrap();
+    fs::write(path("b"), b"now a file").unwrap();
+    let second = delta_from(&base);
+
+    // And then back again, under a new name.
+    fs::remove_file(path("b")).unwrap();
+    fs::create_dir(path("b")).unwrap();
+    fs::write(path("b/w"), b"four").unwrap();
+    symlink("b/w", path("link")).unwrap();
+    let third = delta_from(&second);
+
+    let res = banyan(repo.path(), &["squash", "--", &third]).unwrap();
+    assert_eq!(res["stack"], serde_json::json!([third, second, base]));
+    let squashed = res["layer"].as_str().unwrap();
+    assert_eq!(
+        listing(repo.path(), squashed),
+        listing(repo.path(), &third)
+    );
+    // It's the very layer a fresh import of the same tree makes.
+    assert_eq!(squashed, import(repo.path(), root.path()));
+    let entries: Vec<_> = listing(repo.path(), squashed)
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["path"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(entries, ["a", "a/x", "b", "b/w", "link"]);
+    assert_eq!(raw(repo.path(), &["cat", "--", squashed, "a/x"]), b"changed");
+}
+
+#[test]
+fn empty_root() {
+    let root = tempfile::tempdir().unwrap();
+    let repo = init();
+    let layer = import(repo.path(), root.path());
+    assert_eq!(listing(repo.path(), &layer), serde_json::json!([]));
+    let archive = raw(repo.path(), &["export", "-o", "-", "--", &layer]);
+    assert!(members(&archive).is_empty());
+}
+
+/// Set an extended attribute on `path`, following links.
+fn setxattr(path: &Path, name: &str, value: &[u8]) {
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+    let name = std::ffi::CString::new(name).unwrap();
+    let ret = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    assert_eq!(ret, 0, "{}", std::io::Error::last_os_error());
+}
+
+/// The names of the extended attributes of `path`.
+fn xattr_names(path: &Path) -> Vec<String> {
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+    let mut buf = vec![0u8; 4096];
+    let len = unsafe {
+        libc::listxattr(path.as_ptr(), buf.as_mut_ptr() as *mut _, buf.len())
+    };
+    assert!(len >= 0, "{}", std::io::Error::last_os_error());
+    let mut names: Vec<String> = buf[..len as usize]
+        .split(|&c| c == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .filter(|name| !name.starts_with("security.selinux"))
+        .collect();
+    names.sort();
+    names
+}
+
+/// Let `nobody` read the whole repository.
+fn share(repo: &Path) {
+    let status = Command::new("chmod")
+        .args(["-R", "a+rX"])
+        .arg(repo)
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+/// A directory `nobody` can restore into.
+fn nobody_target() -> tempfile::TempDir {
+    let out = tempfile::tempdir().unwrap();
+    let nobody = Some(common::NOBODY);
+    std::os::unix::fs::chown(out.path(), nobody, nobody).unwrap();
+    out
+}
+
+#[test]
+fn restore_xattrs() {
+    let root = tempfile::tempdir().unwrap();
+    fs::create_dir(root.path().join("dir")).unwrap();
+    fs::write(root.path().join("dir/file"), b"data").unwrap();
+    setxattr(&root.path().join("dir"), "user.dir", b"1");
+    setxattr(&root.path().join("dir/file"), "user.file", b"2");
+    let trusted = unsafe { libc::geteuid() } == 0;
+    if trusted {
+        setxattr(&root.path().join("dir/file"), "trusted.file", b"3");
+    }
+
+    let repo = init();
+    let layer = import(repo.path(), root.path());
+    let restore = |out: &Path, xattrs: &str| {
+        let out = out.to_str().unwrap().to_string();
+        let args = ["restore", "-t", &out, "--xattrs", xattrs, "--", &layer];
+        banyan(repo.path(), &args).unwrap()
+    };
+
+    // Everything the filter lets through goes back on.
+    let out = tempfile::tempdir().unwrap();
+    let res = restore(out.path(), "all");
+    assert_eq!(res["xattrs"], if trusted { 3 } else { 2 });
+    assert_eq!(res["xattrs_skipped"], serde_json::json!([]));
+    let file = out.path().join("dir/file");
+    let mut want = vec!["user.file"];
+    if trusted {
+        want.push("trusted.file");
+        want.sort_unstable();
+    }
+    assert_eq!(xattr_names(&file), want);
+    assert_eq!(xattr_names(&out.path().join("dir")), ["user.dir"]);
+
+    // And nothing it doesn't, which isn't counted as skipped either.
+    let out = tempfile::tempdir().unwrap();
+    let res = restore(out.path(), "trusted");
+    assert_eq!(res["xattrs"], if trusted { 1 } else { 0 });
+    assert_eq!(res["xattrs_skipped"], serde_json::json!([]));
+    assert_eq!(xattr_names(&out.path().join("dir")), Vec::<String>::new());
+    let out = tempfile::tempdir().unwrap();
+    let res = restore(out.path(), "none");
+    assert_eq!(res["xattrs"], 0);
+    assert!(xattr_names(&out.path().join("dir/file")).is_empty());
+
+    // Without privileges, trusted attributes are refused, and counted,
+    // while the user ones still go on.
+    share(repo.path());
+    let out = nobody_target();
+    let target = out.path().to_str().unwrap();
+    let args = ["restore", "-t", target, "--", &layer];
+    let res = match common::unprivileged(repo.path(), &args) {
+        Some(res) => res.unwrap(),
+        None => return,
+    };
+    assert_eq!(res["xattrs"], 2);
+    assert_eq!(
+        res["xattrs_skipped"],
+        serde_json::json!([{
+            "kind": "trusted",
+            "reason": "not permitted without CAP_SYS_ADMIN",
+            "count": 1,
+        }])
+    );
+    let file = out.path().join("dir/file");
+    assert_eq!(fs::read(&file).unwrap(), b"data");
+}
+
+#[test]
+fn restore_unprivileged() {
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+    let root = tempfile::tempdir().unwrap();
+    fs::create_dir(root.path().join("dir")).unwrap();
+    fs::write(root.path().join("dir/frozen"), b"frozen").unwrap();
+    fs::write(root.path().join("dir/mine"), b"mine").unwrap();
+    symlink("frozen", root.path().join("dir/link")).unwrap();
+    let nobody = Some(common::NOBODY);
+    std::os::unix::fs::chown(root.path().join("dir/mine"), nobody, nobody)
+        .unwrap();
+    let chattr = |flag: &str| {
+        let status = Command::new("chattr")
+            .arg(flag)
+            .arg(root.path().join("dir/frozen"))
+            .status()
+            .unwrap();
+        assert!(status.success());
+    };
+
+    // Importing while the file is immutable records the flag, and clearing
+    // it afterwards lets the tree be cleaned up.
+    chattr("+i");
+    let repo = init();
+    let layer = import(repo.path(), root.path());
+    chattr("-i");
+
+    // Without privileges, nothing can be handed to root and nothing can be
+    // made immutable, but the restore carries on and counts them. The
+    // file nobody owned already is the one chown that works.
+    share(repo.path());
+    let out = nobody_target();
+    let target = out.path().to_str().unwrap();
+    let args = ["restore", "-t", target, "--", &layer];
+    let res = common::unprivileged(repo.path(), &args).unwrap().unwrap();
+    let counts = [&res["dirs"], &res["files"], &res["links"]];
+    assert_eq!(counts, [1, 2, 1]);
+    assert_eq!(res["ownership_skipped"], 3);
+    assert_eq!(res["flags_skipped"], 1);
+    assert_eq!(tree(out.path()), tree(root.path()));
+    let meta = fs::metadata(out.path().join("dir/frozen")).unwrap();
+    assert_eq!(std::os::unix::fs::MetadataExt::uid(&meta), common::NOBODY);
+}
+
+#[test]
+fn reflink_fallback() {
+    // tmpfs can't share blocks, so a repository set up for reflinks has to
+    // copy there instead, and say so.
+    let shm = Path::new("/dev/shm");
+    if !shm.is_dir() {
+        return;
+    }
+    let root = tempfile::tempdir_in(shm).unwrap();
+    fs::create_dir(root.path().join("dir")).unwrap();
+    fs::write(root.path().join("dir/small"), b"small").unwrap();
+    let big: Vec<u8> = (0..3 << 20).map(|i| (i % 251) as u8).collect();
+    fs::write(root.path().join("dir/big"), &big).unwrap();
+
+    let repo = tempfile::tempdir_in(shm).unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_banyan"))
+        .arg("-r")
+        .arg(repo.path())
+        .args(["init", "--reflink"])
+        .output()
+        .unwrap();
+    assert!(out.status.success(), "{:?}", out);
+
+    let args: [&OsStr; 4] = [
+        "import".as_ref(),
+        root.path().as_os_str(),
+        "--progress".as_ref(),
+        "none".as_ref(),
+    ];
+    let res = banyan(repo.path(), &args).unwrap();
+    assert_eq!(res["bytes_stored"], big.len() + 5);
+    assert_eq!(res["bytes_cloned"], 0);
+    let layer = res["layer"].as_str().unwrap();
+
+    let out = tempfile::tempdir_in(shm).unwrap();
+    let target = out.path().to_str().unwrap();
+    let res =
+        banyan(repo.path(), &["restore", "-t", target, "--", layer]).unwrap();
+    assert_eq!(res["bytes"], big.len() + 5);
+    assert_eq!(res["bytes_cloned"], 0);
+    assert_eq!(tree(out.path()), tree(root.path()));
+}
@@ -0,0 +1,103 @@
+//! Running the banyan binary, for the tests that drive it from the command
+//! line.
+
+// Not every test uses every helper.
+#![allow(dead_code)]
+
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Output};
+
+use serde_json::Value;
+
+/// Run banyan against `repo`, returning its JSON result, or the error it
+/// reported on stderr.
+pub fn banyan<S: AsRef<OsStr>>(
+    repo: &Path,
+    args: &[S],
+) -> Result<Value, String> {
+    let exe = Path::new(env!("CARGO_BIN_EXE_banyan"));
+    json(json_command(exe, repo, args).output().expect("banyan runs"))
+}
+
+/// The uid and gid of `nobody`, which `unprivileged` runs banyan as.
+pub const NOBODY: u32 = 65534;
+
+/// Run banyan as `nobody`, or return `None` if we aren't root and so can't.
+/// Whatever it needs from `repo` has to be readable by anyone.
+pub fn unprivileged<S: AsRef<OsStr>>(
+    repo: &Path,
+    args: &[S],
+) -> Option<Result<Value, String>> {
+    if unsafe { libc::geteuid() } != 0 {
+        return None;
+    }
+    // The build directory may be out of nobody's reach, so run a copy.
+    let bin = tempfile::tempdir().unwrap();
+    fs::set_permissions(bin.path(), fs::Permissions::from_mode(0o755))
+        .unwrap();
+    let exe = bin.path().join("banyan");
+    fs::copy(env!("CARGO_BIN_EXE_banyan"), &exe).unwrap();
+    let mut cmd = json_command(&exe, repo, args);
+    cmd.uid(NOBODY).gid(NOBODY);
+    Some(json(cmd.output().expect("banyan runs")))
+}
+
+fn json_command<S: AsRef<OsStr>>(
+    exe: &Path,
+    repo: &Path,
+    args: &[S],
+) -> Command {
+    let mut cmd = Command::new(exe);
+    cmd.arg("-r").arg(repo).args(["--output", "json"]).args(args);
+    cmd
+}
+
+fn json(out: Output) -> Result<Value, String> {
+    let json = if out.status.success() { &out.stdout } else { &out.stderr };
+    let res: Value = serde_json::from_slice(json)
+        .unwrap_or_else(|e| panic!("bad output {:?}: {}", out, e));
+    match res.get("error") {
+        Some(error) => Err(error.as_str().unwrap().to_string()),
+        None => Ok(res),
+    }
+}
+
+pub fn init() -> tempfile::TempDir {
+    let repo = tempfile::tempdir().unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_banyan"))
+        .arg("-r")
+        .arg(repo.path())
+        .arg("init")
+        .output()
+        .unwrap();
+    assert!(out.status.success(), "{:?}", out);
+    repo
+}
+
+/// Import `dir` into `repo`, returning the new layer's hash.
+pub fn import(repo: &Path, dir: &Path) -> String {
+    let args: [&OsStr; 4] = [
+        "import".as_ref(),
+        dir.as_os_str(),
+        "--progress".as_ref(),
+        "none".as_ref(),
+    ];
+    let res = banyan(repo, &args).unwrap();
+    res["layer"].as_str().unwrap().to_string()
+}
+
+/// Run banyan against `repo` for a command that writes raw bytes to stdout.
+pub fn raw<S: AsRef<OsStr>>(repo: &Path, args: &[S]) -> Vec<u8> {
+    let out = Command::new(env!("CARGO_BIN_EXE_banyan"))
+        .arg("-r")
+        .arg(repo)
+        .args(args)
+        .output()
+        .expect("banyan runs");
+    assert!(out.status.success(), "{:?}", out);
+    out.stdout
+}
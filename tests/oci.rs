@@ -0,0 +1,228 @@
+//! Importing and exporting OCI image layouts, using the layouts under
+//! `tests/fixtures/oci`.
+
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use serde_json::Value;
+
+use common::{banyan, init};
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/oci").join(name)
+}
+
+fn layers(res: &Value) -> Vec<String> {
+    res["layers"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|l| l.as_str().unwrap().to_string())
+        .collect()
+}
+
+/// Every entry of a layer, as `path kind`.
+fn listing(repo: &Path, layer: &str) -> Vec<String> {
+    // Hashes can start with a hyphen.
+    let res = banyan(repo, &["ls", "-R", "--", layer]).unwrap();
+    res["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| {
+            format!(
+                "{} {}",
+                e["path"].as_str().unwrap(),
+                e["kind"].as_str().unwrap()
+            )
+        })
+        .collect()
+}
+
+fn cat(repo: &Path, layer: &str, path: &str) -> Vec<u8> {
+    Command::new(env!("CARGO_BIN_EXE_banyan"))
+        .arg("-r")
+        .arg(repo)
+        .args(["cat", "--", layer, path])
+        .output()
+        .unwrap()
+        .stdout
+}
+
+#[test]
+fn import_applies_whiteouts() {
+    let repo = init();
+    let layout = fixture("layered");
+    let res = banyan(
+        repo.path(),
+        &[
+            "oci-import",
+            layout.to_str().unwrap(),
+            "--tag",
+            "plain",
+            "--progress",
+            "none",
+        ],
+    )
+    .unwrap();
+    let stack = layers(&res);
+    assert_eq!(stack.len(), 2);
+
+    // The bottom layer is just the first image layer.
+    assert_eq!(
+        listing(repo.path(), &stack[0]),
+        [
+            "bin dir",
+            "bin/ls file",
+            "bin/sh file",
+            "etc dir",
+            "etc/hostname file",
+            "etc/motd file",
+            "usr dir",
+            "usr/share dir",
+            "usr/share/doc dir",
+            "usr/share/doc/README file",
+            "var dir",
+            "var/cache dir",
+            "var/cache/old file",
+            "var/cache/sub dir",
+            "var/cache/sub/deep file",
+        ]
+    );
+
+    // The second removes etc/motd and usr/share, hides everything that was
+    // in var/cache, and changes bin/ls.
+    assert_eq!(
+        listing(repo.path(), &stack[1]),
+        [
+            "bin dir",
+            "bin/ls file",
+            "bin/sh file",
+            "bin/sh.link link",
+            "etc dir",
+            "etc/hostname file",
+            "usr dir",
+            "var dir",
+            "var/cache dir",
+            "var/cache/new file",
+        ]
+    );
+    assert_eq!(cat(repo.path(), &stack[0], "bin/ls"), b"ls\n");
+    assert_eq!(cat(repo.path(), &stack[1], "bin/ls"), b"ls v2\n");
+}
+
+#[test]
+fn import_selects_image() {
+    let repo = init();
+    let layout = fixture("layered");
+    let layout = layout.to_str().unwrap();
+
+    let err =
+        banyan(repo.path(), &["oci-import", layout, "--progress", "none"])
+            .unwrap_err();
+    assert!(err.contains("--tag"), "{}", err);
+    let err = banyan(
+        repo.path(),
+        &["oci-import", layout, "--tag", "missing", "--progress", "none"],
+    )
+    .unwrap_err();
+    assert!(err.contains("missing"), "{}", err);
+
+    // `latest` goes through a multi-platform index to the same manifest.
+    let plain = banyan(
+        repo.path(),
+        &["oci-import", layout, "--tag", "plain", "--progress", "none"],
+    )
+    .unwrap();
+    let latest = banyan(
+        repo.path(),
+        &["oci-import", layout, "--tag", "latest", "--progress", "none"],
+    )
+    .unwrap();
+    assert_eq!(layers(&plain), layers(&latest));
+    assert_eq!(latest["bytes_stored"], 0);
+}
+
+#[test]
+fn import_rejects_corrupt_blob() {
+    let layout = tempfile::tempdir().unwrap();
+    let status = Command::new("cp")
+        .arg("-r")
+        .arg(fixture("layered"))
+        .arg(layout.path())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let layout = layout.path().join("layered");
+
+    // Flip a byte in the uncompressed second layer.
+    let blobs = layout.join("blobs/sha256");
+    let blob = fs::read_dir(&blobs)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .find(|p| fs::metadata(p).unwrap().len() == 10240)
+        .unwrap();
+    let mut data = fs::read(&blob).unwrap();
+    data[0] ^= 1;
+    fs::write(&blob, data).unwrap();
+
+    let repo = init();
+    let err = banyan(
+        repo.path(),
+        &[
+            "oci-import",
+            layout.to_str().unwrap(),
+            "--tag",
+            "plain",
+            "--progress",
+            "none",
+        ],
+    )
+    .unwrap_err();
+    assert!(err.contains("does not match"), "{}", err);
+}
+
+#[test]
+fn export_round_trips() {
+    let repo = init();
+    let layout = fixture("layered");
+    let res = banyan(
+        repo.path(),
+        &[
+            "oci-import",
+            layout.to_str().unwrap(),
+            "--tag",
+            "plain",
+            "--progress",
+            "none",
+        ],
+    )
+    .unwrap();
+    let stack = layers(&res);
+
+    let out = tempfile::tempdir().unwrap();
+    let out = out.path().join("image");
+    let mut args =
+        vec!["oci-export", "-o", out.to_str().unwrap(), "--tag", "v1", "--"];
+    args.extend(stack.iter().map(|s| s.as_str()));
+    let exported = banyan(repo.path(), &args).unwrap();
+    let whiteouts: Vec<_> = exported["layers"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|l| l["whiteouts"].as_u64().unwrap())
+        .collect();
+    // Opaque directories become a whiteout per hidden entry.
+    assert_eq!(whiteouts, [0, 4]);
+
+    let fresh = init();
+    let res = banyan(
+        fresh.path(),
+        &["oci-import", out.to_str().unwrap(), "--progress", "none"],
+    )
+    .unwrap();
+    assert_eq!(layers(&res), stack);
+}